@@ -3,7 +3,7 @@ import path from 'node:path';
 import * as cheerio from 'cheerio';
 import { createHash } from 'node:crypto';
 import { ulid } from 'ulid';
-import { emitProgress, pathExists, rpcError } from './utils.js';
+import { emitProgress, pathExists, rpcError, newJobId, sandboxPath } from './utils.js';
 
 type SiteMap = {
   siteMapId: string;
@@ -23,9 +23,9 @@ function sha1(input: string): string {
 }
 
 export async function analyze(siteMapId: string): Promise<AnalyzeResult> {
-  const jobId = ulid();
+  const jobId = newJobId();
   const analysisId = ulid();
-  const sitemapPath = path.join('.site2ts', 'cache', 'sitemaps', `${siteMapId}.json`);
+  const sitemapPath = sandboxPath('cache', 'sitemaps', `${siteMapId}.json`);
   if (!(await pathExists(sitemapPath))) {
     throw rpcError(-32001, `siteMapId ${siteMapId} not found; run crawl first`);
   }
@@ -51,7 +51,7 @@ export async function analyze(siteMapId: string): Promise<AnalyzeResult> {
     const route = u.pathname || '/';
     routes.push({ route, sourceUrl: p.url, dynamic: false });
 
-    const htmlPath = path.join('.site2ts', 'cache', 'crawl', p.hash || sha1(p.url), 'page.html');
+    const htmlPath = sandboxPath('cache', 'crawl', p.hash || sha1(p.url), 'page.html');
     try {
       const html = await fs.readFile(htmlPath, 'utf-8');
       const $ = cheerio.load(html);
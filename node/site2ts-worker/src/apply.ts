@@ -1,7 +1,16 @@
 import { promises as fs } from 'node:fs';
 import path from 'node:path';
-import { ulid } from 'ulid';
-import { emitProgress, pathExists, rpcError } from './utils.js';
+import {
+  emitProgress,
+  newJobId,
+  generationStaging,
+  pathExists,
+  projectRoot,
+  rpcError,
+  sandboxPath,
+  setPartial,
+  throwIfCancelled,
+} from './utils.js';
 
 async function* walk(dir: string): AsyncGenerator<string> {
   for (const entry of await fs.readdir(dir, { withFileTypes: true })) {
@@ -29,17 +38,14 @@ async function ensureDir(p: string) {
   await fs.mkdir(p, { recursive: true });
 }
 
-export async function apply(_generationId: string, target: string, dryRun: boolean) {
-  const jobId = ulid();
-  const staging = path.join('.site2ts', 'staging');
-  if (!(await pathExists(staging))) {
-    throw rpcError(-32006, 'staging output missing; run scaffold/generate before apply');
-  }
-  if (!(await pathExists(path.join('.site2ts', 'reports', 'tailwind', 'fallbacks.json')))) {
-    throw rpcError(-32005, 'generation artifacts missing; run generate before apply');
-  }
-  emitProgress({ tool: 'apply', phase: 'start', extra: { jobId, generationId: _generationId, target, dryRun } });
+type ApplyPlan = {
+  changedFiles: string[];
+  deletedFiles: { removed: string[]; skipped: string[] };
+};
 
+// Work out what applying `staging` to `target` writes and deletes, without
+// touching anything.
+async function planApply(staging: string, target: string): Promise<ApplyPlan> {
   const changedFiles: string[] = [];
   const deletedFiles = { removed: [] as string[], skipped: [] as string[] };
 
@@ -67,6 +73,7 @@ export async function apply(_generationId: string, target: string, dryRun: boole
     const rel = path.relative(staging, file).replaceAll('\\', '/');
     if (isExcluded(rel)) continue;
     stagingSet.add(rel);
+    changedFiles.push(rel);
   }
   const targetApp = path.join(target, 'app');
   try {
@@ -85,59 +92,137 @@ export async function apply(_generationId: string, target: string, dryRun: boole
             const txt = await fs.readFile(file, 'utf-8');
             if (!txt.includes('Auto-generated content (MVP)')) {
               deletedFiles.skipped.push(rel);
-              return;
+              continue;
             }
           } catch {
             deletedFiles.skipped.push(rel);
-            return;
+            continue;
           }
         }
-        if (dryRun) deletedFiles.removed.push(rel);
-        else {
-          await fs.rm(file, { force: true });
-          deletedFiles.removed.push(rel);
-        }
+        deletedFiles.removed.push(rel);
       }
     }
   } catch {
     // target app dir may not exist; ignore
   }
+  return { changedFiles, deletedFiles };
+}
 
-  for await (const file of walk(staging)) {
-    const rel = path.relative(staging, file).replaceAll('\\', '/');
-    if (isExcluded(rel)) continue;
-    const dest = path.join(target, rel);
-    const destDir = path.dirname(dest);
-    if (!dryRun) {
-      await ensureDir(destDir);
-      await fs.copyFile(file, dest);
+// Paths in one plan but not the other, as `{ path, reason }` like the
+// server's APPLY_BLOCKED report.
+function planDifferences(checked: ApplyPlan, current: ApplyPlan) {
+  const out: Array<{ path: string; reason: string }> = [];
+  const compare = (was: string[], now: string[], what: string) => {
+    const before = new Set(was);
+    const after = new Set(now);
+    for (const rel of after) {
+      if (!before.has(rel)) out.push({ path: rel, reason: `would be ${what} but was not in the checked plan` });
     }
-    changedFiles.push(rel);
-    if (changedFiles.length % 25 === 0) {
-      emitProgress({
-        tool: 'apply',
-        phase: 'copy',
-        current: changedFiles.length,
-        detail: rel,
-        extra: { jobId },
-      });
+    for (const rel of before) {
+      if (!after.has(rel)) out.push({ path: rel, reason: `was planned to be ${what} but no longer is` });
     }
-  }
+  };
+  compare(checked.changedFiles, current.changedFiles, 'written');
+  compare(checked.deletedFiles.removed, current.deletedFiles.removed, 'deleted');
+  return out;
+}
+
+// Whether `rel` under `target` still lands inside the project and outside
+// the excluded paths: the server checked it, but a symlink may have
+// appeared since.
+async function staysInProject(root: string, target: string, rel: string) {
+  const dest = path.join(target, rel);
+  const stat = await fs.lstat(dest).catch(() => null);
+  if (stat?.isSymbolicLink()) return false;
+  let dir = path.dirname(dest);
+  while (!(await pathExists(dir)) && path.dirname(dir) !== dir) dir = path.dirname(dir);
+  const resolved = path.join(await fs.realpath(dir), path.relative(dir, dest));
+  const inside = path.relative(root, resolved);
+  if (inside === '..' || inside.startsWith(`..${path.sep}`) || path.isAbsolute(inside)) return false;
+  return !isExcluded(inside.replaceAll('\\', '/'));
+}
 
+function blocked(paths: Array<{ path: string; reason: string }>) {
+  const first = paths[0];
+  const message =
+    paths.length === 1
+      ? `apply blocked: ${first.path}: ${first.reason}`
+      : `apply blocked: ${paths.length} paths not allowed, first ${first.path}: ${first.reason}`;
+  return rpcError(-32012, message, { class: 'APPLY_BLOCKED', paths });
+}
+
+// A dry run returns the plan. A real apply takes the plan the server
+// checked and writes exactly that, refusing if the staging or target has
+// changed so the plan it would make now differs.
+export async function apply(
+  generationId: string,
+  targetDir: string,
+  dryRun: boolean,
+  checked?: ApplyPlan,
+) {
+  const jobId = newJobId();
+  // A relative target is relative to the project, not the worker's cwd.
+  const target = path.resolve(projectRoot(), targetDir);
+  const staging = await generationStaging(generationId, 'apply');
+  emitProgress({ tool: 'apply', phase: 'start', extra: { jobId, generationId, target, dryRun } });
+
+  const plan = await planApply(staging, target);
   if (dryRun) {
-    const planDir = path.join('.site2ts', 'reports', 'apply');
+    const planDir = sandboxPath('reports', 'apply');
     await ensureDir(planDir);
-    await fs.writeFile(
-      path.join(planDir, `${jobId}.plan.json`),
-      JSON.stringify({ changedFiles, deletedFiles }, null, 2),
-    );
+    await fs.writeFile(path.join(planDir, `${jobId}.plan.json`), JSON.stringify(plan, null, 2));
+    emitProgress({
+      tool: 'apply',
+      phase: 'complete',
+      extra: { jobId, applied: false, changed: plan.changedFiles.length, removed: plan.deletedFiles.removed.length },
+    });
+    return { jobId, applied: false, ...plan };
+  }
+  if (!checked) throw rpcError(-32602, 'plan required unless dryRun');
+
+  const differences = planDifferences(checked, plan);
+  if (differences.length) throw blocked(differences);
+  const root = await fs.realpath(projectRoot());
+  const escaped: Array<{ path: string; reason: string }> = [];
+  for (const rel of [...checked.deletedFiles.removed, ...checked.changedFiles]) {
+    if (!(await staysInProject(root, target, rel))) {
+      escaped.push({ path: rel, reason: 'resolves elsewhere through a symlink added since the plan was checked' });
+    }
+  }
+  if (escaped.length) throw blocked(escaped);
+
+  const changedFiles: string[] = [];
+  const deletedFiles = { removed: [] as string[], skipped: checked.deletedFiles.skipped };
+  const total = checked.deletedFiles.removed.length + checked.changedFiles.length;
+  const done = () => deletedFiles.removed.length + changedFiles.length;
+  const step = (rel: string) => {
+    setPartial({ jobId, applied: true, changedFiles, deletedFiles });
+    if (done() % 25 === 0 || done() === total) {
+      emitProgress({ tool: 'apply', phase: 'copy', current: done(), total, detail: rel, extra: { jobId } });
+    }
+  };
+  // Stop between files so nothing is written once the server has given up
+  // on the request.
+  for (const rel of checked.deletedFiles.removed) {
+    throwIfCancelled();
+    await fs.rm(path.join(target, rel), { force: true });
+    deletedFiles.removed.push(rel);
+    step(rel);
+  }
+  for (const rel of checked.changedFiles) {
+    throwIfCancelled();
+    const dest = path.join(target, rel);
+    await ensureDir(path.dirname(dest));
+    await fs.copyFile(path.join(staging, rel), dest);
+    changedFiles.push(rel);
+    step(rel);
   }
 
   emitProgress({
     tool: 'apply',
     phase: 'complete',
-    extra: { jobId, applied: !dryRun, changed: changedFiles.length, removed: deletedFiles.removed.length },
+    extra: { jobId, applied: true, changed: changedFiles.length, removed: deletedFiles.removed.length },
   });
 
-  return { jobId, applied: !dryRun, changedFiles, deletedFiles };
+  return { jobId, applied: true, changedFiles, deletedFiles };
 }
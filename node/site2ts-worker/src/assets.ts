@@ -1,18 +1,22 @@
 import { promises as fs } from 'node:fs';
 import path from 'node:path';
-import { ulid } from 'ulid';
-import { emitProgress } from './utils.js';
+import { emitProgress, generationDir, newJobId, pathExists, sandboxPath } from './utils.js';
 
 async function ensureDir(p: string) {
   await fs.mkdir(p, { recursive: true });
 }
 
-export async function assets(_id: string) {
-  const jobId = ulid();
-  const manifestDir = path.join('.site2ts', 'reports');
+// `id` is a generationId or a siteMapId; a generation lists its own assets,
+// anything else the scaffold's.
+export async function assets(id: string) {
+  const jobId = newJobId();
+  const genStaging = sandboxPath('generations', id, 'staging');
+  const isGeneration = /^[0-9A-Za-z_-]+$/.test(id) && (await pathExists(genStaging));
+  const staging = isGeneration ? genStaging : sandboxPath('staging');
+  // A generation's manifest is kept with it; the scaffold has just the one.
+  const manifestDir = isGeneration ? generationDir(id) : sandboxPath('reports');
   await ensureDir(manifestDir);
-
-  const stagingAssets = path.join('.site2ts', 'staging', 'app', '(site2ts)', 'assets');
+  const stagingAssets = path.join(staging, 'app', '(site2ts)', 'assets');
   const items: string[] = [];
   emitProgress({ tool: 'assets', phase: 'start', extra: { jobId } });
   try {
@@ -1,22 +1,16 @@
 import { promises as fs } from 'node:fs';
 import path from 'node:path';
 import { ulid } from 'ulid';
-import { ensureDeps, run, pathExists, rpcError, emitProgress } from './utils.js';
+import { ensureGenerationDeps, run, emitProgress, newJobId, generationStaging, sandboxPath } from './utils.js';
 
-export async function audit(_generationId: string, tsStrict: boolean, eslintConfig: string) {
-  const jobId = ulid();
+export async function audit(generationId: string, tsStrict: boolean, eslintConfig: string) {
+  const jobId = newJobId();
   const auditId = ulid();
-  const staging = path.join('.site2ts', 'staging');
-  const reportsDir = path.join('.site2ts', 'reports');
-  if (!(await pathExists(path.join(staging, 'package.json')))) {
-    throw rpcError(-32003, 'scaffold output missing; run scaffold before audit');
-  }
-  if (!(await pathExists(path.join('.site2ts', 'reports', 'tailwind', 'fallbacks.json')))) {
-    throw rpcError(-32005, 'generation artifacts missing; run generate before audit');
-  }
+  const staging = await generationStaging(generationId, 'audit');
+  const reportsDir = sandboxPath('reports');
   await fs.mkdir(reportsDir, { recursive: true });
-  await ensureDeps(staging);
-  emitProgress({ tool: 'audit', phase: 'start', extra: { jobId, generationId: _generationId } });
+  await ensureGenerationDeps(staging);
+  emitProgress({ tool: 'audit', phase: 'start', extra: { jobId, generationId } });
 
   // TypeScript tsc
   const tscArgs = ['tsc', '--noEmit', '--pretty', 'false', '--incremental', 'false'];
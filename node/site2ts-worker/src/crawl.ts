@@ -5,7 +5,7 @@ import path from 'node:path';
 import { Minimatch } from 'minimatch';
 import { XMLParser } from 'fast-xml-parser';
 import { ulid } from 'ulid';
-import { emitProgress } from './utils.js';
+import { emitProgress, onCancel, setPartial, throwIfCancelled, newJobId, sandboxPath } from './utils.js';
 
 export type CrawlParams = {
   startUrl: string;
@@ -123,6 +123,7 @@ async function savePageArtifacts(baseDir: string, url: string): Promise<PageEntr
   await ensureDir(dir);
 
   const browser = await chromium.launch();
+  const unregister = onCancel(() => void browser.close().catch(() => {}));
   try {
     const context = await browser.newContext({
       recordHar: { path: path.join(dir, 'page.har') },
@@ -149,6 +150,7 @@ async function savePageArtifacts(baseDir: string, url: string): Promise<PageEntr
     const meta = { title, meta: {}, headers };
     await fs.writeFile(path.join(dir, 'meta.json'), JSON.stringify(meta, null, 2));
   } finally {
+    unregister();
     await browser.close();
   }
 
@@ -156,10 +158,10 @@ async function savePageArtifacts(baseDir: string, url: string): Promise<PageEntr
 }
 
 export async function crawl(params: CrawlParams): Promise<{ jobId: string; siteMapId: string; pages: PageEntry[] }> {
-  const jobId = ulid();
+  const jobId = newJobId();
   const siteMapId = ulid();
   const start = new URL(params.startUrl);
-  const baseDir = path.join('.site2ts', 'cache', 'crawl');
+  const baseDir = sandboxPath('cache', 'crawl');
   await ensureDir(baseDir);
 
   emitProgress({ tool: 'crawl', phase: 'start', detail: params.startUrl, extra: { jobId } });
@@ -194,6 +196,7 @@ export async function crawl(params: CrawlParams): Promise<{ jobId: string; siteM
 
   const pages: PageEntry[] = [];
   while (queue.length && pages.length < params.maxPages) {
+    throwIfCancelled();
     const { url, depth } = queue.shift()!;
     if (visited.has(url.toString())) continue;
     visited.add(url.toString());
@@ -207,6 +210,7 @@ export async function crawl(params: CrawlParams): Promise<{ jobId: string; siteM
     try {
       const entry = await savePageArtifacts(baseDir, url.toString());
       pages.push(entry);
+      setPartial({ jobId, pages });
       emitProgress({
         tool: 'crawl',
         phase: 'page',
@@ -216,7 +220,8 @@ export async function crawl(params: CrawlParams): Promise<{ jobId: string; siteM
         extra: { jobId },
       });
     } catch {
-      // ignore fetch failures for MVP
+      // ignore fetch failures for MVP, but not a cancel
+      throwIfCancelled();
     }
 
     if (pages.length >= params.maxPages || depth >= params.maxDepth) continue;
@@ -6,7 +6,20 @@ import { createHash } from 'node:crypto';
 import { ulid } from 'ulid';
 import { chromium } from 'playwright-core';
 import getPort from 'get-port';
-import { run, ensureDir, pathExists, rpcError, ensureDeps, emitProgress } from './utils.js';
+import {
+  run,
+  ensureDir,
+  pathExists,
+  rpcError,
+  ensureGenerationDeps,
+  generationStaging,
+  emitProgress,
+  onCancel,
+  setPartial,
+  throwIfCancelled,
+  newJobId,
+  sandboxPath,
+} from './utils.js';
 import { spawn } from 'node:child_process';
 
 type Analysis = {
@@ -55,23 +68,19 @@ export async function diff(
   threshold: number,
   renderReport: boolean,
 ) {
-  const jobId = ulid();
+  const jobId = newJobId();
   const diffId = ulid();
-  const stagingDir = path.join('.site2ts', 'staging');
+  const stagingDir = await generationStaging(generationId, 'diff');
 
   // Load analysis for route list
-  const analysisPath = path.join('.site2ts', 'staging', 'meta', 'analysis.json');
+  const analysisPath = path.join(stagingDir, 'meta', 'analysis.json');
   if (!(await pathExists(analysisPath))) {
     throw rpcError(-32004, 'analysis.json missing; run analyze before diff');
   }
-  const fallbacks = path.join('.site2ts', 'reports', 'tailwind', 'fallbacks.json');
-  if (!(await pathExists(fallbacks))) {
-    throw rpcError(-32005, 'generation artifacts missing; run generate before diff');
-  }
   let raw: string;
   emitProgress({ tool: 'diff', phase: 'start', extra: { jobId, generationId }, detail: 'initializing' });
   emitProgress({ tool: 'diff', phase: 'deps', extra: { jobId, generationId }, detail: stagingDir });
-  await ensureDeps(stagingDir);
+  await ensureGenerationDeps(stagingDir);
 
   try {
     raw = await fs.readFile(analysisPath, 'utf-8');
@@ -81,7 +90,7 @@ export async function diff(
   }
   const analysis: Analysis = JSON.parse(raw);
 
-  const outRoot = path.join('.site2ts', 'reports', 'diff', diffId);
+  const outRoot = sandboxPath('reports', 'diff', diffId);
   const perRoute: Array<{
     route: string;
     diffRatio: number;
@@ -130,6 +139,7 @@ export async function diff(
       extra: { jobId, generationId, stdout: buildRes.stdout.length, stderr: buildRes.stderr.length },
     });
     serverProc = spawnServer(stagingDir, port);
+    onCancel(() => serverProc?.kill());
     emitProgress({
       tool: 'diff',
       phase: 'serve',
@@ -156,9 +166,16 @@ export async function diff(
   }
 
   for (const r of analysis.routes) {
+    try {
+      throwIfCancelled();
+    } catch (err) {
+      serverProc?.kill();
+      serverProc = null;
+      throw err;
+    }
     // Baseline: crawled screenshot
     const baseHash = sha1(r.sourceUrl);
-    const baselinePath = path.join('.site2ts', 'cache', 'crawl', baseHash, 'snap.png');
+    const baselinePath = sandboxPath('cache', 'crawl', baseHash, 'snap.png');
 
     // Actual: if server started, render route; otherwise fallback to baseline
     let actualPath = baselinePath;
@@ -179,6 +196,7 @@ export async function diff(
       } catch {
         // Fallback to baseline if rendering fails (e.g., Playwright unavailable)
         actualPath = baselinePath;
+        throwIfCancelled();
       }
     }
 
@@ -252,6 +270,7 @@ export async function diff(
         domZones: routeSummary.domZones,
         summaryPath,
       });
+      setPartial({ jobId, diffId, perRoute });
     } catch {
       // Skip routes without baseline
     }
@@ -300,14 +319,19 @@ async function renderActual(url: string, vp: { w: number; h: number; deviceScale
   await ensureDir(folder);
   const outActual = path.join(folder, 'actual.png');
   const browser = await chromium.launch();
-  const context = await browser.newContext({ viewport: { width: vp.w, height: vp.h }, deviceScaleFactor: vp.deviceScale });
-  const page = await context.newPage();
-  await page.goto(url, { waitUntil: 'networkidle' });
-  const domZones = await captureDomZones(page);
-  await page.screenshot({ path: outActual, fullPage: true });
-  await context.close();
-  await browser.close();
-  return { actualPath: outActual, domZones };
+  const unregister = onCancel(() => void browser.close().catch(() => {}));
+  try {
+    const context = await browser.newContext({ viewport: { width: vp.w, height: vp.h }, deviceScaleFactor: vp.deviceScale });
+    const page = await context.newPage();
+    await page.goto(url, { waitUntil: 'networkidle' });
+    const domZones = await captureDomZones(page);
+    await page.screenshot({ path: outActual, fullPage: true });
+    await context.close();
+    return { actualPath: outActual, domZones };
+  } finally {
+    unregister();
+    await browser.close();
+  }
 }
 
 async function captureDomZones(page: import('playwright-core').Page): Promise<DomZone[]> {
@@ -632,6 +656,7 @@ function spawnServer(cwd: string, port: number) {
 async function waitForHttp(url: string, timeoutMs: number) {
   const start = Date.now();
   while (Date.now() - start < timeoutMs) {
+    throwIfCancelled();
     try {
       const res = await fetch(url, { method: 'GET' });
       if (res.ok) return;
@@ -3,7 +3,7 @@ import { promises as fs } from 'node:fs';
 import path from 'node:path';
 import { createHash } from 'node:crypto';
 import { ulid } from 'ulid';
-import { emitProgress, pathExists, rpcError } from './utils.js';
+import { emitProgress, pathExists, rpcError, newJobId, generationDir, sandboxPath } from './utils.js';
 
 type Analysis = {
   routes: { route: string; sourceUrl: string; dynamic: boolean; params?: string[] }[];
@@ -108,18 +108,36 @@ async function copyImages(appAssetsDir: string, images: string[] = []) {
   return mapping;
 }
 
-export async function generate(_analysisId: string, _scaffoldId: string, _tailwindMode: string) {
-  const jobId = ulid();
+// Directories of the scaffold that a generation does not copy.
+const UNCOPIED = new Set(['node_modules', '.next']);
+
+export async function generate(analysisId: string, scaffoldId: string, tailwindMode: string) {
+  const jobId = newJobId();
   const generationId = ulid();
-  const stagingDir = path.join('.site2ts', 'staging');
-  const appDir = path.join(stagingDir, 'app');
-  const analysisPath = path.join(stagingDir, 'meta', 'analysis.json');
-  if (!(await pathExists(path.join(stagingDir, 'package.json')))) {
+  const scaffoldDir = sandboxPath('staging');
+  const analysisPath = path.join(scaffoldDir, 'meta', 'analysis.json');
+  if (!(await pathExists(path.join(scaffoldDir, 'package.json')))) {
     throw rpcError(-32003, 'scaffold output missing; run scaffold before generate');
   }
   if (!(await pathExists(analysisPath))) {
     throw rpcError(-32002, 'analysis.json missing; run analyze before generate');
   }
+  // Start from a copy of the scaffold so this generation owns its app.
+  const genDir = generationDir(generationId);
+  const stagingDir = path.join(genDir, 'staging');
+  await fs.cp(scaffoldDir, stagingDir, {
+    recursive: true,
+    filter: (src) => !UNCOPIED.has(path.relative(scaffoldDir, src).split(path.sep)[0]),
+  });
+  await fs.writeFile(
+    path.join(genDir, 'generation.json'),
+    JSON.stringify(
+      { generationId, jobId, analysisId, scaffoldId, tailwindMode, createdAt: new Date().toISOString() },
+      null,
+      2,
+    ),
+  );
+  const appDir = path.join(stagingDir, 'app');
   let raw: string;
   try {
     raw = await fs.readFile(analysisPath, 'utf-8');
@@ -146,7 +164,7 @@ const fallbackReport: Array<{ route: string; unmappedInlineStyles: number }> = [
   const cssVariables = new Map<string, string>();
   for (const r of analysis.routes) {
     const hash = sha1(r.sourceUrl);
-    const htmlPath = path.join('.site2ts', 'cache', 'crawl', hash, 'page.html');
+    const htmlPath = sandboxPath('cache', 'crawl', hash, 'page.html');
     try {
       const html = await fs.readFile(htmlPath, 'utf-8');
       const $ = cheerio.load(html);
@@ -331,7 +349,7 @@ const fallbackReport: Array<{ route: string; unmappedInlineStyles: number }> = [
   }
 
   // Track fallbacks
-  const fallbacksPath = path.join('.site2ts', 'reports', 'tailwind', 'fallbacks.json');
+  const fallbacksPath = path.join(genDir, 'reports', 'tailwind', 'fallbacks.json');
   await ensureDir(path.dirname(fallbacksPath));
   await fs.writeFile(
     fallbacksPath,
@@ -1,7 +1,6 @@
 import { promises as fs } from 'node:fs';
 import path from 'node:path';
-import { ulid } from 'ulid';
-import { ensureDir } from './utils.js';
+import { ensureDir, newJobId, sandboxPath } from './utils.js';
 
 export type ImproveRequest = {
   generationId: string;
@@ -22,8 +21,8 @@ export async function improve(req: ImproveRequest): Promise<ImproveResult> {
     throw Object.assign(new Error('generationId required'), { code: -32602 });
   }
 
-  const jobId = ulid();
-  const outDir = path.join('.site2ts', 'reports', 'improve');
+  const jobId = newJobId();
+  const outDir = sandboxPath('reports', 'improve');
   await ensureDir(outDir);
 
   const payload = {
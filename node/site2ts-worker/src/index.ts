@@ -1,3 +1,4 @@
+import fs from 'node:fs';
 import readline from 'node:readline';
 import { crawl, CrawlParams } from './crawl.js';
 import { analyze } from './analyze.js';
@@ -8,8 +9,9 @@ import { audit as doAudit } from './audit.js';
 import { apply as doApply } from './apply.js';
 import { assets as doAssets } from './assets.js';
 import { pack as doPack } from './pack.js';
-import { initRuntime } from './init.js';
+import { initRuntime, playwrightVersion, runtimeInfo } from './init.js';
 import { improve as doImprove } from './improve.js';
+import { requestScope, cancelledError, RequestScope } from './utils.js';
 
 type Json = any;
 
@@ -27,6 +29,38 @@ type RpcResponse = {
   id?: string | number | null;
 };
 
+// Version of the server <-> worker message format; the server refuses a
+// worker whose version differs. Bump together with the server's constant.
+const PROTOCOL_VERSION = 1;
+
+// Methods handled below, reported in `hello` so the server can spot a stale build.
+const METHODS = [
+  'hello',
+  'ping',
+  'cancel',
+  'initRuntime',
+  'runtimeInfo',
+  'crawl',
+  'analyze',
+  'scaffold',
+  'generate',
+  'diff',
+  'audit',
+  'apply',
+  'assets',
+  'pack',
+  'improve',
+];
+
+function packageVersion(): string | null {
+  try {
+    const pkg = JSON.parse(fs.readFileSync(new URL('../package.json', import.meta.url), 'utf8'));
+    return typeof pkg.version === 'string' ? pkg.version : null;
+  } catch {
+    return null;
+  }
+}
+
 function respond(ok: boolean, payload: Json, id?: RpcRequest['id']) {
   const resp: RpcResponse = ok
     ? { jsonrpc: '2.0', result: payload, id }
@@ -37,6 +71,14 @@ function respond(ok: boolean, payload: Json, id?: RpcRequest['id']) {
 
 async function handleAsync(method: string, params: Json): Promise<Json> {
   switch (method) {
+    case 'hello':
+      return {
+        protocolVersion: PROTOCOL_VERSION,
+        workerVersion: packageVersion(),
+        methods: METHODS,
+        node: process.versions.node,
+        playwright: playwrightVersion(),
+      };
     case 'ping':
       return { ok: true, msg: 'site2ts-worker ready' };
     case 'crawl': {
@@ -97,7 +139,7 @@ async function handleAsync(method: string, params: Json): Promise<Json> {
       const target = (params?.target as string) || './';
       const dryRun = Boolean(params?.dryRun ?? false);
       if (!generationId) throw Object.assign(new Error('generationId required'), { code: -32602 });
-      return await doApply(generationId, target, dryRun);
+      return await doApply(generationId, target, dryRun, params?.plan);
     }
     case 'assets': {
       const id = (params?.siteMapId as string) || (params?.generationId as string) || '';
@@ -112,11 +154,17 @@ async function handleAsync(method: string, params: Json): Promise<Json> {
     case 'initRuntime': {
       return await initRuntime();
     }
+    case 'runtimeInfo': {
+      return await runtimeInfo();
+    }
     default:
       throw Object.assign(new Error('method not found'), { code: -32601 });
   }
 }
 
+// Requests still running, by id, so `cancel` can abort them.
+const inflight = new Map<string | number, AbortController>();
+
 function main() {
   const rl = readline.createInterface({ input: process.stdin });
   rl.on('line', async (line: string) => {
@@ -130,13 +178,35 @@ function main() {
       respond(false, { code: -32700, message: `parse error: ${msg}` });
       return;
     }
+    if (req.method === 'cancel') {
+      // Aborting answers the cancelled request; the notification itself gets no reply.
+      inflight.get(req.params?.id)?.abort(req.params?.reason ?? 'cancelled');
+      return;
+    }
+    const controller = new AbortController();
+    const scope: RequestScope = {
+      requestId: req.id,
+      jobId: typeof req.params?._meta?.jobId === 'string' ? req.params._meta.jobId : undefined,
+      projectRoot:
+        typeof req.params?._meta?.projectRoot === 'string' ? req.params._meta.projectRoot : undefined,
+      signal: controller.signal,
+    };
+    if (req.id !== undefined && req.id !== null) inflight.set(req.id, controller);
+    // Only answer once the handler has settled: the server holds the project
+    // lock until then, and kills the worker if a cancelled handler hangs.
     try {
-      const res = await handleAsync(req.method, req.params || {});
+      const res = await requestScope.run(scope, () => handleAsync(req.method, req.params || {}));
       respond(true, res, req.id);
-    } catch (e: unknown) {
+    } catch (err: unknown) {
+      // A step torn down by the cancel (a closed browser, a killed child)
+      // fails with its own error; report the cancel and the partial results.
+      const e = controller.signal.aborted ? cancelledError(scope) : err;
       const code = typeof (e as any)?.code === 'number' ? (e as any).code : -32603;
       const message = (e as any)?.message ?? 'internal error';
-      respond(false, { code, message }, req.id);
+      const data = (e as any)?.data;
+      respond(false, data === undefined ? { code, message } : { code, message, data }, req.id);
+    } finally {
+      if (req.id !== undefined && req.id !== null) inflight.delete(req.id);
     }
   });
 }
@@ -1,10 +1,11 @@
-import { emitProgress, run, rpcError } from './utils.js';
-import { ulid } from 'ulid';
+import { existsSync } from 'node:fs';
+import { createRequire } from 'node:module';
+import { emitProgress, run, rpcError, newJobId } from './utils.js';
 
 const INIT_TIMEOUT_MS = 120_000;
 
 export async function initRuntime() {
-  const jobId = ulid();
+  const jobId = newJobId();
   emitProgress({ tool: 'initRuntime', phase: 'start', extra: { jobId } });
   try {
     const res = await run('npx', ['playwright', 'install', 'chromium'], process.cwd(), {
@@ -29,3 +30,47 @@ export async function initRuntime() {
   }
   return { ok: true, jobId };
 }
+
+// Playwright is optional until `init` installs it, so a missing one is null.
+export function playwrightVersion(): string | null {
+  try {
+    return createRequire(import.meta.url)('playwright/package.json').version ?? null;
+  } catch {
+    return null;
+  }
+}
+
+// Where Playwright expects its Chromium build, whether or not it is there.
+function chromiumPath(): string | null {
+  try {
+    const { chromium } = createRequire(import.meta.url)('playwright');
+    return chromium.executablePath() || null;
+  } catch {
+    return null;
+  }
+}
+
+// npm does not change under a running worker, so it is asked once it answers.
+let npmVersion: string | null = null;
+
+async function npm(): Promise<string | null> {
+  if (npmVersion) return npmVersion;
+  try {
+    const res = await run('npm', ['--version'], process.cwd(), { timeoutMs: 15_000 });
+    if (res.code === 0 && !res.timedOut) npmVersion = res.stdout.trim() || null;
+  } catch {
+    // Not installed or not on PATH: reported as null.
+  }
+  return npmVersion;
+}
+
+// Installed runtime versions, for the server to check against pins.json.
+export async function runtimeInfo() {
+  const executable = chromiumPath();
+  return {
+    node: process.versions.node,
+    npm: await npm(),
+    playwright: playwrightVersion(),
+    chromium: { executable, installed: !!executable && existsSync(executable) },
+  };
+}
@@ -1,18 +1,24 @@
 import tar from 'tar';
 import { promises as fs } from 'node:fs';
 import path from 'node:path';
-import { ulid } from 'ulid';
-import { emitProgress } from './utils.js';
+import { emitProgress, newJobId, generationStaging, generationDir, projectRoot, sandboxPath } from './utils.js';
 
-export async function pack(_generationId: string) {
-  const jobId = ulid();
-  const tarPath = path.join('.site2ts', 'exports', 'site2ts-mvp.tgz');
-  emitProgress({ tool: 'pack', phase: 'start', extra: { jobId } });
+export async function pack(generationId: string) {
+  const jobId = newJobId();
+  await generationStaging(generationId, 'pack');
+  // Kept with its generation, so packs of different generations never
+  // overwrite each other and gc removes them together.
+  const tarPath = path.join(generationDir(generationId), `site2ts-${generationId}.tgz`);
+  emitProgress({ tool: 'pack', phase: 'start', extra: { jobId, generationId } });
   await fs.mkdir(path.dirname(tarPath), { recursive: true });
-  await tar.create(
-    { gzip: true, file: tarPath, cwd: '.' },
-    ['.site2ts/staging', '.site2ts/reports'],
-  );
+  const root = projectRoot();
+  // The archive lives inside the directory it packs; leave it out.
+  const ownPath = path.normalize(path.relative(root, tarPath));
+  const filter = (p: string) => path.normalize(p) !== ownPath;
+  await tar.create({ gzip: true, file: tarPath, cwd: root, filter }, [
+    path.relative(root, generationDir(generationId)),
+    path.relative(root, sandboxPath('reports')),
+  ]);
   emitProgress({ tool: 'pack', phase: 'complete', extra: { jobId, tarPath } });
   return { jobId, tarPath };
 }
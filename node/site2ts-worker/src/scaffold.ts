@@ -1,7 +1,7 @@
 import { promises as fs } from 'node:fs';
 import path from 'node:path';
 import { ulid } from 'ulid';
-import { emitProgress } from './utils.js';
+import { emitProgress, newJobId, sandboxPath } from './utils.js';
 
 export type ScaffoldParams = { analysisId: string; appRouter?: boolean };
 
@@ -14,17 +14,32 @@ async function writeFile(p: string, content: string) {
   await fs.writeFile(p, content);
 }
 
+// Used when the sandbox has no pins.json; keep in step with the server's
+// default pins.
+const DEFAULT_PINS = { next: '14.2.32', typescript: '5.5.4', tailwind: '3.4.10' };
+
+// The dependency versions staging is built with. The server refuses later
+// stages if staging's package.json does not match pins.json exactly.
+async function readPins(): Promise<typeof DEFAULT_PINS> {
+  try {
+    return { ...DEFAULT_PINS, ...JSON.parse(await fs.readFile(sandboxPath('pins.json'), 'utf8')) };
+  } catch {
+    return DEFAULT_PINS;
+  }
+}
+
 export async function scaffold(_params: ScaffoldParams): Promise<{ jobId: string; scaffoldId: string; outDir: string }> {
-  const jobId = ulid();
+  const jobId = newJobId();
   const scaffoldId = ulid();
-  const outDir = path.join('.site2ts', 'staging');
+  const outDir = sandboxPath('staging');
   const appDir = path.join(outDir, 'app');
 
   await ensureDir(outDir);
 
   emitProgress({ tool: 'scaffold', phase: 'start', extra: { jobId } });
 
-  // package.json (versions are indicative; exact pins are handled by pins.json)
+  // package.json, with the pinned versions
+  const pins = await readPins();
   await writeFile(
     path.join(outDir, 'package.json'),
     JSON.stringify(
@@ -39,16 +54,16 @@ export async function scaffold(_params: ScaffoldParams): Promise<{ jobId: string
           lint: 'next lint'
         },
         dependencies: {
-          next: '14.2.32',
+          next: pins.next,
           react: '18.3.1',
           'react-dom': '18.3.1'
         },
         devDependencies: {
-          typescript: '^5.5.4',
+          typescript: pins.typescript,
           '@types/react': '^18.3.3',
           '@types/react-dom': '^18.3.0',
           '@types/node': '^20.14.10',
-          tailwindcss: '^3.4.10',
+          tailwindcss: pins.tailwind,
           autoprefixer: '^10.4.20',
           postcss: '^8.4.41'
         }
@@ -1,6 +1,9 @@
+import { AsyncLocalStorage } from 'node:async_hooks';
 import { spawn } from 'node:child_process';
+import { createHash } from 'node:crypto';
 import { promises as fs } from 'node:fs';
 import path from 'node:path';
+import { ulid } from 'ulid';
 
 type RunOptions = { timeoutMs?: number };
 
@@ -11,7 +14,9 @@ export function run(
   opts: RunOptions = {},
 ): Promise<{ code: number; stdout: string; stderr: string; timedOut: boolean }> {
   return new Promise((resolve, reject) => {
-    const child = spawn(cmd, args, { cwd, env: process.env });
+    // Children die with the request if it is cancelled.
+    const signal = requestScope.getStore()?.signal;
+    const child = spawn(cmd, args, { cwd, env: process.env, signal });
     let stdout = '';
     let stderr = '';
     let timedOut = false;
@@ -57,6 +62,71 @@ export function rpcError(code: number, message: string, data?: unknown) {
   return err;
 }
 
+// State of the server request being handled: its id, so progress can be
+// routed back to the right caller while several requests run concurrently,
+// the jobId the server assigned (its stderr capture is filed under it), the
+// project root every path resolves against, and its cancellation signal plus
+// whatever it has finished so far.
+export type RequestScope = {
+  requestId?: string | number | null;
+  jobId?: string;
+  projectRoot?: string;
+  signal?: AbortSignal;
+  partial?: unknown;
+};
+
+export const requestScope = new AsyncLocalStorage<RequestScope>();
+
+export const CANCELLED = -32800;
+
+// The jobId for the current request: the server's if it sent one.
+export function newJobId() {
+  return requestScope.getStore()?.jobId || ulid();
+}
+
+// The project the current request works on, as bound by the server's init.
+export function projectRoot() {
+  return requestScope.getStore()?.projectRoot || process.cwd();
+}
+
+// A path inside the current project's .site2ts sandbox.
+export function sandboxPath(...parts: string[]) {
+  return path.join(projectRoot(), '.site2ts', ...parts);
+}
+
+// Record the results finished so far; they are returned as `partial` if the
+// request is cancelled.
+export function setPartial(partial: unknown) {
+  const scope = requestScope.getStore();
+  if (scope) scope.partial = partial;
+}
+
+export function cancelledError(scope: RequestScope) {
+  const reason = scope.signal?.reason;
+  const message = typeof reason === 'string' ? reason : 'cancelled';
+  return rpcError(CANCELLED, `request cancelled: ${message}`, { partial: scope.partial });
+}
+
+// Run `stop` when the current request is cancelled, to tear down work that
+// does not watch the signal itself (a browser, a server process). Returns a
+// function that unregisters it once that work is done.
+export function onCancel(stop: () => void): () => void {
+  const signal = requestScope.getStore()?.signal;
+  if (!signal) return () => {};
+  if (signal.aborted) {
+    stop();
+    return () => {};
+  }
+  signal.addEventListener('abort', stop, { once: true });
+  return () => signal.removeEventListener('abort', stop);
+}
+
+// Throw if the current request was cancelled; call between units of work.
+export function throwIfCancelled() {
+  const scope = requestScope.getStore();
+  if (scope?.signal?.aborted) throw cancelledError(scope);
+}
+
 type ProgressParams = {
   tool: string;
   phase: string;
@@ -73,6 +143,8 @@ export function emitProgress(params: ProgressParams) {
   if (typeof current === 'number') payload.current = current;
   if (typeof total === 'number') payload.total = total;
   if (extra && Object.keys(extra).length) Object.assign(payload, extra);
+  const requestId = requestScope.getStore()?.requestId;
+  if (requestId !== undefined && requestId !== null) payload.requestId = requestId;
   console.log(
     JSON.stringify({ jsonrpc: '2.0', method: 'progress', params: payload }),
   );
@@ -101,3 +173,64 @@ export async function ensureDeps(staging: string) {
     );
   }
 }
+
+// Each generation keeps its own copy of the staged app plus its meta and
+// reports under .site2ts/generations/<id>/, so a later generate never
+// overwrites it. Old generations stay until garbage collected.
+export function generationDir(generationId: string) {
+  if (!/^[0-9A-Za-z_-]+$/.test(generationId)) {
+    throw rpcError(-32602, `invalid generationId: ${generationId}`);
+  }
+  return sandboxPath('generations', generationId);
+}
+
+// The staged app of `generationId`; `tool` names the caller in the order error.
+export async function generationStaging(generationId: string, tool: string) {
+  const staging = path.join(generationDir(generationId), 'staging');
+  if (!(await pathExists(path.join(staging, 'package.json')))) {
+    throw rpcError(-32005, `generation ${generationId} missing; run generate before ${tool}`);
+  }
+  return staging;
+}
+
+// Generations link a node_modules installed once per dependency set, under
+// .site2ts/cache/deps/<key>/ keyed by the generation's own package.json and
+// lockfile. A later scaffold with other pins gets a new key, so it never
+// changes what an older generation runs with.
+export async function ensureGenerationDeps(staging: string) {
+  const nm = path.join(staging, 'node_modules');
+  // Relink a generation staged before the cache, which linked the
+  // scaffold's own install, or whose cached install was deleted.
+  const legacy = path.resolve(sandboxPath('staging'), 'node_modules');
+  const link = await fs.readlink(nm).catch(() => null);
+  if (link !== null && (link === legacy || !(await pathExists(nm)))) await fs.unlink(nm);
+  if (await pathExists(nm)) return;
+  const manifests = ['package.json', 'package-lock.json'];
+  const hash = createHash('sha1');
+  for (const name of manifests) {
+    const file = path.join(staging, name);
+    hash.update(name).update('\0');
+    if (await pathExists(file)) hash.update(await fs.readFile(file));
+    hash.update('\0');
+  }
+  const cached = sandboxPath('cache', 'deps', hash.digest('hex').slice(0, 16));
+  if (!(await pathExists(path.join(cached, 'node_modules')))) {
+    // Install beside it and move it in whole, so a concurrent install of
+    // the same set never sees half of one.
+    const tmp = `${cached}.${ulid()}.tmp`;
+    await ensureDir(tmp);
+    try {
+      for (const name of manifests) {
+        const file = path.join(staging, name);
+        if (await pathExists(file)) await fs.copyFile(file, path.join(tmp, name));
+      }
+      await ensureDeps(tmp);
+      await fs.rename(tmp, cached).catch(async (err) => {
+        if (!(await pathExists(path.join(cached, 'node_modules')))) throw err;
+      });
+    } finally {
+      await fs.rm(tmp, { recursive: true, force: true });
+    }
+  }
+  await fs.symlink(path.join(cached, 'node_modules'), nm, 'junction');
+}
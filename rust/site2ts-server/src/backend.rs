@@ -0,0 +1,140 @@
+use futures_util::future::BoxFuture;
+use serde_json::Value;
+use std::fmt;
+
+use crate::pool::{self, pool};
+use crate::{RequestContext, RpcResult};
+
+/// Where tool handlers send work. The server runs against the Node worker
+/// pool; tests swap in [`FakeBackend`] so the RPC layer runs without Node.
+pub(crate) trait Backend: fmt::Debug + Send + Sync {
+    /// Run worker `method`, reporting progress and honouring cancellation
+    /// through `ctx`.
+    fn call<'a>(
+        &'a self,
+        method: &'a str,
+        params: Value,
+        ctx: &'a mut RequestContext,
+    ) -> BoxFuture<'a, RpcResult<Value>>;
+
+    /// What the worker reported in its `hello` handshake.
+    fn handshake(&self) -> BoxFuture<'_, RpcResult<Value>>;
+}
+
+/// The shared pool of Node workers.
+#[derive(Debug, Default)]
+pub(crate) struct WorkerBackend;
+
+impl Backend for WorkerBackend {
+    fn call<'a>(
+        &'a self,
+        method: &'a str,
+        params: Value,
+        ctx: &'a mut RequestContext,
+    ) -> BoxFuture<'a, RpcResult<Value>> {
+        Box::pin(pool().call(method, params, ctx))
+    }
+
+    fn handshake(&self) -> BoxFuture<'_, RpcResult<Value>> {
+        Box::pin(async { pool().handshake().await.map_err(pool::into_rpc_error) })
+    }
+}
+
+#[cfg(test)]
+pub(crate) use fake::FakeBackend;
+
+#[cfg(test)]
+mod fake {
+    use serde_json::{json, Value};
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::Mutex;
+
+    use super::{Backend, BoxFuture};
+    use crate::worker::PROTOCOL_VERSION;
+    use crate::{RequestContext, RpcError, RpcResult};
+
+    /// In-memory stand-in for the worker. Replies are queued per method and
+    /// replayed in order, the last one repeating; every call is recorded.
+    #[derive(Debug, Default)]
+    pub(crate) struct FakeBackend {
+        replies: Mutex<HashMap<String, VecDeque<Reply>>>,
+        calls: Mutex<Vec<(String, Value)>>,
+    }
+
+    #[derive(Debug, Clone)]
+    struct Reply {
+        progress: Vec<Value>,
+        result: RpcResult<Value>,
+    }
+
+    impl FakeBackend {
+        /// Answer the next call to `method` with `result`.
+        pub(crate) fn reply(&self, method: &str, result: RpcResult<Value>) -> &Self {
+            self.reply_with_progress(method, Vec::new(), result)
+        }
+
+        /// Like [`reply`](Self::reply), emitting `progress` notifications first.
+        pub(crate) fn reply_with_progress(
+            &self,
+            method: &str,
+            progress: Vec<Value>,
+            result: RpcResult<Value>,
+        ) -> &Self {
+            self.replies
+                .lock()
+                .unwrap()
+                .entry(method.to_string())
+                .or_default()
+                .push_back(Reply { progress, result });
+            self
+        }
+
+        /// Methods called so far with the params they were sent.
+        pub(crate) fn calls(&self) -> Vec<(String, Value)> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn next_reply(&self, method: &str) -> Option<Reply> {
+            let mut replies = self.replies.lock().unwrap();
+            let queue = replies.get_mut(method)?;
+            if queue.len() > 1 {
+                queue.pop_front()
+            } else {
+                queue.front().cloned()
+            }
+        }
+    }
+
+    impl Backend for FakeBackend {
+        fn call<'a>(
+            &'a self,
+            method: &'a str,
+            params: Value,
+            ctx: &'a mut RequestContext,
+        ) -> BoxFuture<'a, RpcResult<Value>> {
+            Box::pin(async move {
+                self.calls
+                    .lock()
+                    .unwrap()
+                    .push((method.to_string(), params));
+                let Some(reply) = self.next_reply(method) else {
+                    return Err(RpcError::new(
+                        -32601,
+                        format!("no fake reply for {}", method),
+                        None,
+                    ));
+                };
+                for p in &reply.progress {
+                    ctx.progress.report(p);
+                }
+                reply.result
+            })
+        }
+
+        fn handshake(&self) -> BoxFuture<'_, RpcResult<Value>> {
+            Box::pin(async {
+                Ok(json!({ "protocolVersion": PROTOCOL_VERSION, "workerVersion": "fake" }))
+            })
+        }
+    }
+}
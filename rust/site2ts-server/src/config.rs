@@ -0,0 +1,324 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::pool::PoolConfig;
+use crate::worker::WorkerLaunch;
+
+pub(crate) const DEFAULT_LISTEN: &str = "127.0.0.1:8765";
+
+const CONFIG_ENV: &str = "SITE2TS_CONFIG";
+const NODE_ENV: &str = "SITE2TS_NODE";
+const SCRIPT_ENV: &str = "SITE2TS_WORKER_SCRIPT";
+const ARGS_ENV: &str = "SITE2TS_WORKER_ARGS";
+
+const USAGE: &str = "\
+Usage: site2ts-server [--transport stdio|http] [--listen <addr>] [--workers <n>]
+                      [--pin <method>=<index>]... [--timeout <method>=<secs>]...
+                      [--config <file>] [--node <path>] [--worker-script <path>]
+                      [--worker-arg <arg>]... [--worker-env <key>=<value>]...
+
+Options:
+  --transport <kind>   stdio (default) or http (MCP streamable HTTP)
+  --listen <addr>      Address for the HTTP transport (default 127.0.0.1:8765)
+  --workers <n>        Number of Node worker processes (default 2)
+  --pin <m>=<i>        Always run worker method <m> on worker <i>, and keep
+                       other methods off it; may be repeated
+  --timeout <m>=<s>    Cancel worker method <m> after <s> seconds (0 = never);
+                       may be repeated
+  --config <file>      JSON config file ({\"worker\": {node, script, args, env}});
+                       relative paths in it are resolved against its directory
+  --node <path>        Node.js binary (default: node on PATH)
+  --worker-script <p>  Worker entry point (default: node/site2ts-worker/dist/
+                       index.js, searched upward from the executable)
+  --worker-arg <arg>   Extra argument for node, before the script; may be
+                       repeated
+  --worker-env <k>=<v> Extra environment variable for workers; may be repeated
+  -h, --help           Print this help
+
+Environment (overridden by flags, overrides the config file):
+  SITE2TS_CONFIG, SITE2TS_NODE, SITE2TS_WORKER_SCRIPT,
+  SITE2TS_WORKER_ARGS (whitespace-separated)";
+
+/// Layout of the `--config` file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    #[serde(default)]
+    worker: WorkerSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WorkerSection {
+    node: Option<String>,
+    script: Option<PathBuf>,
+    args: Option<Vec<String>>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Transport {
+    Stdio,
+    Http(SocketAddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ServerConfig {
+    pub(crate) transport: Transport,
+    pub(crate) pool: PoolConfig,
+}
+
+impl ServerConfig {
+    /// Parse command-line arguments (without the program name). Returns
+    /// `Ok(None)` when help was requested.
+    pub(crate) fn from_args<I>(args: I) -> Result<Option<Self>>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Self::parse(args, |key| std::env::var(key).ok())
+    }
+
+    fn parse<I, E>(args: I, env: E) -> Result<Option<Self>>
+    where
+        I: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut transport = "stdio".to_string();
+        let mut config_file: Option<String> = None;
+        let mut node: Option<String> = None;
+        let mut script: Option<String> = None;
+        let mut worker_args: Vec<String> = Vec::new();
+        let mut worker_env: Vec<(String, String)> = Vec::new();
+        let mut listen: Option<String> = None;
+        let mut pool = PoolConfig::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((f, v)) if f.starts_with("--") => (f.to_string(), Some(v.to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = |name: &str| {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| anyhow!("{} requires a value", name))
+            };
+            match flag.as_str() {
+                "--transport" => transport = value("--transport")?,
+                "--listen" => listen = Some(value("--listen")?),
+                "--workers" => {
+                    let n = value("--workers")?;
+                    pool.size = n
+                        .parse()
+                        .map_err(|_| anyhow!("invalid --workers value: {}", n))?;
+                }
+                "--pin" => pool.pin(&value("--pin")?)?,
+                "--timeout" => pool.timeout(&value("--timeout")?)?,
+                "--config" => config_file = Some(value("--config")?),
+                "--node" => node = Some(value("--node")?),
+                "--worker-script" => script = Some(value("--worker-script")?),
+                "--worker-arg" => worker_args.push(value("--worker-arg")?),
+                "--worker-env" => {
+                    let spec = value("--worker-env")?;
+                    let (key, val) = spec.split_once('=').ok_or_else(|| {
+                        anyhow!("invalid --worker-env {} (expected key=value)", spec)
+                    })?;
+                    worker_env.push((key.to_string(), val.to_string()));
+                }
+                "-h" | "--help" => return Ok(None),
+                other => bail!("unknown argument: {}\n\n{}", other, USAGE),
+            }
+        }
+        let transport = match transport.as_str() {
+            "stdio" => {
+                if listen.is_some() {
+                    bail!("--listen requires --transport http");
+                }
+                Transport::Stdio
+            }
+            "http" => {
+                let addr = listen.as_deref().unwrap_or(DEFAULT_LISTEN);
+                Transport::Http(
+                    addr.parse()
+                        .map_err(|e| anyhow!("invalid --listen address {}: {}", addr, e))?,
+                )
+            }
+            other => bail!("unknown transport: {} (expected stdio or http)", other),
+        };
+
+        let mut launch = WorkerLaunch::default();
+        if let Some(path) = config_file.or_else(|| env(CONFIG_ENV)) {
+            apply_config_file(&mut launch, Path::new(&path))?;
+        }
+        if let Some(n) = node.or_else(|| env(NODE_ENV)) {
+            launch.node = PathBuf::from(n);
+        }
+        if let Some(s) = script.or_else(|| env(SCRIPT_ENV)) {
+            launch.set_script(PathBuf::from(s));
+        }
+        if !worker_args.is_empty() {
+            launch.args = worker_args;
+        } else if let Some(a) = env(ARGS_ENV) {
+            launch.args = a.split_whitespace().map(String::from).collect();
+        }
+        launch.env.extend(worker_env);
+        pool.launch = launch;
+        pool.validate()?;
+        Ok(Some(Self { transport, pool }))
+    }
+
+    pub(crate) fn usage() -> &'static str {
+        USAGE
+    }
+}
+
+fn apply_config_file(launch: &mut WorkerLaunch, path: &Path) -> Result<()> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("read config file {}", path.display()))?;
+    let file: FileConfig = serde_json::from_str(&text)
+        .with_context(|| format!("parse config file {}", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    let worker = file.worker;
+    if let Some(node) = worker.node {
+        // Bare names are looked up on PATH; anything with a separator is a path.
+        let node = PathBuf::from(node);
+        launch.node = if node.components().count() > 1 {
+            base.join(node)
+        } else {
+            node
+        };
+    }
+    if let Some(script) = worker.script {
+        launch.set_script(base.join(script));
+    }
+    if let Some(args) = worker.args {
+        launch.args = args;
+    }
+    launch.env.extend(worker.env);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<ServerConfig>> {
+        parse_with_env(args, &[])
+    }
+
+    fn parse_with_env(args: &[&str], env: &[(&str, &str)]) -> Result<Option<ServerConfig>> {
+        let env: BTreeMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        ServerConfig::parse(args.iter().map(|s| s.to_string()), |k| env.get(k).cloned())
+    }
+
+    #[test]
+    fn defaults_to_stdio() {
+        assert_eq!(parse(&[]).unwrap().unwrap().transport, Transport::Stdio);
+    }
+
+    #[test]
+    fn http_transport_uses_listen_address() {
+        let cfg = parse(&["--transport", "http"]).unwrap().unwrap();
+        assert_eq!(
+            cfg.transport,
+            Transport::Http(DEFAULT_LISTEN.parse().unwrap())
+        );
+        let cfg = parse(&["--transport=http", "--listen=127.0.0.1:9000"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            cfg.transport,
+            Transport::Http("127.0.0.1:9000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn pool_size_and_pins() {
+        let cfg = parse(&["--workers", "3", "--pin", "apply=0", "--pin=audit=2"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(cfg.pool.size, 3);
+        assert_eq!(cfg.pool.pins.get("apply"), Some(&0));
+        assert_eq!(cfg.pool.pins.get("audit"), Some(&2));
+        let cfg = parse(&["--timeout", "crawl=60"]).unwrap().unwrap();
+        assert_eq!(cfg.pool.timeouts.get("crawl"), Some(&60));
+        assert_eq!(parse(&[]).unwrap().unwrap().pool, PoolConfig::default());
+    }
+
+    #[test]
+    fn worker_launch_precedence_is_flags_then_env_then_file() {
+        let dir = std::env::temp_dir().join(format!("site2ts-config-{}", ulid::Ulid::new()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("site2ts.json");
+        fs::write(
+            &file,
+            r#"{ "worker": { "node": "bin/node", "script": "worker/index.js",
+                 "args": ["--max-old-space-size=4096"], "env": { "A": "file" } } }"#,
+        )
+        .unwrap();
+        let file_arg = file.to_str().unwrap();
+
+        let launch = parse(&["--config", file_arg]).unwrap().unwrap().pool.launch;
+        assert_eq!(launch.node, dir.join("bin/node"));
+        assert_eq!(launch.script, dir.join("worker/index.js"));
+        assert!(launch.searched.is_empty());
+        assert_eq!(launch.args, vec!["--max-old-space-size=4096"]);
+        assert_eq!(launch.env.get("A").map(String::as_str), Some("file"));
+
+        let env = [
+            ("SITE2TS_CONFIG", file_arg),
+            ("SITE2TS_NODE", "/env/node"),
+            ("SITE2TS_WORKER_ARGS", "--inspect  --trace-warnings"),
+        ];
+        let launch = parse_with_env(&[], &env).unwrap().unwrap().pool.launch;
+        assert_eq!(launch.node, PathBuf::from("/env/node"));
+        assert_eq!(launch.script, dir.join("worker/index.js"));
+        assert_eq!(launch.args, vec!["--inspect", "--trace-warnings"]);
+
+        let launch = parse_with_env(
+            &[
+                "--node=/cli/node",
+                "--worker-script",
+                "/cli/index.js",
+                "--worker-arg",
+                "--no-warnings",
+                "--worker-env",
+                "A=cli",
+            ],
+            &env,
+        )
+        .unwrap()
+        .unwrap()
+        .pool
+        .launch;
+        assert_eq!(launch.node, PathBuf::from("/cli/node"));
+        assert_eq!(launch.script, PathBuf::from("/cli/index.js"));
+        assert_eq!(launch.args, vec!["--no-warnings"]);
+        assert_eq!(launch.env.get("A").map(String::as_str), Some("cli"));
+
+        fs::write(&file, r#"{ "worker": { "nodePath": "x" } }"#).unwrap();
+        assert!(parse(&["--config", file_arg]).is_err());
+        assert!(parse(&["--config", "/nonexistent/site2ts.json"]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&["--transport", "carrier-pigeon"]).is_err());
+        assert!(parse(&["--listen", "127.0.0.1:1"]).is_err());
+        assert!(parse(&["--transport", "http", "--listen", "nope"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(parse(&["--worker-env", "NOEQUALS"]).is_err());
+        assert!(parse(&["--workers", "0"]).is_err());
+        assert!(parse(&["--workers", "2", "--pin", "apply=2"]).is_err());
+        assert!(parse(&["--help"]).unwrap().is_none());
+    }
+}
@@ -0,0 +1,498 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::lineage::{self, Kind};
+use crate::{jobs, workspace, RequestContext, RpcError, RpcResult};
+
+/// Tool that collects garbage in the sandbox.
+pub(crate) const METHOD: &str = "gc";
+
+/// Tools after which an `auto` policy collects: the ones that add bulk.
+pub(crate) const AUTO_AFTER: &[&str] = &["crawl", "generate", "diff"];
+
+/// What `gc` keeps, stored per project in `.site2ts/gc.json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct Policy {
+    /// Newest generations kept, with everything they were made from.
+    keep_generations: usize,
+    /// Newest diff reports kept.
+    keep_diffs: usize,
+    /// Job logs older than this are removed.
+    log_max_age_days: u64,
+    /// Collect after every crawl, generate and diff.
+    auto: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            keep_generations: 3,
+            keep_diffs: 5,
+            log_max_age_days: 14,
+            auto: false,
+        }
+    }
+}
+
+impl Policy {
+    fn path(base: &Path) -> PathBuf {
+        base.join("gc.json")
+    }
+
+    fn load(base: &Path) -> Self {
+        let Ok(bytes) = fs::read(Self::path(base)) else {
+            return Self::default();
+        };
+        serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            warn!(
+                target = "site2ts",
+                "gc policy unreadable, using defaults: {}", e
+            );
+            Self::default()
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GcParams {
+    /// List what would be removed without removing it.
+    #[serde(default)]
+    dry_run: bool,
+    keep_generations: Option<usize>,
+    keep_diffs: Option<usize>,
+    log_max_age_days: Option<u64>,
+    auto: Option<bool>,
+    /// Store the resulting policy as the project's default.
+    #[serde(default)]
+    save: bool,
+}
+
+/// One file or directory `gc` removes.
+#[derive(Debug)]
+struct Item {
+    path: PathBuf,
+    kind: &'static str,
+    /// The lineage id whose artifacts these are, if any.
+    id: Option<String>,
+    bytes: u64,
+}
+
+/// What a collection removes and what it keeps because something live
+/// still needs it.
+#[derive(Debug, Default)]
+struct Plan {
+    items: Vec<Item>,
+    generations: Vec<String>,
+    site_maps: Vec<String>,
+}
+
+/// Size of `path` on disk. Symlinks count as themselves and are not
+/// followed, as `remove_dir_all` does not follow them either.
+fn size(path: &Path) -> u64 {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| size(&e.path()))
+        .sum()
+}
+
+/// Entries of `dir` with their names, skipping anything unreadable.
+fn entries(dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut out: Vec<(String, PathBuf)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|e| Some((e.file_name().into_string().ok()?, e.path())))
+        .collect();
+    out.sort();
+    out
+}
+
+fn modified(path: &Path) -> SystemTime {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+fn item(path: PathBuf, kind: &'static str, id: Option<&str>) -> Item {
+    Item {
+        bytes: size(&path),
+        path,
+        kind,
+        id: id.map(str::to_string),
+    }
+}
+
+/// Decide what to remove under `base`. Generations beyond the newest kept
+/// go, and so do siteMaps none of the kept generations, the staged
+/// analysis or scaffold, or the latest crawl came from, with every crawl
+/// cache entry no remaining siteMap lists and every dependency install no
+/// kept generation links to.
+fn plan(base: &Path, policy: &Policy) -> Plan {
+    let mut generations = lineage::current(base, Kind::Generation);
+    generations.truncate(policy.keep_generations);
+    let mut plan = Plan {
+        generations,
+        ..Default::default()
+    };
+    for (id, path) in entries(&base.join("generations")) {
+        if lineage::kind_of(base, &id) == Some(Kind::Generation) && !plan.generations.contains(&id)
+        {
+            plan.items.push(item(path, "generation", Some(&id)));
+        }
+    }
+
+    let staged: Vec<String> = [Kind::Analysis, Kind::Scaffold]
+        .into_iter()
+        .flat_map(|k| lineage::current(base, k))
+        .collect();
+    let mut live = lineage::site_maps_of(
+        base,
+        plan.generations.iter().chain(&staged).map(String::as_str),
+    );
+    live.extend(lineage::current(base, Kind::SiteMap).into_iter().take(1));
+    // A manifest the registry does not know predates it; keep it.
+    let mut referenced = HashSet::new();
+    let mut complete = true;
+    for (name, path) in entries(&base.join("cache").join("sitemaps")) {
+        let Some(id) = name.strip_suffix(".json") else {
+            continue;
+        };
+        if lineage::kind_of(base, id) == Some(Kind::SiteMap) && !live.contains(id) {
+            plan.items.push(item(path, "siteMap", Some(id)));
+            continue;
+        }
+        let manifest: Option<Value> = fs::read(&path)
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok());
+        let hashes = manifest
+            .as_ref()
+            .and_then(|m| m["pages"].as_array())
+            .map(|pages| {
+                pages
+                    .iter()
+                    .map(|p| p["hash"].as_str().map(str::to_string))
+                    .collect::<Option<Vec<String>>>()
+            });
+        match hashes {
+            Some(Some(hashes)) => referenced.extend(hashes),
+            // Which entries it needs is unknown, so none can go.
+            _ => complete = false,
+        }
+    }
+    if complete {
+        for (hash, path) in entries(&base.join("cache").join("crawl")) {
+            if !referenced.contains(&hash) {
+                plan.items.push(item(path, "crawl", None));
+            }
+        }
+    }
+    let mut site_maps: Vec<String> = live.into_iter().collect();
+    site_maps.sort();
+    plan.site_maps = site_maps;
+
+    // Dependency installs no kept generation links to, matched by key as
+    // the link may spell the sandbox path differently.
+    let linked: HashSet<String> = plan
+        .generations
+        .iter()
+        .filter_map(|id| {
+            let link = base
+                .join("generations")
+                .join(id)
+                .join("staging")
+                .join("node_modules");
+            let target = fs::read_link(link).ok()?;
+            Some(target.parent()?.file_name()?.to_str()?.to_string())
+        })
+        .collect();
+    for (key, path) in entries(&base.join("cache").join("deps")) {
+        if !linked.contains(&key) {
+            plan.items.push(item(path, "deps", None));
+        }
+    }
+
+    let mut diffs = entries(&base.join("reports").join("diff"));
+    diffs.sort_by_key(|(_, path)| std::cmp::Reverse(modified(path)));
+    for (id, path) in diffs.into_iter().skip(policy.keep_diffs) {
+        plan.items.push(item(path, "diff", Some(&id)));
+    }
+
+    let max_age = Duration::from_secs(policy.log_max_age_days * 24 * 60 * 60);
+    let cutoff = SystemTime::now()
+        .checked_sub(max_age)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    for (name, path) in entries(&base.join("logs")) {
+        let Some(job_id) = name.strip_suffix(".ndjson") else {
+            continue;
+        };
+        if modified(&path) < cutoff && !jobs::is_running(job_id) {
+            plan.items.push(item(path, "log", Some(job_id)));
+        }
+    }
+    plan
+}
+
+/// Remove what `plan` lists and mark the ids gone in the lineage registry.
+/// Returns the bytes actually freed.
+fn sweep(base: &Path, plan: &Plan) -> u64 {
+    let mut freed = 0;
+    let mut removed = Vec::new();
+    for item in &plan.items {
+        let res = if item.path.is_dir() {
+            fs::remove_dir_all(&item.path)
+        } else {
+            fs::remove_file(&item.path)
+        };
+        match res {
+            Ok(()) => {
+                freed += item.bytes;
+                if item.kind != "log" {
+                    removed.extend(item.id.clone());
+                }
+            }
+            Err(e) => warn!(
+                target = "site2ts",
+                "gc could not remove {}: {}",
+                item.path.display(),
+                e
+            ),
+        }
+    }
+    lineage::mark_removed(base, &removed);
+    freed
+}
+
+fn report(base: &Path, policy: &Policy, plan: &Plan, dry_run: bool, freed: u64) -> Value {
+    let items: Vec<Value> = plan
+        .items
+        .iter()
+        .map(|i| {
+            json!({
+                "path": Path::new(".site2ts").join(i.path.strip_prefix(base).unwrap_or(&i.path)),
+                "kind": i.kind,
+                "id": i.id,
+                "bytes": i.bytes,
+            })
+        })
+        .collect();
+    json!({
+        "dryRun": dry_run,
+        "policy": policy,
+        "reclaimableBytes": plan.items.iter().map(|i| i.bytes).sum::<u64>(),
+        "freedBytes": freed,
+        "items": items,
+        "kept": { "generations": plan.generations, "siteMaps": plan.site_maps },
+    })
+}
+
+/// Collect garbage in the sandbox under the project's policy, overridden by
+/// `params`. With `dryRun` nothing is removed and the result lists what
+/// would be, with the bytes it would free.
+pub(crate) fn run(params: GcParams, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let base = ctx.sandbox();
+    let mut policy = Policy::load(&base);
+    if let Some(n) = params.keep_generations {
+        policy.keep_generations = n;
+    }
+    if let Some(n) = params.keep_diffs {
+        policy.keep_diffs = n;
+    }
+    if let Some(days) = params.log_max_age_days {
+        policy.log_max_age_days = days;
+    }
+    if let Some(auto) = params.auto {
+        policy.auto = auto;
+    }
+    if params.save {
+        let value = serde_json::to_value(&policy).map_err(|e| RpcError::internal(e.to_string()))?;
+        jobs::write_atomic(&Policy::path(&base), &value)
+            .map_err(|e| RpcError::internal(e.to_string()))?;
+    }
+    let plan = plan(&base, &policy);
+    let freed = if params.dry_run {
+        0
+    } else {
+        sweep(&base, &plan)
+    };
+    Ok(report(&base, &policy, &plan, params.dry_run, freed))
+}
+
+/// Collect under the project's policy if it asks for automatic collection.
+/// The caller must hold the project exclusively.
+pub(crate) fn auto(base: &Path) {
+    let policy = Policy::load(base);
+    if !policy.auto {
+        return;
+    }
+    let Ok(_workspace) = workspace::acquire(base, METHOD) else {
+        return;
+    };
+    let plan = plan(base, &policy);
+    if plan.items.is_empty() {
+        return;
+    }
+    let freed = sweep(base, &plan);
+    info!(
+        target = "site2ts",
+        "gc removed {} items, {} bytes",
+        plan.items.len(),
+        freed
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn write(path: &Path, body: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, body).unwrap();
+    }
+
+    fn produce(base: &Path, tool: &str, params: Value, result: Value) {
+        lineage::check(base, tool, &params).unwrap();
+        lineage::record(base, tool, &params, &result, "J");
+    }
+
+    fn site_map(base: &Path, id: &str, hashes: &[&str]) {
+        let pages: Vec<Value> = hashes
+            .iter()
+            .map(|h| json!({ "url": h, "hash": h }))
+            .collect();
+        let manifest = json!({ "siteMapId": id, "pages": pages });
+        write(
+            &base.join(format!("cache/sitemaps/{}.json", id)),
+            &manifest.to_string(),
+        );
+        for h in hashes {
+            write(&base.join(format!("cache/crawl/{}/page.html", h)), "<html>");
+        }
+        produce(base, "crawl", json!({}), json!({ "siteMapId": id }));
+    }
+
+    fn generation(base: &Path, site_map: &str, n: u32) {
+        let (a, sc, g) = (format!("A{}", n), format!("SC{}", n), format!("G{}", n));
+        produce(
+            base,
+            "analyze",
+            json!({ "siteMapId": site_map }),
+            json!({ "analysisId": a }),
+        );
+        produce(
+            base,
+            "scaffold",
+            json!({ "analysisId": a }),
+            json!({ "scaffoldId": sc }),
+        );
+        produce(
+            base,
+            "generate",
+            json!({ "analysisId": a, "scaffoldId": sc }),
+            json!({ "generationId": g }),
+        );
+        write(
+            &base.join(format!("generations/{}/staging/package.json", g)),
+            "{}",
+        );
+    }
+
+    #[test]
+    fn gc_keeps_what_live_generations_need() {
+        let base = std::env::temp_dir().join(format!("site2ts-gc-{}", ulid::Ulid::new()));
+        site_map(&base, "S1", &["h1", "shared"]);
+        generation(&base, "S1", 1);
+        site_map(&base, "S2", &["h2", "shared"]);
+        generation(&base, "S2", 2);
+        generation(&base, "S2", 3);
+        site_map(&base, "S3", &["h3"]);
+        write(&base.join("cache/crawl/orphan/page.html"), "<html>");
+        for d in ["D1", "D2", "D3"] {
+            write(&base.join(format!("reports/diff/{}/report.json", d)), "{}");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        for key in ["k1", "k2"] {
+            write(
+                &base.join(format!("cache/deps/{}/node_modules/next/package.json", key)),
+                "{}",
+            );
+        }
+        #[cfg(unix)]
+        for (g, key) in [("G1", "k1"), ("G2", "k2"), ("G3", "k2")] {
+            std::os::unix::fs::symlink(
+                base.join(format!("cache/deps/{}/node_modules", key)),
+                base.join(format!("generations/{}/staging/node_modules", g)),
+            )
+            .unwrap();
+        }
+        write(&base.join("logs/OLD.ndjson"), "{}\n");
+        write(&base.join("logs/NEW.ndjson"), "{}\n");
+        File::options()
+            .write(true)
+            .open(base.join("logs/OLD.ndjson"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60))
+            .unwrap();
+
+        let policy = Policy {
+            keep_generations: 2,
+            keep_diffs: 1,
+            ..Default::default()
+        };
+        let planned = plan(&base, &policy);
+        let mut removed: Vec<(&str, String)> = planned
+            .items
+            .iter()
+            .map(|i| {
+                let name = i.path.file_name().unwrap().to_string_lossy().into_owned();
+                (i.kind, name)
+            })
+            .collect();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                ("crawl", "h1".to_string()),
+                ("crawl", "orphan".to_string()),
+                ("deps", "k1".to_string()),
+                ("diff", "D1".to_string()),
+                ("diff", "D2".to_string()),
+                ("generation", "G1".to_string()),
+                ("log", "OLD.ndjson".to_string()),
+                ("siteMap", "S1.json".to_string()),
+            ]
+        );
+        assert_eq!(planned.generations, vec!["G3", "G2"]);
+        assert_eq!(planned.site_maps, vec!["S2", "S3"]);
+        assert!(planned.items.iter().all(|i| i.path.exists() && i.bytes > 0));
+
+        let freed = sweep(&base, &planned);
+        assert_eq!(freed, planned.items.iter().map(|i| i.bytes).sum::<u64>());
+        assert!(planned.items.iter().all(|i| !i.path.exists()));
+        assert!(base.join("cache/crawl/shared").is_dir());
+        assert!(base.join("cache/deps/k2/node_modules").is_dir());
+        assert!(base.join("generations/G2").is_dir());
+        let err = lineage::check(&base, "diff", &json!({ "generationId": "G1" })).unwrap_err();
+        assert!(err.message().contains("removed by gc"));
+        let err = lineage::check(&base, "analyze", &json!({ "siteMapId": "S1" })).unwrap_err();
+        assert!(err.message().contains("removed by gc"));
+        lineage::check(&base, "diff", &json!({ "generationId": "G2" })).unwrap();
+        assert!(plan(&base, &policy).items.is_empty());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,499 @@
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use futures_util::stream::{self, Stream};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tracing::info;
+use ulid::Ulid;
+
+use crate::session::{Notifier, Session};
+use crate::{finish, handle_request, mcp, response_json, RpcError, RpcRequest};
+
+const SESSION_HEADER: &str = "mcp-session-id";
+const PROTOCOL_HEADER: &str = "mcp-protocol-version";
+const ENDPOINT: &str = "/mcp";
+
+/// A session with no open stream and nothing in flight is dropped once it
+/// has gone this long without a request; its client is taken to be gone.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// How often idle sessions are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct HttpSession {
+    session: Arc<Session>,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct HttpState {
+    sessions: Mutex<HashMap<String, HttpSession>>,
+}
+
+impl HttpState {
+    fn sessions(&self) -> MutexGuard<'_, HashMap<String, HttpSession>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn insert(&self, session: Arc<Session>) {
+        let entry = HttpSession {
+            session: session.clone(),
+            last_seen: Instant::now(),
+        };
+        self.sessions().insert(session.id().to_string(), entry);
+    }
+
+    /// The session `id`, marked as seen now.
+    fn session(&self, id: &str) -> Option<Arc<Session>> {
+        let mut sessions = self.sessions();
+        let entry = sessions.get_mut(id)?;
+        entry.last_seen = Instant::now();
+        Some(entry.session.clone())
+    }
+
+    /// Drop sessions unseen for `idle` that have no open stream and no
+    /// request in flight. Returns how many were dropped.
+    fn evict_idle(&self, idle: Duration) -> usize {
+        let mut sessions = self.sessions();
+        let before = sessions.len();
+        sessions.retain(|_, s| {
+            s.last_seen.elapsed() < idle
+                || s.session.notifier().is_open()
+                || !s.session.in_flight().is_empty()
+        });
+        before - sessions.len()
+    }
+}
+
+/// Evict idle sessions every SWEEP_INTERVAL until the server is gone.
+async fn sweep_idle(state: Weak<HttpState>) {
+    let mut tick = tokio::time::interval(SWEEP_INTERVAL);
+    tick.tick().await;
+    loop {
+        tick.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let evicted = state.evict_idle(SESSION_IDLE_TIMEOUT);
+        if evicted > 0 {
+            info!(target = "site2ts", "dropped {} idle http sessions", evicted);
+        }
+    }
+}
+
+/// Serve MCP over streamable HTTP on `addr` until the process exits.
+pub(crate) async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("bind {}", addr))?;
+    info!(
+        target = "site2ts",
+        "listening on http://{}{}", addr, ENDPOINT
+    );
+    axum::serve(listener, router()).await.context("http server")
+}
+
+fn router() -> Router {
+    let state = Arc::new(HttpState::default());
+    tokio::spawn(sweep_idle(Arc::downgrade(&state)));
+    Router::new()
+        .route(
+            ENDPOINT,
+            post(handle_post).get(handle_get).delete(handle_delete),
+        )
+        .with_state(state)
+}
+
+async fn handle_post(
+    State(state): State<Arc<HttpState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Some(resp) = reject_origin(&headers) {
+        return resp;
+    }
+    let value: Value = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            return rpc_error_response(
+                StatusCode::BAD_REQUEST,
+                RpcError::new(-32700, format!("parse error: {}", e), None),
+            )
+        }
+    };
+    if value.is_array() {
+        return rpc_error_response(
+            StatusCode::BAD_REQUEST,
+            RpcError::new(-32600, "batch requests are not supported", None),
+        );
+    }
+    // Responses from the client carry no method; we never issue requests,
+    // so there is nothing to route them to.
+    if value.get("method").is_none() {
+        return StatusCode::ACCEPTED.into_response();
+    }
+    let req: RpcRequest = match serde_json::from_value(value) {
+        Ok(r) => r,
+        Err(e) => {
+            return rpc_error_response(
+                StatusCode::BAD_REQUEST,
+                RpcError::new(-32600, format!("invalid request: {}", e), None),
+            )
+        }
+    };
+
+    let session = if req.method == "initialize" {
+        let session = Arc::new(Session::new(Ulid::new().to_string(), Notifier::default()));
+        state.insert(session.clone());
+        session
+    } else {
+        match session_from_headers(&state, &headers) {
+            Ok(s) => s,
+            Err((status, err)) => return rpc_error_response(status, err),
+        }
+    };
+
+    let id = req.id.clone();
+    if id.is_none() {
+        let session = session.clone();
+        let notifier = session.notifier();
+        tokio::spawn(async move { handle_request(req, &session, notifier).await });
+        return StatusCode::ACCEPTED.into_response();
+    }
+
+    let mut resp = if accepts_event_stream(&headers) {
+        // Stream this request's notifications, then its response.
+        let (tx, rx) = mpsc::unbounded_channel();
+        let notifier = Notifier::new(tx.clone());
+        let task_session = session.clone();
+        tokio::spawn(async move {
+            let res =
+                tokio::spawn(async move { handle_request(req, &task_session, notifier).await })
+                    .await;
+            let _ = tx.send(respond(id, res));
+        });
+        sse(rx).into_response()
+    } else {
+        let notifier = session.notifier();
+        let task_session = session.clone();
+        let res =
+            tokio::spawn(async move { handle_request(req, &task_session, notifier).await }).await;
+        Json(respond(id, res)).into_response()
+    };
+    if let Ok(v) = HeaderValue::from_str(session.id()) {
+        resp.headers_mut().insert(SESSION_HEADER, v);
+    }
+    resp
+}
+
+/// Open the session's standalone SSE stream for server-initiated messages.
+async fn handle_get(State(state): State<Arc<HttpState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = reject_origin(&headers) {
+        return resp;
+    }
+    if !accepts_event_stream(&headers) {
+        return StatusCode::METHOD_NOT_ALLOWED.into_response();
+    }
+    let session = match session_from_headers(&state, &headers) {
+        Ok(s) => s,
+        Err((status, err)) => return rpc_error_response(status, err),
+    };
+    let (tx, rx) = mpsc::unbounded_channel();
+    session.set_notifier(Notifier::new(tx));
+    sse(rx).into_response()
+}
+
+async fn handle_delete(State(state): State<Arc<HttpState>>, headers: HeaderMap) -> Response {
+    if let Some(resp) = reject_origin(&headers) {
+        return resp;
+    }
+    let session = match session_from_headers(&state, &headers) {
+        Ok(s) => s,
+        Err((status, err)) => return rpc_error_response(status, err),
+    };
+    state.sessions().remove(session.id());
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Requests always get a body; an unanswerable one is acknowledged empty.
+fn respond(
+    id: Option<Value>,
+    res: std::result::Result<Option<crate::RpcResult<Value>>, tokio::task::JoinError>,
+) -> Value {
+    let fallback = id.clone();
+    finish(id, res).unwrap_or_else(|| response_json(fallback, Ok(json!({}))))
+}
+
+fn sse(
+    rx: mpsc::UnboundedReceiver<Value>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    let events = stream::unfold(rx, |mut rx| async move {
+        let msg = rx.recv().await?;
+        Some((
+            Ok(Event::default().event("message").data(msg.to_string())),
+            rx,
+        ))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+fn session_from_headers(
+    state: &HttpState,
+    headers: &HeaderMap,
+) -> std::result::Result<Arc<Session>, (StatusCode, RpcError)> {
+    let id = headers
+        .get(SESSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                RpcError::new(-32600, "missing Mcp-Session-Id header", None),
+            )
+        })?;
+    let session = state.session(id).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            RpcError::new(-32600, "unknown or expired session", None),
+        )
+    })?;
+    check_protocol_version(&session, headers)?;
+    Ok(session)
+}
+
+/// Requests after `initialize` may name the protocol version in use; it
+/// must be the one negotiated. Without the header the client is taken to
+/// speak it.
+fn check_protocol_version(
+    session: &Session,
+    headers: &HeaderMap,
+) -> std::result::Result<(), (StatusCode, RpcError)> {
+    let Some(version) = headers.get(PROTOCOL_HEADER) else {
+        return Ok(());
+    };
+    let version = version.to_str().unwrap_or("");
+    let message = match session.protocol_version() {
+        Some(negotiated) if negotiated != version => format!(
+            "MCP-Protocol-Version {} does not match the negotiated {}",
+            version, negotiated
+        ),
+        None if !mcp::supports_protocol(version) => {
+            format!("unsupported MCP-Protocol-Version {}", version)
+        }
+        _ => return Ok(()),
+    };
+    Err((
+        StatusCode::BAD_REQUEST,
+        RpcError::new(-32600, message, None),
+    ))
+}
+
+fn accepts_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("text/event-stream"))
+}
+
+/// Guard against DNS rebinding: browsers always send `Origin`, and only
+/// local pages may talk to this server.
+fn reject_origin(headers: &HeaderMap) -> Option<Response> {
+    let origin = headers.get(header::ORIGIN)?.to_str().unwrap_or("");
+    let host = origin
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(origin);
+    let host = if host.starts_with('[') {
+        host.split_inclusive(']').next().unwrap_or(host)
+    } else {
+        host.split(':').next().unwrap_or(host)
+    };
+    if matches!(host, "localhost" | "127.0.0.1" | "[::1]") {
+        None
+    } else {
+        Some(StatusCode::FORBIDDEN.into_response())
+    }
+}
+
+fn rpc_error_response(status: StatusCode, err: RpcError) -> Response {
+    (status, Json(response_json(None, Err(err)))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn spawn_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router()).await });
+        format!("http://{}{}", addr, ENDPOINT)
+    }
+
+    fn rpc(method: &str, id: u64) -> Value {
+        json!({ "jsonrpc": "2.0", "method": method, "params": {}, "id": id })
+    }
+
+    #[tokio::test]
+    async fn session_lifecycle_over_http() {
+        let url = spawn_server().await;
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .post(&url)
+            .json(&rpc("initialize", 1))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let session = resp.headers()[SESSION_HEADER].to_str().unwrap().to_string();
+        let body: Value = resp.json().await.unwrap();
+        assert!(body["result"]["capabilities"]["tools"].is_object());
+
+        let resp = client
+            .post(&url)
+            .json(&rpc("tools/list", 2))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 400);
+
+        let resp = client
+            .post(&url)
+            .header(SESSION_HEADER, &session)
+            .json(&rpc("tools/list", 3))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["id"], 3);
+        assert!(!body["result"]["tools"].as_array().unwrap().is_empty());
+
+        for (version, status) in [(mcp::LATEST_PROTOCOL_VERSION, 200), ("2024-11-05", 400)] {
+            let resp = client
+                .post(&url)
+                .header(SESSION_HEADER, &session)
+                .header(PROTOCOL_HEADER, version)
+                .json(&rpc("ping", 3))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), status, "{}", version);
+        }
+
+        let resp = client
+            .post(&url)
+            .header(SESSION_HEADER, &session)
+            .json(&json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 202);
+
+        let resp = client
+            .delete(&url)
+            .header(SESSION_HEADER, &session)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 204);
+        let resp = client
+            .post(&url)
+            .header(SESSION_HEADER, &session)
+            .json(&rpc("ping", 4))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn post_streams_response_as_sse_when_accepted() {
+        let url = spawn_server().await;
+        let client = reqwest::Client::new();
+        let resp = client
+            .post(&url)
+            .json(&rpc("initialize", 1))
+            .send()
+            .await
+            .unwrap();
+        let session = resp.headers()[SESSION_HEADER].to_str().unwrap().to_string();
+
+        let resp = client
+            .post(&url)
+            .header(SESSION_HEADER, &session)
+            .header(header::ACCEPT, "application/json, text/event-stream")
+            .json(&rpc("ping", 2))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/event-stream"));
+        let text = resp.text().await.unwrap();
+        assert!(text.contains("event: message"));
+        assert!(text.contains(r#""id":2"#));
+    }
+
+    #[test]
+    fn idle_sessions_without_streams_or_requests_are_evicted() {
+        let state = HttpState::default();
+        let idle = Arc::new(Session::new("idle", Notifier::default()));
+        let streaming = Arc::new(Session::new("streaming", Notifier::default()));
+        let (tx, rx) = mpsc::unbounded_channel();
+        streaming.set_notifier(Notifier::new(tx));
+        let busy = Arc::new(Session::new("busy", Notifier::default()));
+        let _request = busy.begin(&json!(1), "tools/call");
+        for session in [&idle, &streaming, &busy] {
+            state.insert(session.clone());
+        }
+
+        assert_eq!(state.evict_idle(Duration::from_secs(60)), 0);
+        assert_eq!(state.evict_idle(Duration::ZERO), 1);
+        assert!(state.session("idle").is_none());
+        assert!(state.session("busy").is_some());
+
+        // A closed stream no longer keeps its session.
+        drop(rx);
+        assert_eq!(state.evict_idle(Duration::ZERO), 1);
+        assert!(state.session("streaming").is_none());
+    }
+
+    #[tokio::test]
+    async fn foreign_origins_are_rejected() {
+        let url = spawn_server().await;
+        let client = reqwest::Client::new();
+        let resp = client
+            .post(&url)
+            .header(header::ORIGIN, "https://evil.example")
+            .json(&rpc("initialize", 1))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 403);
+        let resp = client
+            .post(&url)
+            .header(header::ORIGIN, "http://localhost:3000")
+            .json(&rpc("initialize", 1))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+    }
+}
@@ -0,0 +1,603 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::worker::CANCELLED_CODE;
+use crate::workspace;
+use crate::{RpcError, RpcResult};
+
+/// Page size for `jobs/list` when the caller gives no `limit`.
+const DEFAULT_LIMIT: usize = 50;
+
+/// `jobs/wait` gives up after this long unless the caller says otherwise.
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+
+/// Progress is written to the job record at most this often.
+const PROGRESS_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+    /// Still marked running by a server process that is gone.
+    Interrupted,
+}
+
+/// One tool call, persisted as `jobs/<jobId>.json` in the sandbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Job {
+    job_id: String,
+    method: String,
+    params: Value,
+    status: JobStatus,
+    started_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ended_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<i64>,
+    /// Latest worker progress: `current`/`total`, `phase`, `detail`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    progress: Option<Value>,
+    /// The result with arrays reduced to their length; the full result is
+    /// kept in `jobs/results/<jobId>.json`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<Value>,
+    /// Ids the call produced, e.g. `siteMapId` or `generationId`.
+    #[serde(default)]
+    entities: BTreeMap<String, String>,
+    /// Server process that ran the job, and the host it ran on. Records
+    /// from before hosts were stored count as this host's.
+    pid: u32,
+    #[serde(default)]
+    host: String,
+}
+
+impl Job {
+    pub(crate) fn start(job_id: &str, method: &str, params: &Value) -> Self {
+        let mut params = params.clone();
+        if let Some(obj) = params.as_object_mut() {
+            obj.remove("_meta");
+        }
+        Self {
+            job_id: job_id.to_string(),
+            method: method.to_string(),
+            params,
+            status: JobStatus::Running,
+            started_at: Utc::now().to_rfc3339(),
+            ended_at: None,
+            duration_ms: None,
+            progress: None,
+            result: None,
+            error: None,
+            entities: BTreeMap::new(),
+            pid: std::process::id(),
+            host: workspace::hostname(),
+        }
+    }
+
+    pub(crate) fn finish(&mut self, res: &RpcResult<Value>) {
+        let now = Utc::now();
+        self.duration_ms = parse_time(&self.started_at).map(|t| (now - t).num_milliseconds());
+        self.ended_at = Some(now.to_rfc3339());
+        match res {
+            Ok(v) => {
+                self.status = JobStatus::Succeeded;
+                self.entities = entities(v);
+                self.result = Some(summarize(v));
+            }
+            Err(e) => {
+                self.status = if e.code() == CANCELLED_CODE {
+                    JobStatus::Cancelled
+                } else {
+                    JobStatus::Failed
+                };
+                self.error = Some(e.to_json());
+            }
+        }
+    }
+
+    fn save(&self, base: &Path) -> Result<()> {
+        write_atomic(&job_path(base, &self.job_id), &serde_json::to_value(self)?)
+    }
+
+    /// What `jobs/status` and `jobs/wait` report.
+    fn status_view(&self) -> Value {
+        json!({
+            "jobId": self.job_id,
+            "method": self.method,
+            "status": self.status,
+            "startedAt": self.started_at,
+            "endedAt": self.ended_at,
+            "durationMs": self.duration_ms,
+            "progress": self.progress,
+        })
+    }
+
+    fn load(path: &Path) -> Option<Self> {
+        let mut job: Self = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+        if job.status == JobStatus::Running && job.owner_gone() {
+            job.status = JobStatus::Interrupted;
+        }
+        Some(job)
+    }
+
+    /// Whether the server that ran the job has exited. Another server may
+    /// run jobs on the same project; a job on another host is assumed to
+    /// be running, since its process cannot be checked from here.
+    fn owner_gone(&self) -> bool {
+        let here = workspace::hostname();
+        if !self.host.is_empty() && self.host != here {
+            return false;
+        }
+        self.pid != std::process::id() && !workspace::pid_alive(self.pid)
+    }
+}
+
+/// A job running in this process. The task running it reports progress and
+/// the outcome here; `jobs/*` pollers read it and wait on `done`.
+#[derive(Debug)]
+pub(crate) struct LiveJob {
+    base: PathBuf,
+    job: Mutex<Job>,
+    saved_at: Mutex<Instant>,
+    done: watch::Sender<bool>,
+}
+
+/// Jobs running in this process, by id.
+fn live() -> MutexGuard<'static, HashMap<String, Arc<LiveJob>>> {
+    static LIVE: OnceLock<Mutex<HashMap<String, Arc<LiveJob>>>> = OnceLock::new();
+    LIVE.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+impl LiveJob {
+    /// Record a new job under `base` and make it visible to pollers.
+    pub(crate) fn start(base: &Path, job_id: &str, method: &str, params: &Value) -> Arc<Self> {
+        let job = Job::start(job_id, method, params);
+        save(base, &job);
+        let live_job = Arc::new(Self {
+            base: base.to_path_buf(),
+            job: Mutex::new(job),
+            saved_at: Mutex::new(Instant::now()),
+            done: watch::channel(false).0,
+        });
+        live().insert(job_id.to_string(), live_job.clone());
+        live_job
+    }
+
+    /// Keep the latest worker `emitProgress` payload on the job.
+    pub(crate) fn progress(&self, payload: &Value) {
+        let mut progress = json!({ "updatedAt": Utc::now().to_rfc3339() });
+        for key in ["tool", "phase", "current", "total", "detail"] {
+            if let Some(v) = payload.get(key) {
+                progress[key] = v.clone();
+            }
+        }
+        let mut job = self.job();
+        job.progress = Some(progress);
+        let mut saved_at = self.saved_at.lock().unwrap_or_else(|e| e.into_inner());
+        if saved_at.elapsed() >= PROGRESS_SAVE_INTERVAL {
+            save(&self.base, &job);
+            *saved_at = Instant::now();
+        }
+    }
+
+    /// Store the outcome and wake everyone waiting on the job.
+    pub(crate) fn finish(&self, res: &RpcResult<Value>) {
+        let job_id = {
+            let mut job = self.job();
+            job.finish(res);
+            if let Ok(result) = res {
+                let path = result_path(&self.base, &job.job_id);
+                if let Err(e) = write_atomic(&path, result) {
+                    warn!(target = "site2ts", "saving job result failed: {:#}", e);
+                }
+            }
+            save(&self.base, &job);
+            job.job_id.clone()
+        };
+        self.done.send_replace(true);
+        live().remove(&job_id);
+    }
+
+    fn job(&self) -> MutexGuard<'_, Job> {
+        self.job.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Job records are bookkeeping; failing to write one must not fail the call.
+fn save(base: &Path, job: &Job) {
+    if let Err(e) = job.save(base) {
+        warn!(target = "site2ts", "recording job failed: {:#}", e);
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListParams {
+    /// Tool name, e.g. `crawl`.
+    #[serde(default)]
+    phase: Option<String>,
+    #[serde(default)]
+    status: Option<JobStatus>,
+    /// RFC 3339; jobs started at or after this time.
+    #[serde(default)]
+    since: Option<String>,
+    /// RFC 3339; jobs started before this time.
+    #[serde(default)]
+    until: Option<String>,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GetParams {
+    job_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WaitParams {
+    job_id: String,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+/// Jobs matching the filters, newest first.
+pub(crate) fn list(base: &Path, params: ListParams) -> Result<Value, RpcError> {
+    let since = params.since.as_deref().map(bound).transpose()?;
+    let until = params.until.as_deref().map(bound).transpose()?;
+    let mut jobs: Vec<(DateTime<Utc>, Job)> = fs::read_dir(base.join("jobs"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|x| x == "json"))
+        .filter_map(|p| Job::load(&p))
+        .filter_map(|j| Some((parse_time(&j.started_at)?, j)))
+        .filter(|(_, j)| params.phase.as_ref().is_none_or(|p| *p == j.method))
+        .filter(|(_, j)| params.status.is_none_or(|s| s == j.status))
+        .filter(|(t, _)| since.is_none_or(|s| *t >= s) && until.is_none_or(|u| *t < u))
+        .collect();
+    jobs.sort_by(|(ta, a), (tb, b)| tb.cmp(ta).then_with(|| b.job_id.cmp(&a.job_id)));
+    let total = jobs.len();
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    let jobs: Vec<Job> = jobs.into_iter().take(limit).map(|(_, j)| j).collect();
+    Ok(json!({ "jobs": jobs, "total": total }))
+}
+
+pub(crate) fn get(base: &Path, params: GetParams) -> Result<Value, RpcError> {
+    let job = find(base, &params.job_id)?;
+    serde_json::to_value(job).map_err(|e| RpcError::internal(e.to_string()))
+}
+
+pub(crate) fn status(base: &Path, params: GetParams) -> Result<Value, RpcError> {
+    Ok(find(base, &params.job_id)?.status_view())
+}
+
+/// Wait until the job ends or `timeoutMs` passes, then report its status.
+pub(crate) async fn wait(base: &Path, params: WaitParams) -> Result<Value, RpcError> {
+    let running = live().get(&params.job_id).cloned();
+    if let Some(job) = running {
+        let timeout = params
+            .timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_WAIT);
+        let mut done = job.done.subscribe();
+        let _ = tokio::time::timeout(timeout, done.wait_for(|d| *d)).await;
+    }
+    Ok(find(base, &params.job_id)?.status_view())
+}
+
+/// The full result or error of a finished job. Running jobs report their
+/// status and progress instead.
+pub(crate) fn result(base: &Path, params: GetParams) -> Result<Value, RpcError> {
+    let job = find(base, &params.job_id)?;
+    let mut out = json!({ "jobId": job.job_id, "status": job.status });
+    match job.status {
+        JobStatus::Running => out["progress"] = json!(job.progress),
+        JobStatus::Succeeded => {
+            let path = result_path(base, &job.job_id);
+            let result: Value = fs::read(&path)
+                .ok()
+                .and_then(|b| serde_json::from_slice(&b).ok())
+                .ok_or_else(|| {
+                    RpcError::internal(format!("job result missing: {}", path.display()))
+                })?;
+            out["result"] = result;
+        }
+        JobStatus::Failed | JobStatus::Cancelled => out["error"] = json!(job.error),
+        JobStatus::Interrupted => {}
+    }
+    Ok(out)
+}
+
+/// Whether `job_id` is still running in this process.
+pub(crate) fn is_running(job_id: &str) -> bool {
+    live().contains_key(job_id)
+}
+
+/// The current record of `job_id`: live if it runs here, else from disk.
+fn find(base: &Path, job_id: &str) -> Result<Job, RpcError> {
+    let not_found = || RpcError::invalid_params(format!("unknown jobId: {}", job_id));
+    if !is_job_id(job_id) {
+        return Err(not_found());
+    }
+    let running = live().get(job_id).cloned();
+    if let Some(job) = running {
+        return Ok(job.job().clone());
+    }
+    Job::load(&job_path(base, job_id)).ok_or_else(not_found)
+}
+
+fn job_path(base: &Path, job_id: &str) -> PathBuf {
+    base.join("jobs").join(format!("{}.json", job_id))
+}
+
+fn result_path(base: &Path, job_id: &str) -> PathBuf {
+    base.join("jobs")
+        .join("results")
+        .join(format!("{}.json", job_id))
+}
+
+/// Write `value` to `path` so readers never see a partial file.
+pub(crate) fn write_atomic(path: &Path, value: &Value) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("creating dir {}", dir.display()))?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?)
+        .with_context(|| format!("write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("rename to {}", path.display()))
+}
+
+pub(crate) fn is_job_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn parse_time(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+fn bound(s: &str) -> Result<DateTime<Utc>, RpcError> {
+    parse_time(s).ok_or_else(|| {
+        RpcError::invalid_params(format!("expected an RFC 3339 timestamp, got {:?}", s))
+    })
+}
+
+/// Top-level `...Id` fields of a result, other than the job's own id.
+fn entities(result: &Value) -> BTreeMap<String, String> {
+    result
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(k, _)| k.ends_with("Id") && *k != "jobId")
+        .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+        .collect()
+}
+
+/// Keep a result small enough to list: arrays become their length.
+pub(crate) fn summarize(result: &Value) -> Value {
+    match result {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), summarize(v)))
+                .collect::<Map<_, _>>(),
+        ),
+        Value::Array(items) => json!(items.len()),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ulid::Ulid;
+
+    fn temp_base() -> PathBuf {
+        std::env::temp_dir().join(format!("site2ts-jobs-{}", Ulid::new()))
+    }
+
+    fn record(base: &Path, id: &str, method: &str, res: RpcResult<Value>, started: &str) {
+        let mut job = Job::start(
+            id,
+            method,
+            &json!({ "x": 1, "_meta": { "progressToken": 1 } }),
+        );
+        job.started_at = started.to_string();
+        job.finish(&res);
+        job.save(base).unwrap();
+    }
+
+    #[test]
+    fn jobs_are_recorded_and_filtered() {
+        let base = temp_base();
+        record(
+            &base,
+            "A",
+            "crawl",
+            Ok(json!({ "jobId": "A", "siteMapId": "S1", "pages": [1, 2, 3] })),
+            "2026-01-01T00:00:00Z",
+        );
+        record(
+            &base,
+            "B",
+            "analyze",
+            Err(RpcError::new(-32001, "run crawl first", None)),
+            "2026-01-02T00:00:00Z",
+        );
+        record(
+            &base,
+            "C",
+            "crawl",
+            Err(RpcError::new(CANCELLED_CODE, "request cancelled", None)),
+            "2026-01-03T00:00:00Z",
+        );
+
+        let a = get(&base, GetParams { job_id: "A".into() }).unwrap();
+        assert_eq!(a["status"], "succeeded");
+        assert_eq!(a["params"], json!({ "x": 1 }));
+        assert_eq!(a["entities"], json!({ "siteMapId": "S1" }));
+        assert_eq!(a["result"]["pages"], 3);
+        assert!(get(
+            &base,
+            GetParams {
+                job_id: "../A".into()
+            }
+        )
+        .is_err());
+        assert!(get(&base, GetParams { job_id: "Z".into() }).is_err());
+
+        let ids = |params: ListParams| -> Vec<String> {
+            list(&base, params).unwrap()["jobs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|j| j["jobId"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(ids(ListParams::default()), ["C", "B", "A"]);
+        let crawls = ListParams {
+            phase: Some("crawl".into()),
+            ..Default::default()
+        };
+        assert_eq!(ids(crawls), ["C", "A"]);
+        let failed = ListParams {
+            status: Some(JobStatus::Failed),
+            ..Default::default()
+        };
+        assert_eq!(ids(failed), ["B"]);
+        let window = ListParams {
+            since: Some("2026-01-02T00:00:00Z".into()),
+            until: Some("2026-01-03T00:00:00Z".into()),
+            ..Default::default()
+        };
+        assert_eq!(ids(window), ["B"]);
+        let bad = ListParams {
+            since: Some("yesterday".into()),
+            ..Default::default()
+        };
+        assert_eq!(list(&base, bad).unwrap_err().code(), -32602);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn live_jobs_expose_progress_and_wake_waiters() {
+        let base = temp_base();
+        let job = LiveJob::start(
+            &base,
+            "L1",
+            "crawl",
+            &json!({ "startUrl": "https://a.test" }),
+        );
+        job.progress(&json!({ "tool": "crawl", "phase": "fetch", "current": 3, "total": 50 }));
+
+        let status = status(
+            &base,
+            GetParams {
+                job_id: "L1".into(),
+            },
+        )
+        .unwrap();
+        assert_eq!(status["status"], "running");
+        assert_eq!(status["progress"]["current"], 3);
+        assert_eq!(status["progress"]["total"], 50);
+        let early = result(
+            &base,
+            GetParams {
+                job_id: "L1".into(),
+            },
+        )
+        .unwrap();
+        assert_eq!(early["status"], "running");
+        assert!(early.get("result").is_none());
+
+        let quick = WaitParams {
+            job_id: "L1".into(),
+            timeout_ms: Some(10),
+        };
+        assert_eq!(wait(&base, quick).await.unwrap()["status"], "running");
+
+        let waiter = tokio::spawn({
+            let base = base.clone();
+            async move {
+                let params = WaitParams {
+                    job_id: "L1".into(),
+                    timeout_ms: Some(5_000),
+                };
+                wait(&base, params).await.unwrap()
+            }
+        });
+        tokio::task::yield_now().await;
+        job.finish(&Ok(
+            json!({ "siteMapId": "S", "pages": [{ "url": "https://a.test/" }] }),
+        ));
+        let done = waiter.await.unwrap();
+        assert_eq!(done["status"], "succeeded");
+        assert_eq!(done["progress"]["phase"], "fetch");
+
+        let full = result(
+            &base,
+            GetParams {
+                job_id: "L1".into(),
+            },
+        )
+        .unwrap();
+        assert_eq!(full["result"]["pages"][0]["url"], "https://a.test/");
+        assert_eq!(
+            list(&base, ListParams::default()).unwrap()["total"],
+            1,
+            "results are not listed as jobs"
+        );
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn running_jobs_of_a_previous_server_are_interrupted() {
+        let base = temp_base();
+        let status = || get(&base, GetParams { job_id: "R".into() }).unwrap()["status"].clone();
+        let mut job = Job::start("R", "diff", &json!({}));
+        job.save(&base).unwrap();
+        assert_eq!(status(), "running");
+
+        // Another server still running on this project.
+        job.pid = std::os::unix::process::parent_id();
+        job.save(&base).unwrap();
+        assert_eq!(status(), "running");
+
+        // One on another host cannot be checked.
+        job.pid = u32::MAX;
+        job.host = "ci-runner".into();
+        job.save(&base).unwrap();
+        assert_eq!(status(), "running");
+
+        job.host = workspace::hostname();
+        job.save(&base).unwrap();
+        assert_eq!(status(), "interrupted");
+        job.host = String::new();
+        job.save(&base).unwrap();
+        assert_eq!(status(), "interrupted", "records without a host are local");
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
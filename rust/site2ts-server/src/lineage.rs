@@ -0,0 +1,478 @@
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use tracing::warn;
+
+use crate::jobs;
+use crate::{RpcError, RpcResult};
+
+/// What an id names. Each kind comes from one tool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Kind {
+    SiteMap,
+    Analysis,
+    Scaffold,
+    Generation,
+    Diff,
+    Audit,
+    Apply,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::SiteMap => "siteMap",
+            Kind::Analysis => "analysis",
+            Kind::Scaffold => "scaffold",
+            Kind::Generation => "generation",
+            Kind::Diff => "diff",
+            Kind::Audit => "audit",
+            Kind::Apply => "apply",
+        }
+    }
+
+    /// The tool that produces this kind.
+    fn producer(self) -> &'static str {
+        match self {
+            Kind::SiteMap => "crawl",
+            Kind::Analysis => "analyze",
+            Kind::Scaffold => "scaffold",
+            Kind::Generation => "generate",
+            Kind::Diff => "diff",
+            Kind::Audit => "audit",
+            Kind::Apply => "apply",
+        }
+    }
+
+    /// Kinds whose artifacts live in the one staging dir, so each new one
+    /// replaces the last.
+    fn exclusive(self) -> bool {
+        matches!(self, Kind::Analysis | Kind::Scaffold)
+    }
+
+    /// Kinds that keep their own copy of what they were made from, so
+    /// replacing an ancestor leaves them usable.
+    fn self_contained(self) -> bool {
+        self == Kind::Generation
+    }
+}
+
+/// Id params each tool takes and the kind each must name.
+fn inputs(tool: &str) -> &'static [(&'static str, Kind)] {
+    match tool {
+        "analyze" => &[("siteMapId", Kind::SiteMap)],
+        "scaffold" => &[("analysisId", Kind::Analysis)],
+        "generate" => &[
+            ("analysisId", Kind::Analysis),
+            ("scaffoldId", Kind::Scaffold),
+        ],
+        "diff" | "audit" | "apply" | "pack" | "improve" => &[("generationId", Kind::Generation)],
+        "assets" => &[
+            ("siteMapId", Kind::SiteMap),
+            ("generationId", Kind::Generation),
+        ],
+        _ => &[],
+    }
+}
+
+/// The result field holding the id a tool produces, and its kind. `apply`
+/// has no entity id of its own; its job id stands in.
+fn output(tool: &str) -> Option<(&'static str, Kind)> {
+    Some(match tool {
+        "crawl" => ("siteMapId", Kind::SiteMap),
+        "analyze" => ("analysisId", Kind::Analysis),
+        "scaffold" => ("scaffoldId", Kind::Scaffold),
+        "generate" => ("generationId", Kind::Generation),
+        "diff" => ("diffId", Kind::Diff),
+        "audit" => ("auditId", Kind::Audit),
+        "apply" => ("jobId", Kind::Apply),
+        _ => return None,
+    })
+}
+
+/// One id a tool produced and the ids it was made from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entity {
+    id: String,
+    kind: Kind,
+    job_id: String,
+    /// Input param name to id, e.g. `analysisId` for a scaffold.
+    parents: BTreeMap<String, String>,
+    created_at: String,
+    /// The newer id of the same kind that replaced this one in staging.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    superseded_by: Option<String>,
+    /// When `gc` deleted its artifacts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    removed_at: Option<String>,
+}
+
+/// Every id the project has produced, persisted as `lineage.json` in the
+/// sandbox.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Registry {
+    entities: BTreeMap<String, Entity>,
+}
+
+/// Serializes read-modify-write of the registry file.
+fn lock() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Registry {
+    fn path(base: &Path) -> PathBuf {
+        base.join("lineage.json")
+    }
+
+    fn load(base: &Path) -> Self {
+        let Ok(bytes) = fs::read(Self::path(base)) else {
+            return Self::default();
+        };
+        serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            warn!(target = "site2ts", "lineage registry unreadable: {}", e);
+            Self::default()
+        })
+    }
+
+    fn save(&self, base: &Path) -> Result<()> {
+        jobs::write_atomic(&Self::path(base), &serde_json::to_value(self)?)
+    }
+
+    /// Why `id` can no longer be used, if it can't: it, or an id it was
+    /// made from, has been superseded or garbage collected.
+    fn stale_reason(&self, id: &str) -> Option<String> {
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([id]);
+        while let Some(cur) = queue.pop_front() {
+            if !seen.insert(cur) {
+                continue;
+            }
+            let Some(e) = self.entities.get(cur) else {
+                continue;
+            };
+            if e.removed_at.is_some() {
+                return Some(if e.id == id {
+                    "removed by gc".to_string()
+                } else {
+                    format!("its {} {} was removed by gc", e.kind.name(), e.id)
+                });
+            }
+            if let Some(by) = &e.superseded_by {
+                return Some(if e.id == id {
+                    format!("superseded by {} {}", e.kind.name(), by)
+                } else {
+                    format!("its {} {} was superseded by {}", e.kind.name(), e.id, by)
+                });
+            }
+            if !e.kind.self_contained() {
+                queue.extend(e.parents.values().map(String::as_str));
+            }
+        }
+        None
+    }
+
+    /// `id` followed by its ancestors, nearest first.
+    fn lineage(&self, id: &str) -> Vec<&Entity> {
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([id]);
+        let mut out = Vec::new();
+        while let Some(cur) = queue.pop_front() {
+            if !seen.insert(cur) {
+                continue;
+            }
+            if let Some(e) = self.entities.get(cur) {
+                queue.extend(e.parents.values().map(String::as_str));
+                out.push(e);
+            }
+        }
+        out
+    }
+
+    /// Everything made from `id`, directly or not, nearest first.
+    fn descendants(&self, id: &str) -> Vec<&Entity> {
+        let mut seen = HashSet::from([id]);
+        let mut queue = VecDeque::from([id]);
+        let mut out = Vec::new();
+        while let Some(cur) = queue.pop_front() {
+            for e in self.entities.values() {
+                if e.parents.values().any(|p| p == cur) && seen.insert(&e.id) {
+                    queue.push_back(&e.id);
+                    out.push(e);
+                }
+            }
+        }
+        out
+    }
+
+    fn check_id(&self, field: &str, kind: Kind, id: &str) -> RpcResult<()> {
+        let details = json!({ "field": field, "id": id });
+        let Some(entity) = self.entities.get(id) else {
+            return Err(RpcError::bad_input(
+                format!("unknown {}: {}; run {} first", field, id, kind.producer()),
+                details,
+            ));
+        };
+        if entity.kind != kind {
+            return Err(RpcError::bad_input(
+                format!(
+                    "{} {} is a {} id, not a {} id",
+                    field,
+                    id,
+                    entity.kind.name(),
+                    kind.name()
+                ),
+                json!({ "field": field, "id": id, "kind": entity.kind }),
+            ));
+        }
+        if let Some(reason) = self.stale_reason(id) {
+            return Err(RpcError::bad_input(
+                format!("{} {} is stale: {}", field, id, reason),
+                json!({ "field": field, "id": id, "stale": reason }),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reject the ids in `params` that the registry does not know, that name
+/// the wrong kind, or that staging no longer holds.
+pub(crate) fn check(base: &Path, tool: &str, params: &Value) -> RpcResult<()> {
+    let fields = inputs(tool);
+    if fields.is_empty() {
+        return Ok(());
+    }
+    let _lock = lock();
+    let registry = Registry::load(base);
+    for (field, kind) in fields {
+        if let Some(id) = params.get(field).and_then(Value::as_str) {
+            registry.check_id(field, *kind, id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Register the id `tool` produced, linked to the ids it was given. A new
+/// analysis, scaffold or generation supersedes the previous one.
+pub(crate) fn record(base: &Path, tool: &str, params: &Value, result: &Value, job_id: &str) {
+    let Some((field, kind)) = output(tool) else {
+        return;
+    };
+    let job_id = result
+        .get("jobId")
+        .and_then(Value::as_str)
+        .unwrap_or(job_id);
+    let id = match result.get(field).and_then(Value::as_str) {
+        Some(id) => id,
+        None if kind == Kind::Apply => job_id,
+        None => return,
+    };
+    let parents = inputs(tool)
+        .iter()
+        .filter_map(|(f, _)| Some((f.to_string(), params.get(f)?.as_str()?.to_string())))
+        .collect();
+
+    let _lock = lock();
+    let mut registry = Registry::load(base);
+    if kind.exclusive() {
+        for e in registry.entities.values_mut() {
+            if e.kind == kind && e.id != id && e.superseded_by.is_none() {
+                e.superseded_by = Some(id.to_string());
+            }
+        }
+    }
+    registry.entities.insert(
+        id.to_string(),
+        Entity {
+            id: id.to_string(),
+            kind,
+            job_id: job_id.to_string(),
+            parents,
+            created_at: Utc::now().to_rfc3339(),
+            superseded_by: None,
+            removed_at: None,
+        },
+    );
+    if let Err(e) = registry.save(base) {
+        warn!(target = "site2ts", "recording lineage failed: {:#}", e);
+    }
+}
+
+/// Whether `id` is known and still usable.
+pub(crate) fn is_current(base: &Path, id: &str) -> bool {
+    let _lock = lock();
+    let registry = Registry::load(base);
+    registry.entities.contains_key(id) && registry.stale_reason(id).is_none()
+}
+
+/// Usable ids of `kind`, newest first.
+pub(crate) fn current(base: &Path, kind: Kind) -> Vec<String> {
+    let _lock = lock();
+    let registry = Registry::load(base);
+    let mut found: Vec<&Entity> = registry
+        .entities
+        .values()
+        .filter(|e| e.kind == kind && registry.stale_reason(&e.id).is_none())
+        .collect();
+    found.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+    found.into_iter().map(|e| e.id.clone()).collect()
+}
+
+/// The siteMaps `ids` were made from, directly or not.
+pub(crate) fn site_maps_of<'a>(
+    base: &Path,
+    ids: impl IntoIterator<Item = &'a str>,
+) -> HashSet<String> {
+    let _lock = lock();
+    let registry = Registry::load(base);
+    ids.into_iter()
+        .flat_map(|id| registry.lineage(id))
+        .filter(|e| e.kind == Kind::SiteMap)
+        .map(|e| e.id.clone())
+        .collect()
+}
+
+/// The kind of `id` if the registry knows it and gc has not removed it.
+pub(crate) fn kind_of(base: &Path, id: &str) -> Option<Kind> {
+    let _lock = lock();
+    let registry = Registry::load(base);
+    let e = registry.entities.get(id)?;
+    e.removed_at.is_none().then_some(e.kind)
+}
+
+/// Record that `gc` deleted the artifacts of `ids`; they, and ids that
+/// still depended on them, are stale from now on.
+pub(crate) fn mark_removed(base: &Path, ids: &[String]) {
+    let _lock = lock();
+    let mut registry = Registry::load(base);
+    let now = Utc::now().to_rfc3339();
+    let mut changed = false;
+    for id in ids {
+        if let Some(e) = registry.entities.get_mut(id) {
+            e.removed_at.get_or_insert_with(|| now.clone());
+            changed = true;
+        }
+    }
+    if !changed {
+        return;
+    }
+    if let Err(e) = registry.save(base) {
+        warn!(
+            target = "site2ts",
+            "recording gc in lineage failed: {:#}", e
+        );
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct GetParams {
+    id: String,
+}
+
+/// `id` with everything it was made from, back to its crawl, and
+/// everything made from it.
+pub(crate) fn get(base: &Path, params: GetParams) -> RpcResult<Value> {
+    let _lock = lock();
+    let registry = Registry::load(base);
+    let mut lineage = registry.lineage(&params.id);
+    if lineage.is_empty() {
+        return Err(RpcError::invalid_params(format!(
+            "unknown id: {}",
+            params.id
+        )));
+    }
+    let entity = lineage.remove(0);
+    let site_map = std::iter::once(entity)
+        .chain(lineage.iter().copied())
+        .find(|e| e.kind == Kind::SiteMap);
+    Ok(json!({
+        "entity": entity,
+        "stale": registry.stale_reason(&params.id),
+        "siteMap": site_map,
+        "ancestors": lineage,
+        "descendants": registry.descendants(&params.id),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_base() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("site2ts-lineage-{}", ulid::Ulid::new()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn run(base: &Path, tool: &str, params: Value, result: Value) {
+        check(base, tool, &params).unwrap();
+        record(base, tool, &params, &result, "J");
+    }
+
+    #[test]
+    fn ids_are_checked_against_what_produced_them() {
+        let base = temp_base();
+        run(&base, "crawl", json!({}), json!({ "siteMapId": "S1" }));
+        run(
+            &base,
+            "analyze",
+            json!({ "siteMapId": "S1" }),
+            json!({ "analysisId": "A1" }),
+        );
+        run(
+            &base,
+            "scaffold",
+            json!({ "analysisId": "A1" }),
+            json!({ "scaffoldId": "SC1" }),
+        );
+        run(
+            &base,
+            "generate",
+            json!({ "analysisId": "A1", "scaffoldId": "SC1" }),
+            json!({ "generationId": "G1" }),
+        );
+
+        let err = check(&base, "apply", &json!({ "generationId": "G0" })).unwrap_err();
+        assert_eq!(err.data.as_ref().unwrap()["class"], "BAD_INPUT");
+        assert!(err.message().contains("run generate first"));
+        let err = check(&base, "diff", &json!({ "generationId": "S1" })).unwrap_err();
+        assert!(err.message().contains("siteMap id"));
+        check(&base, "diff", &json!({ "generationId": "G1" })).unwrap();
+
+        // A new analysis replaces staging, so the scaffold built on A1 goes
+        // stale; generation G1 has its own copy and stays usable.
+        run(
+            &base,
+            "analyze",
+            json!({ "siteMapId": "S1" }),
+            json!({ "analysisId": "A2" }),
+        );
+        let err = check(
+            &base,
+            "generate",
+            &json!({ "analysisId": "A2", "scaffoldId": "SC1" }),
+        )
+        .unwrap_err();
+        assert!(err
+            .message()
+            .contains("scaffoldId SC1 is stale: its analysis A1 was superseded by A2"));
+        let err = check(&base, "scaffold", &json!({ "analysisId": "A1" })).unwrap_err();
+        assert!(err.message().contains("superseded by analysis A2"));
+        assert!(!is_current(&base, "SC1"));
+        check(&base, "apply", &json!({ "generationId": "G1" })).unwrap();
+
+        let traced = get(&base, GetParams { id: "G1".into() }).unwrap();
+        assert_eq!(traced["siteMap"]["id"], "S1");
+        assert_eq!(traced["ancestors"].as_array().unwrap().len(), 3);
+        let traced = get(&base, GetParams { id: "S1".into() }).unwrap();
+        assert_eq!(traced["descendants"].as_array().unwrap().len(), 4);
+        fs::remove_dir_all(base).ok();
+    }
+}
@@ -3,13 +3,37 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::fs;
 use std::fs::OpenOptions;
-use std::io::{self, BufRead, Write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use tracing::{error, info, Level};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, Level};
 use tracing_subscriber::EnvFilter;
 use ulid::Ulid;
+mod backend;
+mod config;
+mod gc;
+mod http;
+mod jobs;
+mod lineage;
+mod mcp;
+mod paths;
+mod pins;
+mod pipeline;
+mod pool;
+mod progress;
+mod resources;
+mod session;
 mod worker;
-use worker::Worker;
+mod workspace;
+use backend::{Backend, WorkerBackend};
+use config::{ServerConfig, Transport};
+use jobs::LiveJob;
+use pool::pool;
+use progress::ProgressReporter;
+use session::{CancelToken, Notifier, ProjectSlot, Session};
 
 type RpcResult<T> = std::result::Result<T, RpcError>;
 
@@ -38,6 +62,38 @@ impl RpcError {
         Self::new(-32603, message.into(), None)
     }
 
+    /// An error in one of the spec's error classes, reported as `data.class`
+    /// alongside `details`.
+    fn classified(code: i32, class: &str, message: impl Into<String>, details: Value) -> Self {
+        let mut data = match details {
+            Value::Object(map) => Value::Object(map),
+            Value::Null => json!({}),
+            other => json!({ "details": other }),
+        };
+        data["class"] = json!(class);
+        Self::new(code, message, Some(data))
+    }
+
+    /// A required runtime piece (node, the worker build, browsers) is absent.
+    fn env_missing(message: impl Into<String>, details: Value) -> Self {
+        Self::classified(-32010, "ENV_MISSING", message, details)
+    }
+
+    /// An id or other param that cannot be used as given.
+    fn bad_input(message: impl Into<String>, details: Value) -> Self {
+        Self::classified(-32011, "BAD_INPUT", message, details)
+    }
+
+    /// `apply` would write outside the project or into an excluded path.
+    fn apply_blocked(message: impl Into<String>, details: Value) -> Self {
+        Self::classified(-32012, "APPLY_BLOCKED", message, details)
+    }
+
+    /// Another process holds the project's workspace lock.
+    fn workspace_locked(message: impl Into<String>, details: Value) -> Self {
+        Self::classified(-32013, "WORKSPACE_LOCKED", message, details)
+    }
+
     fn code(&self) -> i32 {
         self.code
     }
@@ -93,14 +149,8 @@ struct RpcResponse<'a> {
 struct InitParams {
     #[serde(rename = "projectRoot")]
     project_root: String,
-}
-
-#[derive(Debug, Serialize)]
-struct Pinned {
-    node: String,
-    next: String,
-    ts: String,
-    playwright: String,
+    #[serde(flatten)]
+    pins: pins::PinChange,
 }
 
 #[derive(Debug, Deserialize)]
@@ -164,6 +214,107 @@ fn default_concurrency() -> u32 {
     4
 }
 
+/// Per-request state threaded through handlers into worker calls.
+#[derive(Debug)]
+struct RequestContext {
+    progress: ProgressReporter,
+    cancel: CancelToken,
+    /// Assigned up front so the worker's stderr can be filed under it.
+    job_id: String,
+    backend: Arc<dyn Backend>,
+    /// The project this call operates on; the sandbox lives inside it.
+    root: PathBuf,
+    /// The session's project binding, which `init` updates.
+    project: ProjectSlot,
+}
+
+impl Default for RequestContext {
+    fn default() -> Self {
+        Self {
+            progress: ProgressReporter::default(),
+            cancel: CancelToken::default(),
+            job_id: Ulid::new().to_string(),
+            backend: Arc::new(WorkerBackend),
+            root: PathBuf::from("."),
+            project: ProjectSlot::default(),
+        }
+    }
+}
+
+impl RequestContext {
+    /// Build the context for a call on `session` against the project at
+    /// `root`. Progress from the request's `params._meta` goes out through
+    /// `notifier`.
+    fn from_params(
+        params: &Value,
+        notifier: Notifier,
+        cancel: CancelToken,
+        session: &Session,
+        root: PathBuf,
+    ) -> Self {
+        Self {
+            progress: ProgressReporter::from_meta(params, notifier),
+            cancel,
+            job_id: Ulid::new().to_string(),
+            backend: session.backend(),
+            root,
+            project: session.project(),
+        }
+    }
+
+    /// The project's `.site2ts` sandbox.
+    fn sandbox(&self) -> PathBuf {
+        self.root.join(".site2ts")
+    }
+
+    /// Append an `info` entry to job `job_id`'s log in the sandbox.
+    fn log(&self, job_id: &str, phase: &str, msg: &str, data: Value) -> RpcResult<()> {
+        append_ndjson(
+            &self.sandbox().join("logs"),
+            "info",
+            job_id,
+            phase,
+            msg,
+            data,
+        )
+        .map_err(|e| RpcError::internal(e.to_string()))
+    }
+
+    /// Run worker `method` on this request's backend.
+    async fn call(&mut self, method: &str, params: Value) -> RpcResult<Value> {
+        let backend = self.backend.clone();
+        backend.call(method, params, self).await
+    }
+}
+
+/// Resolve a client-supplied project root to an absolute path. Relative
+/// roots are taken from the server's working directory once, here.
+fn resolve_root(root: &str) -> RpcResult<PathBuf> {
+    let abs = std::path::absolute(root).map_err(|e| {
+        RpcError::bad_input(
+            format!("invalid projectRoot {:?}: {}", root, e),
+            json!({ "projectRoot": root }),
+        )
+    })?;
+    Ok(fs::canonicalize(&abs).unwrap_or(abs))
+}
+
+/// The project a call to `name` runs against. `init` and `pipeline` name it
+/// in `projectRoot`; everything else uses the one `init` bound the session
+/// to, and is rejected before that.
+fn bind_project(name: &str, params: &Value, session: &Session) -> RpcResult<PathBuf> {
+    let named = match name {
+        "init" | "pipeline" | pipeline::RESUME_METHOD => {
+            params.get("projectRoot").and_then(Value::as_str)
+        }
+        _ => None,
+    };
+    match named {
+        Some(root) => resolve_root(root),
+        None => session.project_root(),
+    }
+}
+
 fn ensure_dir(path: &Path) -> Result<()> {
     if !path.exists() {
         fs::create_dir_all(path).with_context(|| format!("creating dir {}", path.display()))?;
@@ -185,9 +336,16 @@ fn parse_params<T: DeserializeOwned>(params: &Value) -> std::result::Result<T, R
     serde_json::from_value(params.clone()).map_err(|e| RpcError::invalid_params(e.to_string()))
 }
 
-fn log_ndjson(job_id: &str, phase: &str, msg: &str, data: Value) -> Result<()> {
-    let logs_dir = PathBuf::from(".site2ts").join("logs");
-    ensure_dir(&logs_dir)?;
+/// Append one entry to `<logs_dir>/<jobId>.ndjson`.
+fn append_ndjson(
+    logs_dir: &Path,
+    level: &str,
+    job_id: &str,
+    phase: &str,
+    msg: &str,
+    data: Value,
+) -> Result<()> {
+    ensure_dir(logs_dir)?;
     let path = logs_dir.join(format!("{}.ndjson", job_id));
     let mut f = OpenOptions::new()
         .create(true)
@@ -196,7 +354,7 @@ fn log_ndjson(job_id: &str, phase: &str, msg: &str, data: Value) -> Result<()> {
         .with_context(|| format!("open log {}", path.display()))?;
     let entry = json!({
         "ts": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
-        "level": "info",
+        "level": level,
         "jobId": job_id,
         "phase": phase,
         "msg": msg,
@@ -207,75 +365,61 @@ fn log_ndjson(job_id: &str, phase: &str, msg: &str, data: Value) -> Result<()> {
     Ok(())
 }
 
-fn handle_init(params: InitParams) -> RpcResult<Value> {
+async fn handle_init(params: InitParams, ctx: &mut RequestContext) -> RpcResult<Value> {
     // Prepare sandbox directories
-    let root = PathBuf::from(&params.project_root);
+    let root = resolve_root(&params.project_root)?;
     let site2ts = root.join(".site2ts");
     for dir in [
         site2ts.join("staging"),
         site2ts.join("cache").join("pw"),
         site2ts.join("reports"),
         site2ts.join("logs"),
-        site2ts.join("exports"),
     ] {
         ensure_dir(&dir).map_err(|e| RpcError::internal(e.to_string()))?;
     }
 
-    // Write pins.json per spec (pinned versions; can be refined later)
-    let pins = json!({
-        "node": "20.15.0",
-        "next": "14.2.5",
-        "typescript": "5.5.4",
-        "playwright": "1.46.0",
-        "tailwind": "3.4.10",
-        "createdAt": chrono::Utc::now().to_rfc3339(),
-    });
-    write_json_pretty(&site2ts.join("pins.json"), &pins)
-        .map_err(|e| RpcError::internal(e.to_string()))?;
-
-    let pinned = Pinned {
-        node: "20.x".to_string(),
-        next: "14.x".to_string(),
-        ts: "5.x".to_string(),
-        playwright: "1.x".to_string(),
-    };
-    // Ask worker to ensure runtime deps (Chromium) are available
-    if let Ok(mutex) = Worker::get() {
-        if let Ok(mut w) = mutex.lock() {
-            w.call("initRuntime", json!({}))?;
-        }
+    // Write pins.json per spec, or keep the project's unless asked to
+    // upgrade or roll back
+    let (pins, upgrade) = pins::settle(&site2ts, &params.pins)?;
+
+    // Ask worker to ensure runtime deps (Chromium) are available, then
+    // refuse a runtime that does not match the pins.
+    ctx.root = fs::canonicalize(&root).unwrap_or(root);
+    ctx.call("initRuntime", json!({})).await?;
+    pins::verify_init(ctx).await?;
+    ctx.project.set(ctx.root.clone());
+    let mut res = json!({ "ok": true, "pinned": pins.summary() });
+    if let Some(upgrade) = upgrade {
+        res["upgrade"] = upgrade;
     }
-    serde_json::to_value(json!({ "ok": true, "pinned": pinned }))
-        .map_err(|e| RpcError::internal(e.to_string()))
+    Ok(res)
 }
 
-fn handle_crawl(params: CrawlParams) -> RpcResult<Value> {
+async fn handle_crawl(params: CrawlParams, ctx: &mut RequestContext) -> RpcResult<Value> {
     // Call Node worker crawl for IDs, then persist sitemap manifest according to spec.
-    let worker_mutex = Worker::get().map_err(|e| RpcError::internal(e.to_string()))?;
-    let mut w = worker_mutex
-        .lock()
-        .map_err(|_| RpcError::internal("failed to lock worker mutex"))?;
-    let res = w.call(
-        "crawl",
-        json!({
-            "startUrl": params.start_url,
-            "sameOrigin": params.same_origin,
-            "maxPages": params.max_pages,
-            "maxDepth": params.max_depth,
-            "allow": params.allow,
-            "deny": params.deny,
-            "concurrency": params.concurrency,
-            "delayMs": params.delay_ms,
-            "useSitemap": params.use_sitemap,
-            "obeyRobots": params.obey_robots
-        }),
-    )?;
+    let res = ctx
+        .call(
+            "crawl",
+            json!({
+                "startUrl": params.start_url,
+                "sameOrigin": params.same_origin,
+                "maxPages": params.max_pages,
+                "maxDepth": params.max_depth,
+                "allow": params.allow,
+                "deny": params.deny,
+                "concurrency": params.concurrency,
+                "delayMs": params.delay_ms,
+                "useSitemap": params.use_sitemap,
+                "obeyRobots": params.obey_robots
+            }),
+        )
+        .await?;
 
     let job_id = res
         .get("jobId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| Ulid::new().to_string());
+        .unwrap_or_else(|| ctx.job_id.clone());
     let site_map_id = res
         .get("siteMapId")
         .and_then(|v| v.as_str())
@@ -283,7 +427,7 @@ fn handle_crawl(params: CrawlParams) -> RpcResult<Value> {
         .unwrap_or_else(|| Ulid::new().to_string());
     let pages = res.get("pages").cloned().unwrap_or_else(|| json!([]));
 
-    let sitemap_dir = PathBuf::from(".site2ts").join("cache").join("sitemaps");
+    let sitemap_dir = ctx.sandbox().join("cache").join("sitemaps");
     ensure_dir(&sitemap_dir).map_err(|e| RpcError::internal(e.to_string()))?;
     let sitemap = json!({
         "siteMapId": site_map_id,
@@ -299,13 +443,12 @@ fn handle_crawl(params: CrawlParams) -> RpcResult<Value> {
     });
     let path = sitemap_dir.join(format!("{}.json", site_map_id));
     write_json_pretty(&path, &sitemap).map_err(|e| RpcError::internal(e.to_string()))?;
-    log_ndjson(
+    ctx.log(
         &job_id,
         "crawl",
         "Crawl stub completed",
         json!({ "pages": sitemap["pages"].as_array().map(|a| a.len()).unwrap_or(0) }),
-    )
-    .map_err(|e| RpcError::internal(e.to_string()))?;
+    )?;
 
     Ok(json!({
         "jobId": job_id,
@@ -314,19 +457,17 @@ fn handle_crawl(params: CrawlParams) -> RpcResult<Value> {
     }))
 }
 
-fn handle_analyze(params: AnalyzeParams) -> RpcResult<Value> {
+async fn handle_analyze(params: AnalyzeParams, ctx: &mut RequestContext) -> RpcResult<Value> {
     // Delegate to worker and persist analysis.json
-    let worker_mutex = Worker::get().map_err(|e| RpcError::internal(e.to_string()))?;
-    let mut w = worker_mutex
-        .lock()
-        .map_err(|_| RpcError::internal("failed to lock worker mutex"))?;
-    let res = w.call("analyze", json!({ "siteMapId": params.site_map_id }))?;
+    let res = ctx
+        .call("analyze", json!({ "siteMapId": params.site_map_id }))
+        .await?;
 
     let job_id = res
         .get("jobId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| Ulid::new().to_string());
+        .unwrap_or_else(|| ctx.job_id.clone());
     let analysis_id = res
         .get("analysisId")
         .and_then(|v| v.as_str())
@@ -339,20 +480,19 @@ fn handle_analyze(params: AnalyzeParams) -> RpcResult<Value> {
         "forms": res.get("forms").cloned().unwrap_or(json!([])),
         "assets": res.get("assets").cloned().unwrap_or(json!({"images":[],"fonts":[],"styles":[]})),
     });
-    let out = PathBuf::from(".site2ts").join("staging").join("meta");
+    let out = ctx.sandbox().join("staging").join("meta");
     ensure_dir(&out).map_err(|e| RpcError::internal(e.to_string()))?;
     write_json_pretty(&out.join("analysis.json"), &analysis)
         .map_err(|e| RpcError::internal(e.to_string()))?;
 
-    log_ndjson(
+    ctx.log(
         &job_id,
         "analyze",
         "Analyze complete",
         json!({
             "routes": analysis["routes"].as_array().map(|a| a.len()).unwrap_or(0)
         }),
-    )
-    .map_err(|e| RpcError::internal(e.to_string()))?;
+    )?;
 
     Ok(json!({
         "jobId": job_id,
@@ -362,24 +502,22 @@ fn handle_analyze(params: AnalyzeParams) -> RpcResult<Value> {
     }))
 }
 
-fn handle_scaffold(params: ScaffoldParams) -> RpcResult<Value> {
-    let worker_mutex = Worker::get().map_err(|e| RpcError::internal(e.to_string()))?;
-    let mut w = worker_mutex
-        .lock()
-        .map_err(|_| RpcError::internal("failed to lock worker mutex"))?;
-    let res = w.call(
-        "scaffold",
-        json!({
-            "analysisId": params.analysis_id,
-            "appRouter": params.app_router,
-        }),
-    )?;
+async fn handle_scaffold(params: ScaffoldParams, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let res = ctx
+        .call(
+            "scaffold",
+            json!({
+                "analysisId": params.analysis_id,
+                "appRouter": params.app_router,
+            }),
+        )
+        .await?;
 
     let job_id = res
         .get("jobId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| Ulid::new().to_string());
+        .unwrap_or_else(|| ctx.job_id.clone());
     let scaffold_id = res
         .get("scaffoldId")
         .and_then(|v| v.as_str())
@@ -391,13 +529,12 @@ fn handle_scaffold(params: ScaffoldParams) -> RpcResult<Value> {
         .unwrap_or(".site2ts/staging")
         .to_string();
 
-    log_ndjson(
+    ctx.log(
         &job_id,
         "scaffold",
         "Scaffold prepared",
         json!({ "outDir": out_dir }),
-    )
-    .map_err(|e| RpcError::internal(e.to_string()))?;
+    )?;
 
     Ok(json!({
         "jobId": job_id,
@@ -406,38 +543,30 @@ fn handle_scaffold(params: ScaffoldParams) -> RpcResult<Value> {
     }))
 }
 
-fn handle_generate(params: GenerateParams) -> RpcResult<Value> {
-    let worker_mutex = Worker::get().map_err(|e| RpcError::internal(e.to_string()))?;
-    let mut w = worker_mutex
-        .lock()
-        .map_err(|_| RpcError::internal("failed to lock worker mutex"))?;
-    let res = w.call(
-        "generate",
-        json!({
+async fn handle_generate(params: GenerateParams, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let res = ctx.call("generate", json!({
             "analysisId": params.analysis_id,
             "scaffoldId": params.scaffold_id,
             "tailwindMode": if params.tailwind_mode.is_empty() { "full" } else { &params.tailwind_mode },
-        }),
-    )?;
+        })).await?;
 
     let job_id = res
         .get("jobId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| Ulid::new().to_string());
+        .unwrap_or_else(|| ctx.job_id.clone());
     let generation_id = res
         .get("generationId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
         .unwrap_or_else(|| Ulid::new().to_string());
 
-    log_ndjson(
+    ctx.log(
         &job_id,
         "generate",
         "Generate complete",
         json!({ "generationId": generation_id }),
-    )
-    .map_err(|e| RpcError::internal(e.to_string()))?;
+    )?;
 
     Ok(json!({
         "jobId": job_id,
@@ -503,205 +632,520 @@ struct ImproveParams {
     metadata: Option<Value>,
 }
 
-fn handle_diff(params: DiffParams) -> RpcResult<Value> {
-    let worker_mutex = Worker::get().map_err(|e| RpcError::internal(e.to_string()))?;
-    let mut w = worker_mutex
-        .lock()
-        .map_err(|_| RpcError::internal("failed to lock worker mutex"))?;
-    let res = w.call(
-        "diff",
-        json!({
+async fn handle_diff(params: DiffParams, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let res = ctx.call("diff", json!({
             "generationId": params.generation_id,
             "baselines": params.baselines.unwrap_or_else(|| "recrawl".into()),
             "viewport": params.viewport.unwrap_or_else(|| json!({"w":1280,"h":800,"deviceScale":1})),
             "threshold": params.threshold.unwrap_or(0.01),
             "renderReport": params.render_report.unwrap_or(false),
-        }),
-    )?;
+        })).await?;
 
     let job_id = res
         .get("jobId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| Ulid::new().to_string());
+        .unwrap_or_else(|| ctx.job_id.clone());
     let diff_id = res
         .get("diffId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
         .unwrap_or_else(|| Ulid::new().to_string());
 
-    log_ndjson(
+    ctx.log(
         &job_id,
         "diff",
         "Visual diff complete",
         json!({ "diffId": diff_id }),
-    )
-    .map_err(|e| RpcError::internal(e.to_string()))?;
+    )?;
     Ok(res)
 }
 
-fn handle_improve(params: ImproveParams) -> RpcResult<Value> {
-    let worker_mutex = Worker::get().map_err(|e| RpcError::internal(e.to_string()))?;
-    let mut w = worker_mutex
-        .lock()
-        .map_err(|_| RpcError::internal("failed to lock worker mutex"))?;
-    let res = w.call(
-        "improve",
-        json!({
-            "generationId": params.generation_id,
-            "route": params.route,
-            "issues": params.issues,
-            "instructions": params.instructions,
-            "metadata": params.metadata,
-        }),
-    )?;
+async fn handle_improve(params: ImproveParams, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let res = ctx
+        .call(
+            "improve",
+            json!({
+                "generationId": params.generation_id,
+                "route": params.route,
+                "issues": params.issues,
+                "instructions": params.instructions,
+                "metadata": params.metadata,
+            }),
+        )
+        .await?;
 
     let job_id = res
         .get("jobId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| Ulid::new().to_string());
+        .unwrap_or_else(|| ctx.job_id.clone());
     let plan_path = res
         .get("planPath")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
         .unwrap_or_default();
 
-    log_ndjson(
+    ctx.log(
         &job_id,
         "improve",
         "Improvement instruction recorded",
         json!({ "planPath": plan_path }),
-    )
-    .map_err(|e| RpcError::internal(e.to_string()))?;
+    )?;
 
     Ok(res)
 }
 
-fn handle_audit(params: AuditParams) -> RpcResult<Value> {
-    let worker_mutex = Worker::get().map_err(|e| RpcError::internal(e.to_string()))?;
-    let mut w = worker_mutex
-        .lock()
-        .map_err(|_| RpcError::internal("failed to lock worker mutex"))?;
-    let res = w.call(
-        "audit",
-        json!({
-            "generationId": params.generation_id,
-            "tsStrict": params.ts_strict.unwrap_or(true),
-            "eslintConfig": params.eslint_config.unwrap_or_else(|| "recommended".into()),
-        }),
-    )?;
+async fn handle_audit(params: AuditParams, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let res = ctx
+        .call(
+            "audit",
+            json!({
+                "generationId": params.generation_id,
+                "tsStrict": params.ts_strict.unwrap_or(true),
+                "eslintConfig": params.eslint_config.unwrap_or_else(|| "recommended".into()),
+            }),
+        )
+        .await?;
 
     let job_id = res
         .get("jobId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| Ulid::new().to_string());
+        .unwrap_or_else(|| ctx.job_id.clone());
     let audit_id = res
         .get("auditId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
         .unwrap_or_else(|| Ulid::new().to_string());
-    log_ndjson(
+    ctx.log(
         &job_id,
         "audit",
         "Audit completed",
         json!({ "auditId": audit_id }),
-    )
-    .map_err(|e| RpcError::internal(e.to_string()))?;
+    )?;
     Ok(res)
 }
 
-fn handle_apply(params: ApplyParams) -> RpcResult<Value> {
-    let worker_mutex = Worker::get().map_err(|e| RpcError::internal(e.to_string()))?;
-    let mut w = worker_mutex
-        .lock()
-        .map_err(|_| RpcError::internal("failed to lock worker mutex"))?;
-    let res = w.call(
-        "apply",
+async fn handle_apply(params: ApplyParams, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let target = paths::apply_target(&ctx.root, params.target.as_deref().unwrap_or("./"))?;
+    let dry_run = params.dry_run.unwrap_or(false);
+    let args = |dry_run: bool| {
         json!({
             "generationId": params.generation_id,
-            "target": params.target.unwrap_or_else(|| "./".into()),
-            "dryRun": params.dry_run.unwrap_or(false),
-        }),
-    )?;
+            "target": target,
+            "dryRun": dry_run,
+        })
+    };
+    // Every apply is planned first; files are only written once each path
+    // in the plan has been checked, and only the paths checked. The worker
+    // refuses if the plan it would make now differs.
+    let plan = ctx.call("apply", args(true)).await?;
+    paths::check_plan(&ctx.root, &target, &plan)?;
+    let res = if dry_run {
+        plan
+    } else {
+        let mut args = args(false);
+        args["plan"] = json!({
+            "changedFiles": plan["changedFiles"],
+            "deletedFiles": plan["deletedFiles"],
+        });
+        ctx.call("apply", args).await?
+    };
     let job_id = res
         .get("jobId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| Ulid::new().to_string());
-    log_ndjson(&job_id, "apply", "Apply executed", json!({}))
-        .map_err(|e| RpcError::internal(e.to_string()))?;
+        .unwrap_or_else(|| ctx.job_id.clone());
+    ctx.log(&job_id, "apply", "Apply executed", json!({}))?;
     Ok(res)
 }
 
-fn handle_assets(params: AssetsParams) -> RpcResult<Value> {
+async fn handle_assets(params: AssetsParams, ctx: &mut RequestContext) -> RpcResult<Value> {
     let id = params
         .site_map_id
         .or(params.generation_id)
         .unwrap_or_else(|| Ulid::new().to_string());
-    let worker_mutex = Worker::get().map_err(|e| RpcError::internal(e.to_string()))?;
-    let mut w = worker_mutex
-        .lock()
-        .map_err(|_| RpcError::internal("failed to lock worker mutex"))?;
-    let res = w.call("assets", json!({ "generationId": id }))?;
+    let res = ctx.call("assets", json!({ "generationId": id })).await?;
     let job_id = res
         .get("jobId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| Ulid::new().to_string());
-    log_ndjson(&job_id, "assets", "Assets manifest generated", json!({}))
-        .map_err(|e| RpcError::internal(e.to_string()))?;
+        .unwrap_or_else(|| ctx.job_id.clone());
+    ctx.log(&job_id, "assets", "Assets manifest generated", json!({}))?;
     Ok(res)
 }
 
-fn handle_pack(params: PackParams) -> RpcResult<Value> {
-    let worker_mutex = Worker::get().map_err(|e| RpcError::internal(e.to_string()))?;
-    let mut w = worker_mutex
-        .lock()
-        .map_err(|_| RpcError::internal("failed to lock worker mutex"))?;
-    let res = w.call("pack", json!({ "generationId": params.generation_id }))?;
+async fn handle_pack(params: PackParams, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let res = ctx
+        .call("pack", json!({ "generationId": params.generation_id }))
+        .await?;
     let job_id = res
         .get("jobId")
         .and_then(|v| v.as_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| Ulid::new().to_string());
-    log_ndjson(&job_id, "pack", "Pack completed", json!({}))
-        .map_err(|e| RpcError::internal(e.to_string()))?;
+        .unwrap_or_else(|| ctx.job_id.clone());
+    ctx.log(&job_id, "pack", "Pack completed", json!({}))?;
     Ok(res)
 }
 
-fn respond(result: Option<Value>, error: Option<Value>, id: Option<Value>) {
-    let resp = RpcResponse {
+/// Run one site2ts tool by name. Used both for MCP `tools/call` and for the
+/// legacy bare-method mode where the tool name is the JSON-RPC method.
+/// Every call of a known tool is recorded in the job store.
+async fn call_tool(name: &str, params: &Value, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let job = begin_job(name, params, ctx);
+    run_job(job, name, params, ctx).await
+}
+
+/// Tools are recorded as jobs.
+fn is_job(name: &str) -> bool {
+    mcp::is_tool(name)
+}
+
+/// Register a job for tool `name`; `None` for names that are not jobs.
+fn begin_job(name: &str, params: &Value, ctx: &mut RequestContext) -> Option<Arc<LiveJob>> {
+    if !is_job(name) {
+        return None;
+    }
+    let job = LiveJob::start(&ctx.sandbox(), &ctx.job_id, name, params);
+    ctx.progress.track(Some(job.clone()));
+    Some(job)
+}
+
+async fn run_job(
+    job: Option<Arc<LiveJob>>,
+    name: &str,
+    params: &Value,
+    ctx: &mut RequestContext,
+) -> RpcResult<Value> {
+    // Pipeline stages lock the project themselves; holding the lock across
+    // them would leave `apply` waiting on its own pipeline.
+    let project = if name == "pipeline" || name == pipeline::RESUME_METHOD {
+        None
+    } else {
+        Some(pool().enter_project(&ctx.root, name).await)
+    };
+    // Tools all write to the sandbox, so every one holds the workspace lock
+    // that keeps other server processes out.
+    let res = match workspace::acquire(&ctx.sandbox(), name)
+        .and_then(|lock| lineage::check(&ctx.sandbox(), name, params).map(|()| lock))
+    {
+        Ok(_workspace) => match pins::verify(ctx, name, params).await {
+            Ok(()) => run_tool(name, params, ctx).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    if let Ok(result) = &res {
+        lineage::record(&ctx.sandbox(), name, params, result, &ctx.job_id);
+    }
+    if let Some(job) = job {
+        job.finish(&res);
+    }
+    // Automatic gc only runs when nothing else is using the project; the
+    // next call that finds it idle collects instead.
+    drop(project);
+    if res.is_ok() && gc::AUTO_AFTER.contains(&name) {
+        if let Some(_exclusive) = pool().try_enter_exclusive(&ctx.root) {
+            gc::auto(&ctx.sandbox());
+        }
+    }
+    res
+}
+
+/// Callers opt into async mode with `_meta.async: true`.
+fn wants_async(params: &Value) -> bool {
+    params
+        .get("_meta")
+        .and_then(|m| m.get("async"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+/// Start tool `name` in the background and answer with its job id. The job
+/// outlives the request, so it is not cancelled with it; its progress goes
+/// to the session's notification stream.
+fn spawn_job(name: &str, params: Value, session: &Arc<Session>) -> Option<RpcResult<Value>> {
+    if !is_job(name) {
+        return None;
+    }
+    let root = match bind_project(name, &params, session) {
+        Ok(root) => root,
+        Err(e) => return Some(Err(e)),
+    };
+    let mut ctx = RequestContext::from_params(
+        &params,
+        session.notifier(),
+        CancelToken::default(),
+        session,
+        root,
+    );
+    let job = begin_job(name, &params, &mut ctx)?;
+    let job_id = ctx.job_id.clone();
+    let name = name.to_string();
+    let session = session.clone();
+    tokio::spawn(async move {
+        let _ = run_job(Some(job), &name, &params, &mut ctx).await;
+        resources::notify_updated(&session);
+    });
+    Some(Ok(json!({ "jobId": job_id, "status": "running" })))
+}
+
+/// Run tool `name` for `session` in the foreground.
+async fn call_session_tool(
+    name: &str,
+    params: &Value,
+    meta: &Value,
+    session: &Session,
+    notifier: Notifier,
+    cancel: CancelToken,
+) -> RpcResult<Value> {
+    let root = bind_project(name, params, session)?;
+    let mut ctx = RequestContext::from_params(meta, notifier, cancel, session, root);
+    let res = call_tool(name, params, &mut ctx).await;
+    resources::notify_updated(session);
+    res
+}
+
+async fn run_tool(name: &str, params: &Value, ctx: &mut RequestContext) -> RpcResult<Value> {
+    match name {
+        "init" => handle_init(parse_params(params)?, ctx).await,
+        "crawl" => handle_crawl(parse_params(params)?, ctx).await,
+        "analyze" => handle_analyze(parse_params(params)?, ctx).await,
+        "scaffold" => handle_scaffold(parse_params(params)?, ctx).await,
+        "generate" => handle_generate(parse_params(params)?, ctx).await,
+        "diff" => handle_diff(parse_params(params)?, ctx).await,
+        "audit" => handle_audit(parse_params(params)?, ctx).await,
+        "apply" => handle_apply(parse_params(params)?, ctx).await,
+        "assets" => handle_assets(parse_params(params)?, ctx).await,
+        "pack" => handle_pack(parse_params(params)?, ctx).await,
+        "improve" => handle_improve(parse_params(params)?, ctx).await,
+        "pipeline" => pipeline::run(parse_params(params)?, ctx).await,
+        pipeline::RESUME_METHOD => pipeline::resume(parse_params(params)?, ctx).await,
+        gc::METHOD => gc::run(parse_params(params)?, ctx),
+        _ => Err(RpcError::new(-32601, "method not found", None)),
+    }
+}
+
+/// Dispatch one JSON-RPC message for `session`. Request-scoped notifications
+/// (progress) go to `notifier`. Returns `None` for notifications, which must
+/// not be answered.
+async fn handle_request(
+    req: RpcRequest,
+    session: &Arc<Session>,
+    notifier: Notifier,
+) -> Option<RpcResult<Value>> {
+    let in_flight = req.id.as_ref().map(|id| session.begin(id, &req.method));
+    let cancel = in_flight
+        .as_ref()
+        .map(|g| g.cancel_token())
+        .unwrap_or_default();
+    match req.method.as_str() {
+        "initialize" => {
+            let worker = match session.backend().handshake().await {
+                Ok(hello) => hello,
+                Err(e) => json!({ "error": e.to_json() }),
+            };
+            let res = mcp::initialize_result(&req.params, worker);
+            if let Some(version) = res["protocolVersion"].as_str() {
+                session.set_protocol_version(version);
+            }
+            Some(Ok(res))
+        }
+        "ping" => Some(Ok(json!({}))),
+        "status" => Some(Ok(json!({
+            "requests": session.in_flight(),
+            "pool": pool().stats(),
+        }))),
+        "tools/list" => Some(Ok(mcp::tools_list())),
+        "jobs/list" => Some(session.sandbox().and_then(|base| {
+            if req.params.is_null() {
+                jobs::list(&base, Default::default())
+            } else {
+                parse_params(&req.params).and_then(|p| jobs::list(&base, p))
+            }
+        })),
+        "jobs/get" => Some(
+            session
+                .sandbox()
+                .and_then(|base| parse_params(&req.params).and_then(|p| jobs::get(&base, p))),
+        ),
+        "jobs/status" => Some(
+            session
+                .sandbox()
+                .and_then(|base| parse_params(&req.params).and_then(|p| jobs::status(&base, p))),
+        ),
+        "jobs/wait" => Some(match session.sandbox() {
+            Ok(base) => match parse_params(&req.params) {
+                Ok(p) => jobs::wait(&base, p).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        }),
+        "jobs/result" => Some(
+            session
+                .sandbox()
+                .and_then(|base| parse_params(&req.params).and_then(|p| jobs::result(&base, p))),
+        ),
+        "lineage/get" => Some(
+            session
+                .sandbox()
+                .and_then(|base| parse_params(&req.params).and_then(|p| lineage::get(&base, p))),
+        ),
+        "lock/status" => Some(session.sandbox().and_then(|base| workspace::status(&base))),
+        "lock/break" => Some(session.sandbox().and_then(|base| {
+            if req.params.is_null() {
+                workspace::break_lock(&base, Default::default())
+            } else {
+                parse_params(&req.params).and_then(|p| workspace::break_lock(&base, p))
+            }
+        })),
+        "resources/list" => Some(session.sandbox().and_then(|base| {
+            if req.params.is_null() {
+                resources::list(&base, Default::default())
+            } else {
+                parse_params(&req.params).and_then(|p| resources::list(&base, p))
+            }
+        })),
+        "resources/templates/list" => Some(Ok(resources::templates())),
+        "resources/read" => Some(
+            session
+                .sandbox()
+                .and_then(|base| parse_params(&req.params).and_then(|p| resources::read(&base, p))),
+        ),
+        "resources/subscribe" => Some(session.sandbox().and_then(|base| {
+            parse_params(&req.params).and_then(|p| resources::subscribe(&base, session, p))
+        })),
+        "resources/unsubscribe" => {
+            Some(parse_params(&req.params).and_then(|p| resources::unsubscribe(session, p)))
+        }
+        "tools/call" => {
+            let call = match parse_params::<mcp::ToolCallParams>(&req.params) {
+                Ok(c) => c,
+                Err(e) => return Some(Err(e)),
+            };
+            if !mcp::is_tool(&call.name) {
+                return Some(Err(RpcError::invalid_params(format!(
+                    "unknown tool: {}",
+                    call.name
+                ))));
+            }
+            if wants_async(&req.params) {
+                let started = spawn_job(&call.name, call.arguments, session);
+                return started.map(|res| Ok(mcp::tool_call_result(res)));
+            }
+            let res = call_session_tool(
+                &call.name,
+                &call.arguments,
+                &req.params,
+                session,
+                notifier,
+                cancel,
+            )
+            .await;
+            Some(Ok(mcp::tool_call_result(res)))
+        }
+        // MCP and LSP-style cancellation; both are notifications.
+        "notifications/cancelled" | "$/cancelRequest" if req.id.is_none() => {
+            let key = if req.method == "$/cancelRequest" {
+                "id"
+            } else {
+                "requestId"
+            };
+            if let Some(id) = req.params.get(key) {
+                let reason = req
+                    .params
+                    .get("reason")
+                    .and_then(|r| r.as_str())
+                    .unwrap_or("cancelled by client");
+                if !session.cancel(id, reason) {
+                    debug!(target = "site2ts", "cancel for unknown request {}", id);
+                }
+            }
+            None
+        }
+        m if m.starts_with("notifications/") => {
+            if req.id.is_some() {
+                Some(Err(RpcError::new(
+                    -32600,
+                    "notifications must not carry an id",
+                    None,
+                )))
+            } else {
+                None
+            }
+        }
+        // Compatibility mode: bare tool names as JSON-RPC methods.
+        m => {
+            if wants_async(&req.params) && is_job(m) {
+                return spawn_job(m, req.params, session);
+            }
+            if !is_job(m) {
+                return Some(Err(RpcError::new(-32601, "method not found", None)));
+            }
+            Some(call_session_tool(m, &req.params, &req.params, session, notifier, cancel).await)
+        }
+    }
+}
+
+/// Build the JSON-RPC response envelope for a handler outcome.
+fn response_json(id: Option<Value>, res: RpcResult<Value>) -> Value {
+    let (result, error) = match res {
+        Ok(v) => (Some(v), None),
+        Err(e) => (None, Some(e.to_json())),
+    };
+    serde_json::to_value(RpcResponse {
         jsonrpc: "2.0",
         result,
         error,
         id,
-    };
-    let s = serde_json::to_string(&resp).unwrap_or_else(|e| {
-        serde_json::to_string(&RpcResponse {
-            jsonrpc: "2.0",
-            result: None,
-            error: Some(json!({"code": -32603, "message": format!("internal: {}", e)})),
-            id: None,
+    })
+    .unwrap_or_else(|e| {
+        json!({
+            "jsonrpc": "2.0",
+            "error": { "code": -32603, "message": format!("internal: {}", e) },
+            "id": null
         })
-        .unwrap()
-    });
-    println!("{}", s);
+    })
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env().add_directive(Level::INFO.into()))
-        .with_writer(std::io::stderr)
-        .init();
-    info!(target = "site2ts", "site2ts-server JSON-RPC starting");
+/// Turn the outcome of a request task into its response, if it needs one.
+/// A panicking handler still answers so the client is never left waiting.
+fn finish(
+    id: Option<Value>,
+    res: std::result::Result<Option<RpcResult<Value>>, tokio::task::JoinError>,
+) -> Option<Value> {
+    match res {
+        Ok(Some(r)) => Some(response_json(id, r)),
+        Ok(None) => None,
+        Err(e) => Some(response_json(
+            id,
+            Err(RpcError::internal(format!("handler failed: {}", e))),
+        )),
+    }
+}
 
-    let stdin = io::stdin();
-    for line in stdin.lock().lines() {
-        let line = match line {
-            Ok(l) => l,
+/// Serve one MCP client over stdin/stdout, one JSON message per line.
+async fn serve_stdio() -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(msg) = rx.recv().await {
+            let line = format!("{}\n", msg);
+            if stdout.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+            stdout.flush().await.ok();
+        }
+    });
+
+    let session = Arc::new(Session::new("stdio", Notifier::new(tx.clone())));
+    let mut tasks = JoinSet::new();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(l)) => l,
+            Ok(None) => break,
             Err(e) => {
                 error!(?e, "stdin read error");
                 break;
@@ -713,99 +1157,82 @@ async fn main() -> Result<()> {
         let req: RpcRequest = match serde_json::from_str(&line) {
             Ok(r) => r,
             Err(e) => {
-                respond(
-                    None,
-                    Some(json!({"code": -32700, "message": format!("parse error: {}", e)})),
-                    None,
-                );
+                let err = RpcError::new(-32700, format!("parse error: {}", e), None);
+                tx.send(response_json(None, Err(err))).ok();
                 continue;
             }
         };
+        // Each request runs as its own task; responses go out as they
+        // finish and clients match them by id.
         let id = req.id.clone();
-        let res: Result<Value> = match req.method.as_str() {
-            "init" => match parse_params::<InitParams>(&req.params) {
-                Ok(params) => handle_init(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            "crawl" => match parse_params::<CrawlParams>(&req.params) {
-                Ok(params) => handle_crawl(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            "analyze" => match parse_params::<AnalyzeParams>(&req.params) {
-                Ok(params) => handle_analyze(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            "scaffold" => match parse_params::<ScaffoldParams>(&req.params) {
-                Ok(params) => handle_scaffold(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            "generate" => match parse_params::<GenerateParams>(&req.params) {
-                Ok(params) => handle_generate(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            "diff" => match parse_params::<DiffParams>(&req.params) {
-                Ok(params) => handle_diff(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            "audit" => match parse_params::<AuditParams>(&req.params) {
-                Ok(params) => handle_audit(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            "apply" => match parse_params::<ApplyParams>(&req.params) {
-                Ok(params) => handle_apply(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            "assets" => match parse_params::<AssetsParams>(&req.params) {
-                Ok(params) => handle_assets(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            "pack" => match parse_params::<PackParams>(&req.params) {
-                Ok(params) => handle_pack(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            "improve" => match parse_params::<ImproveParams>(&req.params) {
-                Ok(params) => handle_improve(params).map_err(|e| e.into()),
-                Err(e) => Err(e.into()),
-            },
-            _ => Err(RpcError::new(-32601, "method not found", None).into()),
-        };
-        match res {
-            Ok(v) => respond(Some(v), None, id.clone()),
-            Err(e) => {
-                if let Some(rpc_err) = e.downcast_ref::<RpcError>() {
-                    respond(None, Some(rpc_err.to_json()), id.clone());
-                } else {
-                    respond(
-                        None,
-                        Some(json!({
-                            "code": -32603,
-                            "message": format!("internal error: {}", e)
-                        })),
-                        id.clone(),
-                    );
-                }
+        let session = session.clone();
+        let tx = tx.clone();
+        tasks.spawn(async move {
+            let notifier = Notifier::new(tx.clone());
+            let res =
+                tokio::spawn(async move { handle_request(req, &session, notifier).await }).await;
+            if let Some(resp) = finish(id, res) {
+                tx.send(resp).ok();
             }
-        }
-        io::stdout().flush().ok();
+        });
+        while tasks.try_join_next().is_some() {}
     }
 
+    // Let in-flight requests answer before shutting the writer down.
+    while tasks.join_next().await.is_some() {}
+    drop(session);
+    drop(tx);
+    writer.await.ok();
     Ok(())
 }
 
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config = match ServerConfig::from_args(std::env::args().skip(1)) {
+        Ok(Some(c)) => c,
+        Ok(None) => {
+            println!("{}", ServerConfig::usage());
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    pool::configure(config.pool.clone());
+    tokio::spawn(pool().supervise());
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env().add_directive(Level::INFO.into()))
+        .with_writer(std::io::stderr)
+        .init();
+
+    match config.transport {
+        Transport::Stdio => {
+            info!(target = "site2ts", "site2ts-server JSON-RPC starting");
+            serve_stdio().await
+        }
+        Transport::Http(addr) => {
+            info!(target = "site2ts", "site2ts-server HTTP starting");
+            http::serve(addr).await
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use once_cell::sync::Lazy;
+    use backend::FakeBackend;
     use serde_json::json;
     use std::fs;
     use std::path::PathBuf;
-    use std::sync::{Mutex, Once};
+    use std::sync::Once;
+    use tokio::sync::{Mutex, MutexGuard};
 
-    static TEST_MUTEX: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+    static TEST_MUTEX: Mutex<()> = Mutex::const_new(());
     static INIT_CWD: Once = Once::new();
 
-    fn guard() -> std::sync::MutexGuard<'static, ()> {
-        let lock = TEST_MUTEX.lock().unwrap_or_else(|e| e.into_inner());
+    async fn guard() -> MutexGuard<'static, ()> {
+        let lock = TEST_MUTEX.lock().await;
         INIT_CWD.call_once(|| {
             let manifest = env!("CARGO_MANIFEST_DIR");
             let repo_root = PathBuf::from(manifest).join("../..");
@@ -818,51 +1245,1000 @@ mod tests {
         let _ = fs::remove_dir_all(".site2ts");
     }
 
-    #[test]
-    fn missing_param_returns_invalid_params_error() {
-        let _guard = guard();
+    /// The sandbox of the project test sessions are bound to.
+    fn sandbox_dir() -> PathBuf {
+        PathBuf::from(".site2ts")
+    }
+
+    /// A session bound to the working directory, as `init` would leave it.
+    fn bound_session(backend: Arc<dyn Backend>) -> Arc<Session> {
+        let session = Session::default().with_backend(backend);
+        session
+            .project()
+            .set(std::env::current_dir().expect("working directory"));
+        Arc::new(session)
+    }
+
+    #[tokio::test]
+    async fn missing_param_returns_invalid_params_error() {
+        let _guard = guard().await;
         let err = parse_params::<InitParams>(&json!({})).unwrap_err();
         assert_eq!(err.code(), -32602);
         assert!(err.message().contains("projectRoot") || err.message().contains("missing field"));
     }
 
-    #[test]
-    fn analyze_before_crawl_returns_order_error() {
-        let _guard = guard();
+    /// A context whose worker calls are answered by `fake`.
+    fn fake_ctx(fake: &Arc<FakeBackend>) -> RequestContext {
+        RequestContext {
+            backend: fake.clone(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn analyze_before_crawl_returns_order_error() {
+        let _guard = guard().await;
         cleanup_site2ts();
-        let err = handle_analyze(AnalyzeParams {
-            site_map_id: "missing".into(),
-        })
+        let fake = Arc::new(FakeBackend::default());
+        fake.reply(
+            "analyze",
+            Err(RpcError::new(
+                -32001,
+                "siteMapId missing not found; run crawl first",
+                None,
+            )),
+        );
+        let err = handle_analyze(
+            AnalyzeParams {
+                site_map_id: "missing".into(),
+            },
+            &mut fake_ctx(&fake),
+        )
+        .await
         .unwrap_err();
         assert_eq!(err.code(), -32001);
         assert!(err.message().contains("crawl"));
+        assert_eq!(fake.calls()[0].1, json!({ "siteMapId": "missing" }));
+        assert!(!sandbox_dir().join("staging").exists());
     }
 
-    #[test]
-    fn generate_before_scaffold_returns_order_error() {
-        let _guard = guard();
+    #[tokio::test]
+    async fn generate_before_scaffold_returns_order_error() {
+        let _guard = guard().await;
         cleanup_site2ts();
-        let err = handle_generate(GenerateParams {
-            analysis_id: "analysis".into(),
-            scaffold_id: "scaffold".into(),
-            tailwind_mode: String::new(),
-        })
+        let fake = Arc::new(FakeBackend::default());
+        fake.reply(
+            "generate",
+            Err(RpcError::new(
+                -32003,
+                "scaffold output missing; run scaffold before generate",
+                None,
+            )),
+        );
+        let err = handle_generate(
+            GenerateParams {
+                analysis_id: "analysis".into(),
+                scaffold_id: "scaffold".into(),
+                tailwind_mode: String::new(),
+            },
+            &mut fake_ctx(&fake),
+        )
+        .await
         .unwrap_err();
         assert_eq!(err.code(), -32003);
         assert!(err.message().contains("scaffold"));
+        assert_eq!(fake.calls()[0].1["tailwindMode"], "full");
     }
 
-    #[test]
-    fn apply_before_generate_returns_order_error() {
-        let _guard = guard();
+    #[tokio::test]
+    async fn apply_before_generate_returns_order_error() {
+        let _guard = guard().await;
+        cleanup_site2ts();
+        let fake = Arc::new(FakeBackend::default());
+        // A generationId no generate produced never reaches the worker.
+        let err = call_tool(
+            "apply",
+            &json!({ "generationId": "gen" }),
+            &mut fake_ctx(&fake),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), -32011);
+        assert_eq!(err.data.as_ref().unwrap()["field"], "generationId");
+        assert!(fake.calls().is_empty());
+
+        // One the registry knows but whose staging is gone fails in the
+        // worker, which names the order to run things in.
+        fake.reply(
+            "apply",
+            Err(RpcError::new(
+                -32005,
+                "generation gen missing; run generate before apply",
+                None,
+            )),
+        );
+        let err = handle_apply(
+            ApplyParams {
+                generation_id: "gen".into(),
+                target: None,
+                dry_run: None,
+            },
+            &mut fake_ctx(&fake),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), -32005);
+        assert!(err.message().contains("run generate before apply"));
         cleanup_site2ts();
-        let err = handle_apply(ApplyParams {
+    }
+
+    #[tokio::test]
+    async fn apply_writes_nothing_when_its_plan_leaves_the_project() {
+        let _guard = guard().await;
+        let fake = Arc::new(FakeBackend::default());
+        fake.reply(
+            "apply",
+            Ok(json!({
+                "applied": false,
+                "changedFiles": ["app/page.tsx", ".env.local"],
+                "deletedFiles": { "removed": ["../other/app/page.tsx"], "skipped": [] },
+            })),
+        );
+        let apply = |target: &str| ApplyParams {
             generation_id: "gen".into(),
-            target: None,
-            dry_run: None,
-        })
+            target: Some(target.into()),
+            dry_run: Some(false),
+        };
+
+        let err = handle_apply(apply("../elsewhere"), &mut fake_ctx(&fake))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), -32012);
+        assert!(fake.calls().is_empty());
+
+        let err = handle_apply(apply("./"), &mut fake_ctx(&fake))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), -32012);
+        let data = err.data.unwrap();
+        assert_eq!(data["class"], "APPLY_BLOCKED");
+        assert_eq!(data["paths"][0]["path"], ".env.local");
+        assert_eq!(data["paths"][1]["path"], "../other/app/page.tsx");
+        let calls = fake.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].1["dryRun"], true);
+        let root = fs::canonicalize(".").unwrap();
+        assert_eq!(calls[0].1["target"], json!(root));
+    }
+
+    #[tokio::test]
+    async fn apply_writes_the_plan_it_checked() {
+        let _guard = guard().await;
+        let fake = Arc::new(FakeBackend::default());
+        let plan = json!({
+            "changedFiles": ["app/page.tsx"],
+            "deletedFiles": { "removed": ["app/old/page.tsx"], "skipped": [] },
+        });
+        let mut planned = plan.clone();
+        planned["applied"] = json!(false);
+        fake.reply("apply", Ok(planned));
+        let mut applied = plan.clone();
+        applied["applied"] = json!(true);
+        fake.reply("apply", Ok(applied));
+
+        let res = handle_apply(
+            ApplyParams {
+                generation_id: "gen".into(),
+                target: None,
+                dry_run: Some(false),
+            },
+            &mut fake_ctx(&fake),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res["applied"], true);
+        let calls = fake.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[0].1.get("plan").is_none());
+        assert_eq!(calls[1].1["dryRun"], false);
+        assert_eq!(calls[1].1["plan"], plan);
+    }
+
+    fn request(method: &str, params: Value, id: Option<Value>) -> RpcRequest {
+        RpcRequest {
+            jsonrpc: Some("2.0".into()),
+            method: method.into(),
+            params,
+            id,
+        }
+    }
+
+    async fn dispatch(req: RpcRequest) -> Option<RpcResult<Value>> {
+        let session = bound_session(Arc::new(FakeBackend::default()));
+        handle_request(req, &session, Notifier::default()).await
+    }
+
+    #[tokio::test]
+    async fn tool_calls_run_end_to_end_against_the_fake() {
+        let _guard = guard().await;
+        cleanup_site2ts();
+        let fake = Arc::new(FakeBackend::default());
+        fake.reply_with_progress(
+            "crawl",
+            vec![json!({ "progress": 1, "total": 2, "message": "https://example.com/" })],
+            Ok(json!({
+                "siteMapId": "MAP1",
+                "pages": [{ "url": "https://example.com/", "hash": "abc" }],
+            })),
+        );
+        let session = bound_session(fake.clone());
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let res = handle_request(
+            request(
+                "tools/call",
+                json!({
+                    "name": "crawl",
+                    "arguments": { "startUrl": "https://example.com/" },
+                    "_meta": { "progressToken": "t1" },
+                }),
+                Some(json!(1)),
+            ),
+            &session,
+            Notifier::new(tx),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(res["isError"], false);
+        assert_eq!(res["structuredContent"]["siteMapId"], "MAP1");
+        let progress = rx.try_recv().unwrap();
+        assert_eq!(progress["method"], "notifications/progress");
+        assert_eq!(progress["params"]["progressToken"], "t1");
+        let (method, params) = &fake.calls()[0];
+        assert_eq!(method, "crawl");
+        assert_eq!(params["maxPages"], 50);
+        assert_eq!(params["sameOrigin"], true);
+        assert!(sandbox_dir().join("cache/sitemaps/MAP1.json").is_file());
+        let job_id = res["structuredContent"]["jobId"].as_str().unwrap();
+        assert!(sandbox_dir()
+            .join(format!("logs/{}.ndjson", job_id))
+            .is_file());
+
+        let job = dispatch(request(
+            "jobs/get",
+            json!({ "jobId": job_id }),
+            Some(json!(3)),
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(job["method"], "crawl");
+        assert_eq!(job["status"], "succeeded");
+        assert_eq!(job["params"]["startUrl"], "https://example.com/");
+        assert_eq!(job["entities"]["siteMapId"], "MAP1");
+
+        let res = dispatch(request(
+            "tools/call",
+            json!({ "name": "pack", "arguments": { "generationId": "G" } }),
+            Some(json!(2)),
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(res["isError"], true);
+        assert_eq!(res["structuredContent"]["error"]["code"], -32011);
+        assert_eq!(
+            res["structuredContent"]["error"]["data"]["class"],
+            "BAD_INPUT"
+        );
+
+        let failed = dispatch(request(
+            "jobs/list",
+            json!({ "status": "failed" }),
+            Some(json!(4)),
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(failed["total"], 1);
+        assert_eq!(failed["jobs"][0]["method"], "pack");
+        cleanup_site2ts();
+    }
+
+    #[tokio::test]
+    async fn another_servers_lock_blocks_tools_but_not_reads() {
+        let _guard = guard().await;
+        cleanup_site2ts();
+        let fake = Arc::new(FakeBackend::default());
+        fake.reply("crawl", Ok(json!({ "siteMapId": "MAP1", "pages": [] })));
+        let session = bound_session(fake.clone());
+        let call = |method: &str, params: Value| {
+            let session = session.clone();
+            let req = request(method, params, Some(json!(1)));
+            async move { handle_request(req, &session, Notifier::default()).await }
+        };
+        let lock = sandbox_dir().join("lock.json");
+        fs::create_dir_all(sandbox_dir()).unwrap();
+        fs::write(
+            &lock,
+            json!({
+                "pid": 4242,
+                "host": "ci-runner",
+                "startedAt": chrono::Utc::now().to_rfc3339(),
+                "method": "generate",
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let crawl = json!({ "startUrl": "https://example.com/" });
+        let err = call("crawl", crawl.clone()).await.unwrap().unwrap_err();
+        assert_eq!(err.code(), -32013);
+        assert!(err.message().contains("ci-runner"));
+        assert!(fake.calls().is_empty());
+        let listed = call("jobs/list", Value::Null).await.unwrap().unwrap();
+        assert_eq!(listed["jobs"][0]["status"], "failed");
+        let status = call("lock/status", Value::Null).await.unwrap().unwrap();
+        assert_eq!(status["holder"]["pid"], 4242);
+        assert_eq!(status["stale"], false);
+
+        let err = call("lock/break", Value::Null).await.unwrap().unwrap_err();
+        assert_eq!(err.code(), -32602);
+        call("lock/break", json!({ "force": true }))
+            .await
+            .unwrap()
+            .unwrap();
+        call("crawl", crawl).await.unwrap().unwrap();
+        assert!(!lock.exists());
+        cleanup_site2ts();
+    }
+
+    #[tokio::test]
+    async fn calls_before_init_are_rejected_and_init_binds_the_project() {
+        let _guard = guard().await;
+        cleanup_site2ts();
+        let fake = Arc::new(FakeBackend::default());
+        fake.reply("initRuntime", Ok(json!({})))
+            .reply("runtimeInfo", Ok(pins::pinned_runtime()))
+            .reply("crawl", Ok(json!({ "siteMapId": "MAP1", "pages": [] })));
+        let session = Arc::new(Session::default().with_backend(fake.clone()));
+        let call = |method: &str, params: Value| {
+            let session = session.clone();
+            let req = request(method, params, Some(json!(1)));
+            async move { handle_request(req, &session, Notifier::default()).await }
+        };
+
+        let crawl = json!({ "startUrl": "https://example.com/" });
+        for (method, params) in [
+            ("crawl", crawl.clone()),
+            ("jobs/list", Value::Null),
+            ("resources/read", json!({ "uri": "site2ts://apply/J/plan" })),
+        ] {
+            let err = call(method, params).await.unwrap().unwrap_err();
+            assert_eq!(err.code(), -32011, "{}", method);
+            assert!(err.message().contains("call init"), "{}", method);
+        }
+        assert!(fake.calls().is_empty());
+
+        let root = std::env::temp_dir().join(format!("site2ts-project-{}", Ulid::new()));
+        call("init", json!({ "projectRoot": root }))
+            .await
+            .unwrap()
+            .unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+        assert_eq!(session.project().get(), Some(root.clone()));
+
+        let res = call("crawl", crawl).await.unwrap().unwrap();
+        assert!(root.join(".site2ts/cache/sitemaps/MAP1.json").is_file());
+        let job_id = res["jobId"].as_str().unwrap();
+        assert!(root
+            .join(format!(".site2ts/jobs/{}.json", job_id))
+            .is_file());
+        let listed = call("jobs/list", Value::Null).await.unwrap().unwrap();
+        assert_eq!(listed["total"], 2);
+        assert!(!sandbox_dir().exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stages_fail_closed_when_the_runtime_drifts_from_the_pins() {
+        let _guard = guard().await;
+        let mut drifted = pins::pinned_runtime();
+        drifted["node"] = json!("22.3.0");
+        let fake = Arc::new(FakeBackend::default());
+        fake.reply("initRuntime", Ok(json!({})))
+            .reply("runtimeInfo", Ok(pins::pinned_runtime()))
+            .reply("runtimeInfo", Ok(drifted))
+            .reply("crawl", Ok(json!({ "siteMapId": "MAP1", "pages": [] })));
+        let session = Arc::new(Session::default().with_backend(fake.clone()));
+        let call = |method: &str, params: Value| {
+            let session = session.clone();
+            let req = request(method, params, Some(json!(1)));
+            async move { handle_request(req, &session, Notifier::default()).await }
+        };
+
+        let root = std::env::temp_dir().join(format!("site2ts-project-{}", Ulid::new()));
+        let res = call("init", json!({ "projectRoot": root }))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res["pinned"]["node"], "20.x");
+        assert!(root.join(".site2ts/pins.json").is_file());
+
+        let crawl = json!({ "startUrl": "https://example.com/" });
+        let err = call("crawl", crawl).await.unwrap().unwrap_err();
+        assert_eq!(err.code(), -32010);
+        let data = err.data.as_ref().unwrap();
+        assert_eq!(data["class"], "ENV_MISSING");
+        assert_eq!(data["mismatches"][0]["name"], "node");
+        assert_eq!(data["mismatches"][0]["expected"], "20.15.0");
+        assert_eq!(data["mismatches"][0]["actual"], "22.3.0");
+        assert!(fake.calls().iter().all(|(m, _)| m != "crawl"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn init_repairs_a_runtime_that_drifted_from_the_pins() {
+        let _guard = guard().await;
+        let mut drifted = pins::pinned_runtime();
+        drifted["chromium"]["installed"] = json!(false);
+        let fake = Arc::new(FakeBackend::default());
+        // initRuntime reinstalls Chromium, so the runtime matches again
+        // once the second init has run it.
+        fake.reply("initRuntime", Ok(json!({})))
+            .reply("runtimeInfo", Ok(pins::pinned_runtime()))
+            .reply("runtimeInfo", Ok(drifted))
+            .reply("runtimeInfo", Ok(pins::pinned_runtime()))
+            .reply("crawl", Ok(json!({ "siteMapId": "MAP1", "pages": [] })));
+        let session = Arc::new(Session::default().with_backend(fake.clone()));
+        let call = |method: &str, params: Value| {
+            let session = session.clone();
+            let req = request(method, params, Some(json!(1)));
+            async move { handle_request(req, &session, Notifier::default()).await }
+        };
+
+        let root = std::env::temp_dir().join(format!("site2ts-project-{}", Ulid::new()));
+        let init = json!({ "projectRoot": root });
+        call("init", init.clone()).await.unwrap().unwrap();
+        let crawl = json!({ "startUrl": "https://example.com/" });
+        let err = call("crawl", crawl.clone()).await.unwrap().unwrap_err();
+        assert_eq!(
+            err.data.as_ref().unwrap()["mismatches"][0]["name"],
+            "chromium"
+        );
+
+        let before = fake.calls().len();
+        call("init", init).await.unwrap().unwrap();
+        let methods: Vec<String> = fake.calls()[before..]
+            .iter()
+            .map(|(m, _)| m.clone())
+            .collect();
+        assert_eq!(methods, ["initRuntime", "runtimeInfo"]);
+        call("crawl", crawl).await.unwrap().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn init_upgrades_pins_to_a_runtime_that_no_longer_matches_them() {
+        let _guard = guard().await;
+        let mut drifted = pins::pinned_runtime();
+        drifted["node"] = json!("22.3.0");
+        let fake = Arc::new(FakeBackend::default());
+        fake.reply("initRuntime", Ok(json!({})))
+            .reply("runtimeInfo", Ok(pins::pinned_runtime()))
+            .reply("runtimeInfo", Ok(drifted))
+            .reply("crawl", Ok(json!({ "siteMapId": "MAP1", "pages": [] })));
+        let session = Arc::new(Session::default().with_backend(fake.clone()));
+        let call = |method: &str, params: Value| {
+            let session = session.clone();
+            let req = request(method, params, Some(json!(1)));
+            async move { handle_request(req, &session, Notifier::default()).await }
+        };
+
+        let root = std::env::temp_dir().join(format!("site2ts-project-{}", Ulid::new()));
+        call("init", json!({ "projectRoot": root }))
+            .await
+            .unwrap()
+            .unwrap();
+        // Node 22 is now installed: the old pins refuse it, plain re-init
+        // keeps them, and an upgrade to it goes through.
+        let crawl = json!({ "startUrl": "https://example.com/" });
+        let err = call("crawl", crawl.clone()).await.unwrap().unwrap_err();
+        assert_eq!(err.code(), -32010);
+        let err = call("init", json!({ "projectRoot": root }))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.code(), -32010);
+
+        let upgrade = json!({ "projectRoot": root, "upgrade": true, "pins": { "node": "22.3.0" } });
+        let res = call("init", upgrade).await.unwrap().unwrap();
+        assert_eq!(res["pinned"]["node"], "22.x");
+        assert_eq!(
+            res["upgrade"]["changes"],
+            json!({ "node": { "from": "20.15.0", "to": "22.3.0" } })
+        );
+        let id = res["upgrade"]["id"].as_str().unwrap();
+        assert!(root
+            .join(format!(".site2ts/upgrades/{}.json", id))
+            .is_file());
+        call("crawl", crawl).await.unwrap().unwrap();
+
+        // Rolling back restores the old pins, which the runtime fails again.
+        let rollback = json!({ "projectRoot": root, "rollback": id });
+        let err = call("init", rollback).await.unwrap().unwrap_err();
+        assert_eq!(err.code(), -32010);
+        assert_eq!(err.data.as_ref().unwrap()["pinned"]["node"], "20.15.0");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Register generation `id` with its chain back to a crawl, as if the
+    /// stages had run.
+    fn seed_generation(id: &str) {
+        let base = sandbox_dir();
+        lineage::record(
+            &base,
+            "crawl",
+            &json!({}),
+            &json!({ "siteMapId": "S0" }),
+            "J",
+        );
+        for (tool, params, result) in [
+            (
+                "analyze",
+                json!({ "siteMapId": "S0" }),
+                json!({ "analysisId": "A0" }),
+            ),
+            (
+                "scaffold",
+                json!({ "analysisId": "A0" }),
+                json!({ "scaffoldId": "SC0" }),
+            ),
+            (
+                "generate",
+                json!({ "analysisId": "A0", "scaffoldId": "SC0" }),
+                json!({ "generationId": id }),
+            ),
+        ] {
+            lineage::record(&base, tool, &params, &result, "J");
+        }
+    }
+
+    #[tokio::test]
+    async fn async_tool_calls_return_a_job_to_poll() {
+        let _guard = guard().await;
+        cleanup_site2ts();
+        seed_generation("G1");
+        let fake = Arc::new(FakeBackend::default());
+        fake.reply_with_progress(
+            "diff",
+            vec![json!({ "tool": "diff", "phase": "route", "current": 1, "total": 1 })],
+            Ok(json!({ "diffId": "D1", "perRoute": [{ "route": "/" }] })),
+        );
+        let session = bound_session(fake.clone());
+        let started = handle_request(
+            request(
+                "tools/call",
+                json!({
+                    "name": "diff",
+                    "arguments": { "generationId": "G1" },
+                    "_meta": { "async": true },
+                }),
+                Some(json!(1)),
+            ),
+            &session,
+            Notifier::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(started["structuredContent"]["status"], "running");
+        let job_id = started["structuredContent"]["jobId"].clone();
+
+        let waited = dispatch(request(
+            "jobs/wait",
+            json!({ "jobId": job_id, "timeoutMs": 5000 }),
+            Some(json!(2)),
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(waited["status"], "succeeded");
+        assert_eq!(waited["progress"]["total"], 1);
+        let res = dispatch(request(
+            "jobs/result",
+            json!({ "jobId": job_id }),
+            Some(json!(3)),
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(res["result"]["diffId"], "D1");
+        assert_eq!(res["result"]["perRoute"][0]["route"], "/");
+
+        // Legacy bare-method calls opt in the same way.
+        let started = handle_request(
+            request(
+                "diff",
+                json!({ "generationId": "G1", "_meta": { "async": true } }),
+                Some(json!(4)),
+            ),
+            &session,
+            Notifier::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(started["status"], "running");
+        let status = dispatch(request(
+            "jobs/wait",
+            json!({ "jobId": started["jobId"] }),
+            Some(json!(5)),
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(status["method"], "diff");
+        assert_eq!(status["status"], "succeeded");
+        cleanup_site2ts();
+    }
+
+    /// A fake that answers every pipeline stage successfully once the
+    /// `first` replies are used up.
+    fn pipeline_fake(first: &[(&str, RpcResult<Value>)]) -> Arc<FakeBackend> {
+        let fake = Arc::new(FakeBackend::default());
+        for (method, reply) in first {
+            fake.reply(method, reply.clone());
+        }
+        fake.reply("initRuntime", Ok(json!({ "ok": true })))
+            .reply("runtimeInfo", Ok(pins::pinned_runtime()))
+            .reply("crawl", Ok(json!({ "siteMapId": "S1", "pages": [] })))
+            .reply(
+                "analyze",
+                Ok(json!({ "analysisId": "A1", "routes": [], "assets": {} })),
+            )
+            .reply("scaffold", Ok(json!({ "scaffoldId": "SC1" })))
+            .reply("generate", Ok(json!({ "generationId": "G1" })))
+            .reply(
+                "diff",
+                Ok(json!({ "diffId": "D1", "summary": { "passed": 9, "failed": 1 } })),
+            )
+            .reply(
+                "audit",
+                Ok(json!({ "auditId": "AU1", "tsc": { "errors": 0 } })),
+            )
+            .reply("apply", Ok(json!({ "applied": false })));
+        fake
+    }
+
+    #[tokio::test]
+    async fn pipeline_threads_ids_and_keeps_apply_dry() {
+        let _guard = guard().await;
+        cleanup_site2ts();
+        let fake = pipeline_fake(&[]);
+        let report = call_tool(
+            "pipeline",
+            &json!({ "startUrl": "https://example.com/", "crawl": { "maxPages": 5 } }),
+            &mut fake_ctx(&fake),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report["ok"], true);
+        assert_eq!(report["dryRun"], true);
+        assert_eq!(report["applied"], false);
+        assert_eq!(report["ids"]["generationId"], "G1");
+        let stages = report["stages"].as_array().unwrap();
+        assert_eq!(stages.len(), 8);
+        assert!(stages.iter().all(|s| s["jobId"].is_string()));
+        let calls = fake.calls();
+        let params = |m: &str| calls.iter().find(|(n, _)| n == m).unwrap().1.clone();
+        assert_eq!(params("crawl")["maxPages"], 5);
+        assert_eq!(params("analyze")["siteMapId"], "S1");
+        assert_eq!(params("generate")["scaffoldId"], "SC1");
+        assert_eq!(params("apply")["dryRun"], true);
+
+        // The pipeline and each of its stages are jobs of their own.
+        let listed = jobs::list(&sandbox_dir(), Default::default()).unwrap();
+        assert_eq!(listed["total"], 9);
+        let traced = dispatch(request(
+            "lineage/get",
+            json!({ "id": "G1" }),
+            Some(json!(1)),
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(traced["siteMap"]["id"], "S1");
+        assert_eq!(traced["descendants"].as_array().unwrap().len(), 3);
+        cleanup_site2ts();
+    }
+
+    #[tokio::test]
+    async fn pipeline_stops_at_failed_gates_and_stage_errors() {
+        let _guard = guard().await;
+        cleanup_site2ts();
+        let fake = pipeline_fake(&[(
+            "audit",
+            Ok(json!({ "auditId": "AU1", "tsc": { "errors": 2 } })),
+        )]);
+        let report = call_tool(
+            "pipeline",
+            &json!({ "startUrl": "https://example.com/", "allowApply": true }),
+            &mut fake_ctx(&fake),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report["ok"], false);
+        assert_eq!(report["stoppedAt"], "audit");
+        assert_eq!(report["gate"]["errors"], 2);
+        assert_eq!(report["skipped"], json!(["apply"]));
+        assert!(fake.calls().iter().all(|(m, _)| m != "apply"));
+
+        let strict = pipeline_fake(&[]);
+        let report = call_tool(
+            "pipeline",
+            &json!({ "startUrl": "https://example.com/", "maxDiffFailureRate": 0.05 }),
+            &mut fake_ctx(&strict),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report["stoppedAt"], "diff");
+        assert_eq!(report["gate"]["failed"], 1);
+
+        let broken = pipeline_fake(&[(
+            "generate",
+            Err(RpcError::new(
+                -32003,
+                "scaffold output missing; run scaffold before generate",
+                None,
+            )),
+        )]);
+        let err = call_tool(
+            "pipeline",
+            &json!({ "startUrl": "https://example.com/" }),
+            &mut fake_ctx(&broken),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), -32003);
+        let data = err.data.as_ref().unwrap();
+        assert_eq!(data["stage"], "generate");
+        assert_eq!(data["partial"]["stages"].as_array().unwrap().len(), 4);
+        cleanup_site2ts();
+    }
+
+    #[tokio::test]
+    async fn pipeline_resume_reruns_only_failed_and_stale_stages() {
+        let _guard = guard().await;
+        cleanup_site2ts();
+        let fake = pipeline_fake(&[(
+            "audit",
+            Err(RpcError::new(-32603, "npm install timed out", None)),
+        )]);
+        let err = call_tool(
+            "pipeline",
+            &json!({ "startUrl": "https://example.com/" }),
+            &mut fake_ctx(&fake),
+        )
+        .await
+        .unwrap_err();
+        let pipeline_id = err.data.as_ref().unwrap()["partial"]["pipelineId"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let ran = |m: &str| fake.calls().iter().filter(|(n, _)| n == m).count();
+
+        let report = call_tool(
+            pipeline::RESUME_METHOD,
+            &json!({ "pipelineId": pipeline_id }),
+            &mut fake_ctx(&fake),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report["ok"], true);
+        assert_eq!(report["pipelineId"], pipeline_id.as_str());
+        assert_eq!(report["resumedAt"], "audit");
+        assert_eq!(report["ids"]["siteMapId"], "S1");
+        assert_eq!(report["stages"][1]["reused"], true);
+        assert_eq!((ran("crawl"), ran("audit"), ran("apply")), (1, 2, 1));
+
+        // Changing a param makes the stages it feeds stale.
+        let report = call_tool(
+            pipeline::RESUME_METHOD,
+            &json!({ "pipelineId": pipeline_id, "allowApply": true }),
+            &mut fake_ctx(&fake),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report["resumedAt"], "apply");
+        assert_eq!(report["applied"], true);
+        // A real apply is planned first, then written.
+        assert_eq!((ran("crawl"), ran("audit"), ran("apply")), (1, 2, 3));
+        let calls = fake.calls();
+        assert_eq!(calls[calls.len() - 2].1["dryRun"], true);
+        assert_eq!(calls.last().unwrap().1["dryRun"], false);
+
+        let err = call_tool(
+            pipeline::RESUME_METHOD,
+            &json!({ "pipelineId": "nope" }),
+            &mut fake_ctx(&fake),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), -32602);
+        cleanup_site2ts();
+    }
+
+    #[tokio::test]
+    async fn initialize_negotiates_protocol_and_advertises_tools() {
+        let res = dispatch(request(
+            "initialize",
+            json!({ "protocolVersion": "2024-11-05", "capabilities": {} }),
+            Some(json!(1)),
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(res["protocolVersion"], "2024-11-05");
+        assert!(res["capabilities"]["tools"].is_object());
+        assert_eq!(res["serverInfo"]["name"], "site2ts-server");
+        assert_eq!(
+            res["capabilities"]["experimental"]["site2tsWorker"]["workerVersion"],
+            "fake"
+        );
+
+        let res = dispatch(request(
+            "initialize",
+            json!({ "protocolVersion": "1999-01-01" }),
+            Some(json!(2)),
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(res["protocolVersion"], mcp::LATEST_PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn gc_is_reachable_as_a_tool_and_dry_runs() {
+        let _guard = guard().await;
+        cleanup_site2ts();
+        for d in ["D1", "D2", "D3"] {
+            let dir = sandbox_dir().join("reports/diff").join(d);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("report.json"), "{}").unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let gc = |dry_run: bool| {
+            dispatch(request(
+                "tools/call",
+                json!({ "name": "gc", "arguments": { "dryRun": dry_run, "keepDiffs": 1 } }),
+                Some(json!(1)),
+            ))
+        };
+
+        let res = gc(true).await.unwrap().unwrap();
+        assert_eq!(res["isError"], false);
+        let report = &res["structuredContent"];
+        assert_eq!(report["dryRun"], true);
+        assert_eq!(report["freedBytes"], 0);
+        let ids: Vec<&str> = report["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| i["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["D2", "D1"]);
+        assert!(sandbox_dir().join("reports/diff/D1").is_dir());
+
+        let res = gc(false).await.unwrap().unwrap();
+        assert_eq!(res["structuredContent"]["freedBytes"], 4);
+        assert!(!sandbox_dir().join("reports/diff/D1").exists());
+        assert!(sandbox_dir().join("reports/diff/D3").is_dir());
+        cleanup_site2ts();
+    }
+
+    #[tokio::test]
+    async fn tools_list_exposes_every_handler_with_schema() {
+        let res = dispatch(request("tools/list", json!({}), Some(json!(1))))
+            .await
+            .unwrap()
+            .unwrap();
+        let tools = res["tools"].as_array().unwrap();
+        for name in [
+            "init",
+            "crawl",
+            "analyze",
+            "scaffold",
+            "generate",
+            "diff",
+            "audit",
+            "apply",
+            "assets",
+            "pack",
+            "improve",
+            "pipeline",
+            "pipeline/resume",
+            "gc",
+        ] {
+            let tool = tools.iter().find(|t| t["name"] == name).unwrap();
+            assert_eq!(tool["inputSchema"]["type"], "object", "{}", name);
+            assert!(tool["inputSchema"].get("$schema").is_none());
+        }
+        let diff = tools.iter().find(|t| t["name"] == "diff").unwrap();
+        let viewport = &diff["inputSchema"]["properties"]["viewport"];
+        assert!(viewport.get("$ref").is_none());
+        assert_eq!(viewport["required"], json!(["w", "h", "deviceScale"]));
+    }
+
+    #[tokio::test]
+    async fn tools_call_rejects_unknown_tool_and_wraps_errors() {
+        let err = dispatch(request(
+            "tools/call",
+            json!({ "name": "nope", "arguments": {} }),
+            Some(json!(1)),
+        ))
+        .await
+        .unwrap()
         .unwrap_err();
-        assert_eq!(err.code(), -32006);
-        assert!(err.message().contains("generate"));
+        assert_eq!(err.code(), -32602);
+
+        let res = dispatch(request(
+            "tools/call",
+            json!({ "name": "init", "arguments": {} }),
+            Some(json!(2)),
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(res["isError"], true);
+        assert_eq!(res["structuredContent"]["error"]["code"], -32602);
+    }
+
+    #[tokio::test]
+    async fn cancellation_notifications_cancel_the_matching_request() {
+        let session = Arc::new(Session::default());
+        let guard = session.begin(&json!(5), "tools/call");
+        let token = guard.cancel_token();
+        let cancel = request(
+            "notifications/cancelled",
+            json!({ "requestId": 4, "reason": "wrong one" }),
+            None,
+        );
+        assert!(handle_request(cancel, &session, Notifier::default())
+            .await
+            .is_none());
+        assert_eq!(token.reason(), None);
+        let cancel = request("$/cancelRequest", json!({ "id": 5 }), None);
+        handle_request(cancel, &session, Notifier::default()).await;
+        assert_eq!(token.reason().as_deref(), Some("cancelled by client"));
+
+        let err = RpcError::new(
+            worker::CANCELLED_CODE,
+            "request cancelled: user",
+            Some(json!({ "state": "cancelled", "partial": { "pages": 3 } })),
+        );
+        let res = mcp::tool_call_result(Err(err));
+        assert_eq!(res["isError"], true);
+        assert_eq!(res["structuredContent"]["partial"]["pages"], 3);
+        assert_eq!(
+            res["structuredContent"]["error"]["data"]["state"],
+            "cancelled"
+        );
+    }
+
+    #[tokio::test]
+    async fn notifications_and_ping_follow_lifecycle() {
+        assert!(
+            dispatch(request("notifications/initialized", json!({}), None))
+                .await
+                .is_none()
+        );
+        let res = dispatch(request("ping", Value::Null, Some(json!("p"))))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res, json!({}));
     }
 }
@@ -0,0 +1,229 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::RpcError;
+
+/// Newest MCP protocol revision this server speaks.
+pub(crate) const LATEST_PROTOCOL_VERSION: &str = "2025-06-18";
+
+/// Protocol revisions we accept from a client during `initialize`.
+const SUPPORTED_PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+const COMMON_SCHEMA: &str = include_str!("../../../schemas/common.v1.json");
+
+struct ToolSpec {
+    name: &'static str,
+    description: &'static str,
+    schema: &'static str,
+}
+
+const TOOLS: &[ToolSpec] = &[
+    ToolSpec {
+        name: "init",
+        description: "Prepare the .site2ts sandbox under projectRoot, write pins.json and ensure the browser runtime.",
+        schema: include_str!("../../../schemas/init.request.v1.json"),
+    },
+    ToolSpec {
+        name: "crawl",
+        description: "Crawl a site from startUrl, cache page artifacts and persist a site map manifest.",
+        schema: include_str!("../../../schemas/crawl.request.v1.json"),
+    },
+    ToolSpec {
+        name: "analyze",
+        description: "Infer App Router routes, forms and assets from a crawled site map.",
+        schema: include_str!("../../../schemas/analyze.request.v1.json"),
+    },
+    ToolSpec {
+        name: "scaffold",
+        description: "Create the Next.js + Tailwind staging app for an analysis.",
+        schema: include_str!("../../../schemas/scaffold.request.v1.json"),
+    },
+    ToolSpec {
+        name: "generate",
+        description: "Convert crawled HTML into TSX pages inside the staging app.",
+        schema: include_str!("../../../schemas/generate.request.v1.json"),
+    },
+    ToolSpec {
+        name: "diff",
+        description: "Screenshot the generated app and compare it against the source site per route.",
+        schema: include_str!("../../../schemas/diff.request.v1.json"),
+    },
+    ToolSpec {
+        name: "audit",
+        description: "Run tsc and ESLint against the generated app and write reports.",
+        schema: include_str!("../../../schemas/audit.request.v1.json"),
+    },
+    ToolSpec {
+        name: "apply",
+        description: "Copy the generated app into target, or write a plan when dryRun is set.",
+        schema: include_str!("../../../schemas/apply.request.v1.json"),
+    },
+    ToolSpec {
+        name: "assets",
+        description: "Emit a manifest of downloaded and copied assets.",
+        schema: include_str!("../../../schemas/assets.request.v1.json"),
+    },
+    ToolSpec {
+        name: "pack",
+        description: "Produce a tarball of the staging output and reports.",
+        schema: include_str!("../../../schemas/pack.request.v1.json"),
+    },
+    ToolSpec {
+        name: "improve",
+        description: "Record an improvement instruction for a generated route.",
+        schema: include_str!("../../../schemas/improve.request.v1.json"),
+    },
+    ToolSpec {
+        name: "pipeline",
+        description: "Run init through apply in one call, passing ids between stages. Stops when tsc reports errors or too many diff routes fail; apply is a dry run unless allowApply is set.",
+        schema: include_str!("../../../schemas/pipeline.request.v1.json"),
+    },
+    ToolSpec {
+        name: "pipeline/resume",
+        description: "Resume a pipeline run from its checkpoints: stages with unchanged inputs are reused; the first failed, gated or changed stage and everything after it run again.",
+        schema: include_str!("../../../schemas/pipeline.resume.request.v1.json"),
+    },
+    ToolSpec {
+        name: "gc",
+        description: "Remove sandbox artifacts no live generation needs: old generations, unused siteMaps and crawl cache, old diff reports and logs. dryRun lists them with the bytes they would free; save stores the retention policy.",
+        schema: include_str!("../../../schemas/gc.request.v1.json"),
+    },
+];
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ToolCallParams {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) arguments: Value,
+}
+
+pub(crate) fn supports_protocol(version: &str) -> bool {
+    SUPPORTED_PROTOCOL_VERSIONS.contains(&version)
+}
+
+pub(crate) fn is_tool(name: &str) -> bool {
+    TOOLS.iter().any(|t| t.name == name)
+}
+
+/// `worker` is the worker's `hello` result, or the error that prevented
+/// one; it is advertised as the `site2tsWorker` experimental capability.
+pub(crate) fn initialize_result(params: &Value, worker: Value) -> Value {
+    let requested = params
+        .get("protocolVersion")
+        .and_then(|v| v.as_str())
+        .unwrap_or(LATEST_PROTOCOL_VERSION);
+    let version = if supports_protocol(requested) {
+        requested
+    } else {
+        LATEST_PROTOCOL_VERSION
+    };
+    json!({
+        "protocolVersion": version,
+        "capabilities": {
+            "tools": { "listChanged": false },
+            "resources": { "subscribe": true, "listChanged": false },
+            "experimental": { "site2tsWorker": worker }
+        },
+        "serverInfo": {
+            "name": "site2ts-server",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "instructions": "Convert a website into a Next.js + TypeScript app. Run tools in order: init, crawl, analyze, scaffold, generate, diff, audit, apply."
+    })
+}
+
+pub(crate) fn tools_list() -> Value {
+    let common: Value = serde_json::from_str(COMMON_SCHEMA).unwrap_or_else(|_| json!({}));
+    let tools: Vec<Value> = TOOLS
+        .iter()
+        .map(|t| {
+            json!({
+                "name": t.name,
+                "description": t.description,
+                "inputSchema": input_schema(t.schema, &common),
+            })
+        })
+        .collect();
+    json!({ "tools": tools })
+}
+
+/// Wrap a handler outcome in an MCP `CallToolResult`. Tool failures are
+/// reported in-band with `isError` so the model can see and react to them.
+pub(crate) fn tool_call_result(res: Result<Value, RpcError>) -> Value {
+    match res {
+        Ok(value) => {
+            let text = serde_json::to_string_pretty(&value).unwrap_or_default();
+            let mut out = json!({
+                "content": [{ "type": "text", "text": text }],
+                "isError": false
+            });
+            if value.is_object() {
+                out["structuredContent"] = value;
+            }
+            out
+        }
+        Err(err) => {
+            let mut body = json!({ "ok": false, "error": err.to_json() });
+            // The error model reports work finished before a cancellation
+            // next to the error, not inside it.
+            if let Some(partial) = err.data.as_ref().and_then(|d| d.get("partial")) {
+                body["partial"] = partial.clone();
+            }
+            json!({
+                "content": [{ "type": "text", "text": serde_json::to_string_pretty(&body).unwrap_or_default() }],
+                "structuredContent": body,
+                "isError": true
+            })
+        }
+    }
+}
+
+/// Turn a bundled request schema into an MCP `inputSchema`: drop the
+/// document-level keywords and inline references into common.v1.json so
+/// clients never have to resolve external documents. Request schemas carry
+/// no local `$defs`, so bare `#/$defs/..` pointers (used inside common.v1.json
+/// itself) resolve against the common document too.
+fn input_schema(raw: &str, common: &Value) -> Value {
+    let mut schema: Value = serde_json::from_str(raw).unwrap_or_else(|_| json!({}));
+    if let Some(obj) = schema.as_object_mut() {
+        obj.remove("$schema");
+        obj.remove("$id");
+    }
+    inline_common_refs(&mut schema, common);
+    schema
+}
+
+fn inline_common_refs(value: &mut Value, common: &Value) {
+    match value {
+        Value::Object(obj) => {
+            if let Some(def) = obj
+                .get("$ref")
+                .and_then(|r| r.as_str())
+                .and_then(|r| {
+                    r.strip_prefix("common.v1.json#/$defs/")
+                        .or_else(|| r.strip_prefix("#/$defs/"))
+                })
+                .and_then(|name| common.pointer(&format!("/$defs/{}", name)))
+            {
+                let mut def = def.clone();
+                inline_common_refs(&mut def, common);
+                obj.remove("$ref");
+                if let Value::Object(def) = def {
+                    for (k, v) in def {
+                        obj.entry(k).or_insert(v);
+                    }
+                }
+                return;
+            }
+            for v in obj.values_mut() {
+                inline_common_refs(v, common);
+            }
+        }
+        Value::Array(items) => {
+            for v in items {
+                inline_common_refs(v, common);
+            }
+        }
+        _ => {}
+    }
+}
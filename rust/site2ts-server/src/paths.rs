@@ -0,0 +1,199 @@
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::{RpcError, RpcResult};
+
+/// Path components `apply` never writes under, wherever they appear.
+const EXCLUDED_DIRS: &[&str] = &[".git", ".site2ts", "node_modules"];
+
+/// Files `apply` never writes: `.env`, `.env.local` and the like.
+const EXCLUDED_PREFIX: &str = ".env";
+
+/// A path `apply` may not touch, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Violation {
+    path: String,
+    reason: String,
+}
+
+impl Violation {
+    fn new(path: &Path, reason: impl Into<String>) -> Self {
+        Self {
+            path: path.display().to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Resolve `path` against `root` the way the filesystem will: `..` is
+/// applied after any symlink before it has been followed. Parts that do
+/// not exist yet are kept as written. A dangling symlink cannot be
+/// followed and is refused.
+fn resolve(root: &Path, path: &Path) -> Result<PathBuf, String> {
+    let mut out = root.to_path_buf();
+    for comp in path.components() {
+        match comp {
+            Component::Prefix(_) | Component::RootDir => {
+                out = PathBuf::from(comp.as_os_str());
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::Normal(part) => {
+                out.push(part);
+                match fs::canonicalize(&out) {
+                    Ok(real) => out = real,
+                    Err(_) if fs::symlink_metadata(&out).is_ok() => {
+                        return Err(format!("dangling symlink {}", out.display()));
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Check that `base`/`rel` stays inside `root` once resolved and is not
+/// excluded, neither as written nor as resolved: a symlink must not sneak a
+/// write into an excluded dir, nor a `..` out of one. `root` must already
+/// be canonical.
+fn check(root: &Path, base: &Path, rel: &Path) -> Result<PathBuf, Violation> {
+    let excluded_in = |path: &Path| {
+        excluded(path).map(|name| Violation::new(rel, format!("{} is excluded", name)))
+    };
+    if rel.is_relative() {
+        if let Some(v) = excluded_in(rel) {
+            return Err(v);
+        }
+    }
+    let resolved = resolve(root, &base.join(rel)).map_err(|reason| Violation::new(rel, reason))?;
+    let Ok(inside) = resolved.strip_prefix(root) else {
+        let reason = format!("resolves to {}, outside the project", resolved.display());
+        return Err(Violation::new(rel, reason));
+    };
+    match excluded_in(inside) {
+        Some(v) => Err(v),
+        None => Ok(resolved),
+    }
+}
+
+/// The first component of `path` that `apply` must not write under.
+fn excluded(path: &Path) -> Option<String> {
+    path.components().find_map(|comp| match comp {
+        Component::Normal(part) => {
+            let name = part.to_string_lossy();
+            (EXCLUDED_DIRS.contains(&name.as_ref()) || name.starts_with(EXCLUDED_PREFIX))
+                .then(|| name.into_owned())
+        }
+        _ => None,
+    })
+}
+
+fn blocked(violations: &[Violation]) -> RpcError {
+    let paths: Vec<Value> = violations
+        .iter()
+        .map(|v| json!({ "path": v.path, "reason": v.reason }))
+        .collect();
+    let first = &violations[0];
+    let message = match violations.len() {
+        1 => format!("apply blocked: {}: {}", first.path, first.reason),
+        n => format!(
+            "apply blocked: {} paths not allowed, first {}: {}",
+            n, first.path, first.reason
+        ),
+    };
+    RpcError::apply_blocked(message, json!({ "paths": paths }))
+}
+
+/// Canonicalize the `apply` target `target` inside project `root`.
+pub(crate) fn apply_target(root: &Path, target: &str) -> RpcResult<PathBuf> {
+    let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    check(&root, &root, Path::new(target)).map_err(|v| blocked(&[v]))
+}
+
+/// Check every file an `apply` plan writes or deletes under `target`: each
+/// must resolve inside `root` and stay out of the excluded paths.
+pub(crate) fn check_plan(root: &Path, target: &Path, plan: &Value) -> RpcResult<()> {
+    let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    let writes = plan["changedFiles"].as_array().into_iter().flatten();
+    let deletes = plan["deletedFiles"]["removed"]
+        .as_array()
+        .into_iter()
+        .flatten();
+    let violations: Vec<Violation> = writes
+        .chain(deletes)
+        .filter_map(Value::as_str)
+        .filter_map(|rel| {
+            let rel = Path::new(rel);
+            if !rel.is_relative() {
+                return Some(Violation::new(rel, "plan paths must be relative"));
+            }
+            check(&root, target, rel).err()
+        })
+        .collect();
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(blocked(&violations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ulid::Ulid;
+
+    #[test]
+    fn writes_outside_the_project_or_into_excluded_paths_are_blocked() {
+        let tmp = std::env::temp_dir().join(format!("site2ts-paths-{}", Ulid::new()));
+        let root = tmp.join("project");
+        let outside = tmp.join("outside");
+        fs::create_dir_all(root.join("app")).unwrap();
+        fs::create_dir_all(root.join("node_modules")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, root.join("app/escape")).unwrap();
+            std::os::unix::fs::symlink(root.join("node_modules"), root.join("deps")).unwrap();
+        }
+
+        let target = apply_target(&root, "./").unwrap();
+        assert_eq!(target, root);
+        assert_eq!(apply_target(&root, "app/../app").unwrap(), root.join("app"));
+        let err = apply_target(&root, "../outside").unwrap_err();
+        assert_eq!(err.code(), -32012);
+        assert_eq!(err.data.as_ref().unwrap()["class"], "APPLY_BLOCKED");
+        assert!(apply_target(&root, outside.to_str().unwrap()).is_err());
+
+        let plan = json!({
+            "changedFiles": ["app/page.tsx", "app/new/page.tsx", "package.json"],
+            "deletedFiles": { "removed": ["app/old/page.tsx"], "skipped": [".env"] },
+        });
+        check_plan(&root, &target, &plan).unwrap();
+
+        let mut bad = vec![
+            "../x.ts",
+            ".env.local",
+            ".git/config",
+            "lib/node_modules/a.js",
+            "/etc/passwd",
+        ];
+        if cfg!(unix) {
+            bad.extend(["app/escape/page.tsx", "deps/a.js", "app/escape/../../x"]);
+        }
+        let plan = json!({ "changedFiles": ["app/page.tsx"], "deletedFiles": { "removed": bad } });
+        let err = check_plan(&root, &target, &plan).unwrap_err();
+        let paths: Vec<&str> = err.data.as_ref().unwrap()["paths"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths, bad);
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+}
@@ -0,0 +1,660 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{jobs, RequestContext, RpcError, RpcResult};
+
+/// Worker method reporting the installed node, npm, Playwright and Chromium.
+const RUNTIME_INFO: &str = "runtimeInfo";
+
+/// Tools checked against the pins before they run.
+const STAGES: &[&str] = &[
+    "crawl", "analyze", "scaffold", "generate", "diff", "audit", "apply", "assets", "pack",
+    "improve",
+];
+
+/// Stages that drive Chromium.
+const BROWSER_STAGES: &[&str] = &["crawl", "diff"];
+
+/// Stages that build or read the staging app. `scaffold` is not one: it
+/// rewrites staging's package.json from the pins.
+const STAGING_STAGES: &[&str] = &[
+    "generate", "diff", "audit", "apply", "assets", "pack", "improve",
+];
+
+/// Versions a project is pinned to, as written to `.site2ts/pins.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Pins {
+    node: String,
+    /// Pins files written before npm was pinned lack it; it is then not
+    /// checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    npm: Option<String>,
+    next: String,
+    typescript: String,
+    playwright: String,
+    tailwind: String,
+    #[serde(default)]
+    created_at: String,
+}
+
+impl Default for Pins {
+    fn default() -> Self {
+        Self {
+            node: "20.15.0".into(),
+            npm: Some("10.7.0".into()),
+            next: "14.2.32".into(),
+            typescript: "5.5.4".into(),
+            playwright: "1.46.0".into(),
+            tailwind: "3.4.10".into(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+impl Pins {
+    pub(crate) fn path(base: &Path) -> PathBuf {
+        base.join("pins.json")
+    }
+
+    /// The project's pins; `None` when it has none yet.
+    pub(crate) fn load(base: &Path) -> RpcResult<Option<Self>> {
+        let Ok(bytes) = fs::read(Self::path(base)) else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&bytes).map(Some).map_err(|e| {
+            RpcError::env_missing(
+                format!("pins.json is unreadable: {}; fix or remove it", e),
+                json!({ "missing": "pins", "pins": Self::path(base) }),
+            )
+        })
+    }
+
+    pub(crate) fn save(&self, base: &Path) -> Result<()> {
+        jobs::write_atomic(&Self::path(base), &serde_json::to_value(self)?)
+    }
+
+    /// The major versions, as `init` reports them.
+    pub(crate) fn summary(&self) -> Value {
+        let x = |v: &str| format!("{}.x", major(v));
+        json!({
+            "node": x(&self.node),
+            "next": x(&self.next),
+            "ts": x(&self.typescript),
+            "playwright": x(&self.playwright),
+        })
+    }
+
+    /// Staging dependencies and the version each is pinned to.
+    fn staging_deps(&self) -> [(&'static str, &str); 3] {
+        [
+            ("next", &self.next),
+            ("typescript", &self.typescript),
+            ("tailwindcss", &self.tailwind),
+        ]
+    }
+}
+
+/// Pins `init` is asked to set; unset ones keep their current value.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PinOverrides {
+    node: Option<String>,
+    npm: Option<String>,
+    next: Option<String>,
+    typescript: Option<String>,
+    playwright: Option<String>,
+    tailwind: Option<String>,
+}
+
+impl PinOverrides {
+    /// `pins` with the overrides applied, each checked to be a version.
+    fn apply(&self, mut pins: Pins) -> RpcResult<Pins> {
+        let fields = [
+            ("node", &self.node, &mut pins.node),
+            ("next", &self.next, &mut pins.next),
+            ("typescript", &self.typescript, &mut pins.typescript),
+            ("playwright", &self.playwright, &mut pins.playwright),
+            ("tailwind", &self.tailwind, &mut pins.tailwind),
+        ];
+        for (name, value, pin) in fields {
+            if let Some(value) = value {
+                *pin = checked(name, value)?;
+            }
+        }
+        if let Some(npm) = &self.npm {
+            pins.npm = Some(checked("npm", npm)?);
+        }
+        pins.created_at = chrono::Utc::now().to_rfc3339();
+        Ok(pins)
+    }
+}
+
+fn checked(name: &str, version: &str) -> RpcResult<String> {
+    if parse(version).is_some() {
+        return Ok(version.to_string());
+    }
+    Err(RpcError::bad_input(
+        format!("pins.{}: {:?} is not a version like 1.2.3", name, version),
+        json!({ "field": format!("pins.{}", name), "value": version }),
+    ))
+}
+
+/// How `init` may change a project's existing pins.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct PinChange {
+    /// Replace the pins, with `pins` or else this server's defaults.
+    #[serde(default)]
+    upgrade: bool,
+    pins: Option<PinOverrides>,
+    /// Id of an upgrade record whose old pins to restore.
+    rollback: Option<String>,
+}
+
+fn upgrades_dir(base: &Path) -> PathBuf {
+    base.join("upgrades")
+}
+
+/// Pins that differ between `from` and `to`, each as `{from, to}`.
+fn changes(from: Option<&Pins>, to: &Pins) -> Map<String, Value> {
+    let old = from.map_or(Value::Null, |p| json!(p));
+    json!(to)
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(name, new)| name.as_str() != "createdAt" && old[name.as_str()] != **new)
+        .map(|(name, new)| {
+            (
+                name.clone(),
+                json!({ "from": old[name.as_str()], "to": new }),
+            )
+        })
+        .collect()
+}
+
+/// The upgrade record `id`, looked up among the records so an id cannot
+/// name a path.
+fn read_record(base: &Path, id: &str) -> RpcResult<Value> {
+    let name = format!("{}.json", id);
+    let known = fs::read_dir(upgrades_dir(base))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|e| e.file_name().to_str() == Some(name.as_str()));
+    let record = known
+        .then(|| fs::read(upgrades_dir(base).join(&name)).ok())
+        .flatten()
+        .and_then(|b| serde_json::from_slice::<Value>(&b).ok());
+    record.ok_or_else(|| {
+        RpcError::bad_input(
+            format!("unknown upgrade record: {}", id),
+            json!({ "field": "rollback", "id": id, "upgrades": upgrades_dir(base) }),
+        )
+    })
+}
+
+/// Move the pins from `from` to `to`, keeping the diff as an upgrade record
+/// `.site2ts/upgrades/<timestamp>.json`. Nothing is written when nothing
+/// changes.
+fn record_change(
+    base: &Path,
+    from: Option<Pins>,
+    to: Pins,
+    rollback_of: Option<&str>,
+) -> RpcResult<(Pins, Option<Value>)> {
+    let changes = changes(from.as_ref(), &to);
+    if changes.is_empty() {
+        return Ok((from.unwrap_or(to), None));
+    }
+    let now = chrono::Utc::now();
+    let stamp = now.format("%Y%m%dT%H%M%S%3fZ").to_string();
+    let id = (0..)
+        .map(|n| match n {
+            0 => stamp.clone(),
+            n => format!("{}-{}", stamp, n),
+        })
+        .find(|id| !upgrades_dir(base).join(format!("{}.json", id)).exists())
+        .unwrap_or(stamp);
+    let mut record = json!({
+        "id": id,
+        "createdAt": now.to_rfc3339(),
+        "kind": if rollback_of.is_some() { "rollback" } else { "upgrade" },
+        "from": from,
+        "to": to,
+        "changes": changes,
+    });
+    if let Some(of) = rollback_of {
+        record["rollbackOf"] = json!(of);
+    }
+    let path = upgrades_dir(base).join(format!("{}.json", id));
+    jobs::write_atomic(&path, &record).map_err(|e| RpcError::internal(e.to_string()))?;
+    to.save(base)
+        .map_err(|e| RpcError::internal(e.to_string()))?;
+    Ok((to, Some(record)))
+}
+
+/// Decide the pins `init` leaves in `base` and write them. A project
+/// without pins gets this server's defaults, with `pins` applied. Existing
+/// pins are kept: changing them takes `upgrade`, or `rollback` to the pins
+/// an earlier upgrade replaced, and each change is kept as an upgrade
+/// record. Returns the pins and the record, if one was written.
+pub(crate) fn settle(base: &Path, change: &PinChange) -> RpcResult<(Pins, Option<Value>)> {
+    let current = Pins::load(base)?;
+    if let Some(id) = &change.rollback {
+        if change.upgrade || change.pins.is_some() {
+            return Err(RpcError::invalid_params(
+                "rollback cannot be combined with upgrade or pins",
+            ));
+        }
+        let record = read_record(base, id)?;
+        let restored: Pins = serde_json::from_value(record["from"].clone()).map_err(|_| {
+            RpcError::bad_input(
+                format!("upgrade record {} has no earlier pins to restore", id),
+                json!({ "field": "rollback", "id": id }),
+            )
+        })?;
+        let restored = Pins {
+            created_at: chrono::Utc::now().to_rfc3339(),
+            ..restored
+        };
+        return record_change(base, current, restored, Some(id));
+    }
+    let Some(current) = current else {
+        let pins = match &change.pins {
+            Some(overrides) => overrides.apply(Pins::default())?,
+            None => Pins::default(),
+        };
+        pins.save(base)
+            .map_err(|e| RpcError::internal(e.to_string()))?;
+        return Ok((pins, None));
+    };
+    if change.upgrade {
+        let to = match &change.pins {
+            Some(overrides) => overrides.apply(current.clone())?,
+            None => Pins::default(),
+        };
+        return record_change(base, Some(current), to, None);
+    }
+    if let Some(overrides) = &change.pins {
+        let changes = changes(Some(&current), &overrides.apply(current.clone())?);
+        if !changes.is_empty() {
+            let names: Vec<&str> = changes.keys().map(String::as_str).collect();
+            return Err(RpcError::bad_input(
+                format!(
+                    "pins.json already pins {}; pass upgrade: true to change it",
+                    names.join(", ")
+                ),
+                json!({ "pins": Pins::path(base), "changes": changes }),
+            ));
+        }
+    }
+    Ok((current, None))
+}
+
+/// How an installed version must relate to its pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum Rule {
+    /// Same major version, and not older than the pin.
+    SameMajorAtLeast,
+    Exact,
+    Installed,
+}
+
+/// One component that does not match its pin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Mismatch {
+    name: String,
+    expected: String,
+    /// `None` when it is not installed at all.
+    actual: Option<String>,
+    rule: Rule,
+    /// Where `actual` was read, for staging dependencies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+}
+
+impl Mismatch {
+    fn describe(&self) -> String {
+        let actual = self.actual.as_deref().unwrap_or("missing");
+        let rule = match self.rule {
+            Rule::SameMajorAtLeast => format!(
+                "want {}.x, at least {}",
+                major(&self.expected),
+                self.expected
+            ),
+            Rule::Exact => format!("want exactly {}", self.expected),
+            Rule::Installed => "want it installed".to_string(),
+        };
+        match &self.source {
+            Some(source) => format!("{} {} in {} ({})", self.name, actual, source, rule),
+            None => format!("{} {} ({})", self.name, actual, rule),
+        }
+    }
+}
+
+/// `major.minor.patch` of a version like `v20.15.0` or `1.46.0-beta`.
+fn parse(version: &str) -> Option<(u64, u64, u64)> {
+    let core = version.trim().trim_start_matches('v');
+    let core = core.split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|p| p.parse::<u64>().ok());
+    let major = parts.next()??;
+    Some((
+        major,
+        parts.next().flatten()?,
+        parts.next().flatten().unwrap_or(0),
+    ))
+}
+
+fn major(version: &str) -> String {
+    parse(version).map_or_else(|| version.to_string(), |v| v.0.to_string())
+}
+
+fn same_major_at_least(expected: &str, actual: &str) -> bool {
+    match (parse(expected), parse(actual)) {
+        (Some(want), Some(have)) => have.0 == want.0 && have >= want,
+        _ => false,
+    }
+}
+
+/// Runtime tools that do not match `pins`, from the worker's `runtimeInfo`.
+fn runtime_mismatches(pins: &Pins, runtime: &Value, browser: bool) -> Vec<Mismatch> {
+    let tools = [
+        ("node", Some(&pins.node)),
+        ("npm", pins.npm.as_ref()),
+        ("playwright", Some(&pins.playwright)),
+    ];
+    let mut out: Vec<Mismatch> = tools
+        .into_iter()
+        .filter_map(|(name, pin)| {
+            let expected = pin?;
+            let actual = runtime[name].as_str();
+            (!actual.is_some_and(|a| same_major_at_least(expected, a))).then(|| Mismatch {
+                name: name.to_string(),
+                expected: expected.clone(),
+                actual: actual.map(str::to_string),
+                rule: Rule::SameMajorAtLeast,
+                source: None,
+            })
+        })
+        .collect();
+    if browser && runtime["chromium"]["installed"] != true {
+        out.push(Mismatch {
+            name: "chromium".into(),
+            expected: format!("playwright {} build", pins.playwright),
+            actual: None,
+            rule: Rule::Installed,
+            source: runtime["chromium"]["executable"]
+                .as_str()
+                .map(str::to_string),
+        });
+    }
+    out
+}
+
+/// Staging dependencies in `staging` that are not exactly pinned, as
+/// declared in its package.json and as installed in its node_modules. A
+/// staging dir without a package.json has nothing to check yet.
+fn staging_mismatches(pins: &Pins, base: &Path, staging: &Path) -> Vec<Mismatch> {
+    let read =
+        |path: &Path| -> Option<Value> { serde_json::from_slice(&fs::read(path).ok()?).ok() };
+    let shown = |path: &Path| {
+        Path::new(".site2ts")
+            .join(path.strip_prefix(base).unwrap_or(path))
+            .display()
+            .to_string()
+    };
+    let manifest_path = staging.join("package.json");
+    let Some(manifest) = read(&manifest_path) else {
+        return Vec::new();
+    };
+    let mut out = Vec::new();
+    for (dep, pin) in pins.staging_deps() {
+        let declared = manifest["dependencies"][dep]
+            .as_str()
+            .or_else(|| manifest["devDependencies"][dep].as_str());
+        if declared != Some(pin) {
+            out.push(Mismatch {
+                name: dep.to_string(),
+                expected: pin.to_string(),
+                actual: declared.map(str::to_string),
+                rule: Rule::Exact,
+                source: Some(shown(&manifest_path)),
+            });
+        }
+        let installed_path = staging.join("node_modules").join(dep).join("package.json");
+        let installed = read(&installed_path);
+        let version = installed.as_ref().and_then(|p| p["version"].as_str());
+        if version.is_some_and(|v| v != pin) {
+            out.push(Mismatch {
+                name: dep.to_string(),
+                expected: pin.to_string(),
+                actual: version.map(str::to_string),
+                rule: Rule::Exact,
+                source: Some(shown(&installed_path)),
+            });
+        }
+    }
+    out
+}
+
+/// The staging dir `tool` works on: the generation's own copy when it is
+/// given one, the shared one otherwise.
+fn staging_of(base: &Path, params: &Value) -> PathBuf {
+    match params.get("generationId").and_then(Value::as_str) {
+        Some(id) => base.join("generations").join(id).join("staging"),
+        None => base.join("staging"),
+    }
+}
+
+fn mismatched(base: &Path, pins: &Pins, mismatches: &[Mismatch]) -> RpcError {
+    let listed: Vec<String> = mismatches.iter().map(Mismatch::describe).collect();
+    let staging = mismatches.iter().any(|m| m.rule == Rule::Exact);
+    let hint = if staging {
+        "install the pinned versions; re-run scaffold to rewrite staging's package.json"
+    } else {
+        "install the pinned versions"
+    };
+    RpcError::env_missing(
+        format!(
+            "runtime does not match pins.json: {}; {}",
+            listed.join("; "),
+            hint
+        ),
+        json!({
+            "missing": "pinned runtime",
+            "pins": Pins::path(base),
+            "pinned": pins,
+            "mismatches": mismatches,
+        }),
+    )
+}
+
+/// Fail closed when the runtime, or the staging app `tool` will use, does
+/// not match the project's pins. Projects without pins are not checked.
+/// `init` is not a stage: it repairs the runtime, so it checks afterwards
+/// with [`verify_init`].
+pub(crate) async fn verify(ctx: &mut RequestContext, tool: &str, params: &Value) -> RpcResult<()> {
+    if !STAGES.contains(&tool) {
+        return Ok(());
+    }
+    let staging = STAGING_STAGES
+        .contains(&tool)
+        .then(|| staging_of(&ctx.sandbox(), params));
+    check(ctx, BROWSER_STAGES.contains(&tool), staging).await
+}
+
+/// Check everything against the pins `init` settled on, once it has
+/// installed the runtime.
+pub(crate) async fn verify_init(ctx: &mut RequestContext) -> RpcResult<()> {
+    let staging = ctx.sandbox().join("staging");
+    check(ctx, true, Some(staging)).await
+}
+
+async fn check(ctx: &mut RequestContext, browser: bool, staging: Option<PathBuf>) -> RpcResult<()> {
+    let base = ctx.sandbox();
+    let Some(pins) = Pins::load(&base)? else {
+        return Ok(());
+    };
+    let runtime = ctx.call(RUNTIME_INFO, json!({})).await?;
+    let mut mismatches = runtime_mismatches(&pins, &runtime, browser);
+    if let Some(staging) = staging {
+        mismatches.extend(staging_mismatches(&pins, &base, &staging));
+    }
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatched(&base, &pins, &mismatches))
+    }
+}
+
+/// What `runtimeInfo` reports on a machine that matches the default pins.
+#[cfg(test)]
+pub(crate) fn pinned_runtime() -> Value {
+    let pins = Pins::default();
+    json!({
+        "node": pins.node,
+        "npm": pins.npm,
+        "playwright": pins.playwright,
+        "chromium": { "executable": "/pw/chromium/chrome", "installed": true },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_and_staging_are_held_to_their_pins() {
+        let pins = Pins::default();
+        assert!(runtime_mismatches(&pins, &pinned_runtime(), true).is_empty());
+
+        let mut runtime = pinned_runtime();
+        runtime["node"] = json!("20.18.1");
+        runtime["playwright"] = json!("1.57.0");
+        assert!(runtime_mismatches(&pins, &runtime, true).is_empty());
+
+        runtime["node"] = json!("22.3.0");
+        runtime["npm"] = Value::Null;
+        runtime["playwright"] = json!("1.45.3");
+        runtime["chromium"]["installed"] = json!(false);
+        let found = runtime_mismatches(&pins, &runtime, false);
+        let names: Vec<&str> = found.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["node", "npm", "playwright"]);
+        assert_eq!(found[0].actual.as_deref(), Some("22.3.0"));
+        assert!(found[1].describe().starts_with("npm missing"));
+        assert_eq!(runtime_mismatches(&pins, &runtime, true).len(), 4);
+
+        let base = std::env::temp_dir().join(format!("site2ts-pins-{}", ulid::Ulid::new()));
+        let staging = base.join("staging");
+        assert!(staging_mismatches(&pins, &base, &staging).is_empty());
+        fs::create_dir_all(staging.join("node_modules/next")).unwrap();
+        let manifest = json!({
+            "dependencies": { "next": pins.next, "react": "18.3.1" },
+            "devDependencies": { "typescript": "^5.5.4", "tailwindcss": pins.tailwind },
+        });
+        fs::write(staging.join("package.json"), manifest.to_string()).unwrap();
+        fs::write(
+            staging.join("node_modules/next/package.json"),
+            json!({ "version": "14.2.5" }).to_string(),
+        )
+        .unwrap();
+        let found = staging_mismatches(&pins, &base, &staging);
+        let err = mismatched(&base, &pins, &found);
+        assert_eq!(err.code(), -32010);
+        let data = err.data.as_ref().unwrap();
+        assert_eq!(data["class"], "ENV_MISSING");
+        assert_eq!(
+            data["mismatches"],
+            json!([
+                {
+                    "name": "next",
+                    "expected": pins.next,
+                    "actual": "14.2.5",
+                    "rule": "exact",
+                    "source": ".site2ts/staging/node_modules/next/package.json",
+                },
+                {
+                    "name": "typescript",
+                    "expected": "5.5.4",
+                    "actual": "^5.5.4",
+                    "rule": "exact",
+                    "source": ".site2ts/staging/package.json",
+                },
+            ])
+        );
+        assert!(err.message().contains("re-run scaffold"));
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    fn change(value: Value) -> PinChange {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn init_keeps_pins_unless_upgraded_and_records_each_change() {
+        let base = std::env::temp_dir().join(format!("site2ts-pins-{}", ulid::Ulid::new()));
+        let (pins, record) =
+            settle(&base, &change(json!({ "pins": { "next": "14.2.5" } }))).unwrap();
+        assert_eq!(pins.next, "14.2.5");
+        assert!(record.is_none());
+
+        // Existing pins are kept, and never silently replaced.
+        let (kept, _) = settle(&base, &PinChange::default()).unwrap();
+        assert_eq!(kept, pins);
+        settle(&base, &change(json!({ "pins": { "next": "14.2.5" } }))).unwrap();
+        let err = settle(&base, &change(json!({ "pins": { "next": "14.2.32" } }))).unwrap_err();
+        assert_eq!(err.code(), -32011);
+        assert_eq!(
+            err.data.as_ref().unwrap()["changes"]["next"]["to"],
+            "14.2.32"
+        );
+        assert_eq!(Pins::load(&base).unwrap().unwrap().next, "14.2.5");
+        let err = settle(
+            &base,
+            &change(json!({ "upgrade": true, "pins": { "next": "latest" } })),
+        )
+        .unwrap_err();
+        assert!(err.message().contains("pins.next"));
+
+        let (upgraded, record) = settle(
+            &base,
+            &change(json!({ "upgrade": true, "pins": { "next": "14.2.32", "npm": "10.8.2" } })),
+        )
+        .unwrap();
+        let record = record.unwrap();
+        assert_eq!(upgraded.next, "14.2.32");
+        assert_eq!(record["kind"], "upgrade");
+        assert_eq!(
+            record["changes"],
+            json!({
+                "next": { "from": "14.2.5", "to": "14.2.32" },
+                "npm": { "from": "10.7.0", "to": "10.8.2" },
+            })
+        );
+        let id = record["id"].as_str().unwrap();
+        assert!(upgrades_dir(&base).join(format!("{}.json", id)).is_file());
+        assert_eq!(Pins::load(&base).unwrap().unwrap(), upgraded);
+
+        let (restored, undo) = settle(&base, &change(json!({ "rollback": id }))).unwrap();
+        assert_eq!(
+            (restored.next.as_str(), restored.npm.as_deref()),
+            ("14.2.5", Some("10.7.0"))
+        );
+        let undo = undo.unwrap();
+        assert_eq!(undo["kind"], "rollback");
+        assert_eq!(undo["rollbackOf"], id);
+        assert_ne!(undo["id"], id);
+        assert_eq!(fs::read_dir(upgrades_dir(&base)).unwrap().count(), 2);
+
+        for bad in ["nope", "../pins"] {
+            let err = settle(&base, &change(json!({ "rollback": bad }))).unwrap_err();
+            assert!(err.message().contains("unknown upgrade record"));
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,486 @@
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::warn;
+use ulid::Ulid;
+
+use crate::jobs::{self, summarize};
+use crate::lineage;
+use crate::worker::CANCELLED_CODE;
+use crate::{call_tool, RequestContext, RpcError, RpcResult};
+
+/// Tool that picks a pipeline up from its checkpoints.
+pub(crate) const RESUME_METHOD: &str = "pipeline/resume";
+
+/// Stages in the order they run.
+const STAGES: &[&str] = &[
+    "init", "crawl", "analyze", "scaffold", "generate", "diff", "audit", "apply",
+];
+
+/// Share of failed diff routes tolerated when the caller sets no limit.
+const DEFAULT_MAX_DIFF_FAILURE_RATE: f64 = 0.1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PipelineParams {
+    start_url: String,
+    /// Defaults to the session's project; recorded resolved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    project_root: Option<String>,
+    /// Extra params per stage; ids threaded from earlier stages win.
+    #[serde(default)]
+    crawl: Map<String, Value>,
+    #[serde(default)]
+    scaffold: Map<String, Value>,
+    #[serde(default)]
+    generate: Map<String, Value>,
+    #[serde(default)]
+    diff: Map<String, Value>,
+    #[serde(default)]
+    audit: Map<String, Value>,
+    #[serde(default)]
+    apply: Map<String, Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_diff_failure_rate: Option<f64>,
+    /// Without it `apply` only writes a plan.
+    #[serde(default)]
+    allow_apply: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ResumeParams {
+    pipeline_id: String,
+    /// Pipeline params to change before resuming, e.g. `allowApply`.
+    #[serde(flatten)]
+    overrides: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StageStatus {
+    Succeeded,
+    /// The stage ran but a gate stopped the pipeline after it.
+    Stopped,
+    Failed,
+}
+
+/// What one stage ran with and produced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Checkpoint {
+    stage: String,
+    status: StageStatus,
+    job_id: String,
+    inputs: Value,
+    /// Hash of `inputs`; a stage is reused only while it matches.
+    fingerprint: String,
+    /// Ids the stage produced.
+    outputs: Map<String, Value>,
+    summary: Value,
+    finished_at: String,
+}
+
+/// A pipeline's params and stage checkpoints, persisted as
+/// `pipelines/<pipelineId>.json` in the sandbox. The pipeline id is the
+/// job id of the run that started it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Checkpoints {
+    pipeline_id: String,
+    /// The run that last worked on the pipeline.
+    job_id: String,
+    params: PipelineParams,
+    /// In stage order, up to the last stage that ran.
+    stages: Vec<Checkpoint>,
+    updated_at: String,
+    /// The sandbox the checkpoints are stored in.
+    #[serde(skip)]
+    base: PathBuf,
+}
+
+impl Checkpoints {
+    fn path(base: &Path, pipeline_id: &str) -> PathBuf {
+        base.join("pipelines").join(format!("{}.json", pipeline_id))
+    }
+
+    fn load(base: &Path, pipeline_id: &str) -> Result<Self, RpcError> {
+        let not_found = || RpcError::invalid_params(format!("unknown pipelineId: {}", pipeline_id));
+        if !jobs::is_job_id(pipeline_id) {
+            return Err(not_found());
+        }
+        let bytes = fs::read(Self::path(base, pipeline_id)).map_err(|_| not_found())?;
+        let checkpoints: Self = serde_json::from_slice(&bytes).map_err(|e| {
+            RpcError::internal(format!(
+                "pipeline {} checkpoints unreadable: {}",
+                pipeline_id, e
+            ))
+        })?;
+        Ok(Self {
+            base: base.to_path_buf(),
+            ..checkpoints
+        })
+    }
+
+    /// Record `checkpoint` as stage `index`, dropping any later ones: they
+    /// were built on what this stage replaced.
+    fn record(&mut self, index: usize, checkpoint: Checkpoint) {
+        self.stages.truncate(index);
+        self.stages.push(checkpoint);
+        self.save();
+    }
+
+    /// Checkpoints are what make a resume possible, but failing to write
+    /// them must not fail the stage that just ran.
+    fn save(&mut self) {
+        self.updated_at = Utc::now().to_rfc3339();
+        let res: Result<()> = serde_json::to_value(&*self)
+            .map_err(Into::into)
+            .and_then(|v| jobs::write_atomic(&Self::path(&self.base, &self.pipeline_id), &v));
+        if let Err(e) = res {
+            warn!(
+                target = "site2ts",
+                "writing pipeline checkpoints failed: {:#}", e
+            );
+        }
+    }
+
+    /// The checkpoint of stage `index` if it succeeded with these inputs
+    /// and the ids it produced are still current.
+    fn reusable(&self, index: usize, fingerprint: &str) -> Option<&Checkpoint> {
+        self.stages.get(index).filter(|c| {
+            c.status == StageStatus::Succeeded
+                && c.fingerprint == fingerprint
+                && c.outputs
+                    .values()
+                    .filter_map(Value::as_str)
+                    .all(|id| lineage::is_current(&self.base, id))
+        })
+    }
+}
+
+/// FNV-1a over the serialized inputs. `serde_json` keeps object keys
+/// sorted, so equal inputs always hash the same.
+fn fingerprint(inputs: &Value) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in inputs.to_string().bytes() {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// What the pipeline did so far; returned whole, or as `partial` when a
+/// stage fails.
+#[derive(Debug, Default)]
+struct Report {
+    pipeline_id: String,
+    /// First stage a resume ran again.
+    resumed_at: Option<String>,
+    ids: Map<String, Value>,
+    stages: Vec<Value>,
+    stopped: Option<(String, String, Value)>,
+    dry_run: bool,
+    applied: bool,
+}
+
+impl Report {
+    fn id(&self, key: &str) -> Value {
+        self.ids.get(key).cloned().unwrap_or(Value::Null)
+    }
+
+    fn to_json(&self) -> Value {
+        let ran = self.stages.len();
+        let skipped: Vec<&str> = STAGES.iter().skip(ran).copied().collect();
+        let mut out = json!({
+            "ok": self.stopped.is_none(),
+            "pipelineId": self.pipeline_id,
+            "ids": self.ids,
+            "stages": self.stages,
+            "skipped": skipped,
+            "dryRun": self.dry_run,
+            "applied": self.applied,
+        });
+        if let Some(stage) = &self.resumed_at {
+            out["resumedAt"] = json!(stage);
+        }
+        if let Some((stage, reason, gate)) = &self.stopped {
+            out["stoppedAt"] = json!(stage);
+            out["reason"] = json!(reason);
+            out["gate"] = gate.clone();
+        }
+        out
+    }
+}
+
+/// Run every stage in order, threading ids from one to the next. A gate
+/// that fails stops the run with `ok: false`; a stage error fails the call
+/// with the report so far as `data.partial`. Each stage is checkpointed so
+/// the run can be picked up with [`resume`].
+pub(crate) async fn run(params: PipelineParams, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let checkpoints = Checkpoints {
+        pipeline_id: ctx.job_id.clone(),
+        job_id: ctx.job_id.clone(),
+        params,
+        stages: Vec::new(),
+        updated_at: String::new(),
+        base: ctx.sandbox(),
+    };
+    execute(checkpoints, false, ctx).await
+}
+
+/// Run pipeline `pipelineId` again, reusing every stage whose inputs are
+/// unchanged and that succeeded; the first failed, stopped or stale stage
+/// and everything after it run again.
+pub(crate) async fn resume(params: ResumeParams, ctx: &mut RequestContext) -> RpcResult<Value> {
+    let mut checkpoints = Checkpoints::load(&ctx.sandbox(), &params.pipeline_id)?;
+    if jobs::is_running(&checkpoints.job_id) {
+        return Err(RpcError::invalid_params(format!(
+            "pipeline {} is still running as job {}",
+            checkpoints.pipeline_id, checkpoints.job_id
+        )));
+    }
+    if !params.overrides.is_empty() {
+        let mut merged = serde_json::to_value(&checkpoints.params)
+            .map_err(|e| RpcError::internal(e.to_string()))?;
+        for (k, v) in params.overrides {
+            if k != "_meta" {
+                merged[k] = v;
+            }
+        }
+        checkpoints.params = crate::parse_params(&merged)?;
+    }
+    checkpoints.job_id = ctx.job_id.clone();
+    execute(checkpoints, true, ctx).await
+}
+
+async fn execute(
+    mut checkpoints: Checkpoints,
+    resuming: bool,
+    ctx: &mut RequestContext,
+) -> RpcResult<Value> {
+    checkpoints.params.project_root = Some(ctx.root.display().to_string());
+    let params = checkpoints.params.clone();
+    let max_failure_rate = params
+        .max_diff_failure_rate
+        .unwrap_or(DEFAULT_MAX_DIFF_FAILURE_RATE);
+    let mut report = Report {
+        pipeline_id: checkpoints.pipeline_id.clone(),
+        dry_run: !params.allow_apply,
+        ..Default::default()
+    };
+    checkpoints.save();
+    for (i, stage) in STAGES.iter().copied().enumerate() {
+        if let Some(reason) = ctx.cancel.reason() {
+            return Err(RpcError::new(
+                CANCELLED_CODE,
+                format!("request cancelled: {}", reason),
+                Some(
+                    json!({ "state": "cancelled", "reason": reason, "partial": report.to_json() }),
+                ),
+            ));
+        }
+        let stage_params = stage_params(stage, &params, &report);
+        if stage == "apply" {
+            report.dry_run = stage_params["dryRun"].as_bool().unwrap_or(true);
+        }
+        let fingerprint = fingerprint(&stage_params);
+        if report.resumed_at.is_none() {
+            if let Some(done) = checkpoints.reusable(i, &fingerprint) {
+                report.ids.extend(done.outputs.clone());
+                report.stages.push(json!({
+                    "stage": stage,
+                    "jobId": done.job_id,
+                    "reused": true,
+                    "summary": done.summary,
+                }));
+                if stage == "apply" {
+                    report.applied = !report.dry_run;
+                }
+                continue;
+            }
+            if resuming {
+                report.resumed_at = Some(stage.to_string());
+            }
+        }
+        ctx.progress.report(&json!({
+            "tool": "pipeline",
+            "phase": stage,
+            "current": i,
+            "total": STAGES.len(),
+        }));
+        let started = Instant::now();
+        let (job_id, res) = run_stage(stage, &stage_params, ctx).await;
+        let mut checkpoint = Checkpoint {
+            stage: stage.to_string(),
+            status: StageStatus::Failed,
+            job_id: job_id.clone(),
+            inputs: stage_params,
+            fingerprint,
+            outputs: Map::new(),
+            summary: Value::Null,
+            finished_at: Utc::now().to_rfc3339(),
+        };
+        let result = match res {
+            Ok(v) => v,
+            Err(e) => {
+                checkpoint.summary = e.to_json();
+                checkpoints.record(i, checkpoint);
+                return Err(stage_failed(stage, e, &report));
+            }
+        };
+        for (k, v) in result.as_object().into_iter().flatten() {
+            if k.ends_with("Id") && k != "jobId" && v.is_string() {
+                checkpoint.outputs.insert(k.clone(), v.clone());
+            }
+        }
+        report.ids.extend(checkpoint.outputs.clone());
+        checkpoint.summary = summarize(&result);
+        report.stages.push(json!({
+            "stage": stage,
+            "jobId": result.get("jobId").cloned().unwrap_or(json!(job_id)),
+            "durationMs": started.elapsed().as_millis() as u64,
+            "summary": checkpoint.summary,
+        }));
+        if stage == "apply" {
+            report.applied = !report.dry_run;
+        }
+        let stop = gate(stage, &result, max_failure_rate);
+        checkpoint.status = if stop.is_some() {
+            StageStatus::Stopped
+        } else {
+            StageStatus::Succeeded
+        };
+        checkpoints.record(i, checkpoint);
+        if let Some(stop) = stop {
+            report.stopped = Some(stop);
+            break;
+        }
+    }
+    Ok(report.to_json())
+}
+
+fn stage_params(stage: &str, params: &PipelineParams, report: &Report) -> Value {
+    let (extra, ids): (&Map<String, Value>, Value) = match stage {
+        "init" => (&Map::new(), json!({ "projectRoot": params.project_root })),
+        "crawl" => (&params.crawl, json!({ "startUrl": params.start_url })),
+        "analyze" => (&Map::new(), json!({ "siteMapId": report.id("siteMapId") })),
+        "scaffold" => (
+            &params.scaffold,
+            json!({ "analysisId": report.id("analysisId") }),
+        ),
+        "generate" => (
+            &params.generate,
+            json!({
+                "analysisId": report.id("analysisId"),
+                "scaffoldId": report.id("scaffoldId"),
+            }),
+        ),
+        "diff" => (
+            &params.diff,
+            json!({ "generationId": report.id("generationId") }),
+        ),
+        "audit" => (
+            &params.audit,
+            json!({ "generationId": report.id("generationId") }),
+        ),
+        _ => (
+            &params.apply,
+            json!({ "generationId": report.id("generationId") }),
+        ),
+    };
+    let mut out = Value::Object(extra.clone());
+    if stage == "apply" {
+        if out.get("target").is_none() {
+            out["target"] = json!("./");
+        }
+        if !params.allow_apply || out.get("dryRun").is_none() {
+            out["dryRun"] = json!(!params.allow_apply);
+        }
+    }
+    for (k, v) in ids.as_object().into_iter().flatten() {
+        out[k] = v.clone();
+    }
+    out
+}
+
+/// Run one stage as its own job, then restore the pipeline's job id and
+/// progress tracking on `ctx`.
+async fn run_stage(
+    stage: &str,
+    params: &Value,
+    ctx: &mut RequestContext,
+) -> (String, RpcResult<Value>) {
+    let pipeline_job = std::mem::replace(&mut ctx.job_id, Ulid::new().to_string());
+    let tracked = ctx.progress.track(None);
+    let res = Box::pin(call_tool(stage, params, ctx)).await;
+    ctx.progress.track(tracked);
+    let job_id = std::mem::replace(&mut ctx.job_id, pipeline_job);
+    (job_id, res)
+}
+
+/// Why the pipeline must stop after `stage`, if it must: `(stage, reason,
+/// gate details)`.
+fn gate(stage: &str, result: &Value, max_failure_rate: f64) -> Option<(String, String, Value)> {
+    match stage {
+        "diff" => {
+            let count = |k: &str| result["summary"][k].as_u64().unwrap_or(0);
+            let (passed, failed) = (count("passed"), count("failed"));
+            let total = passed + failed;
+            let rate = if total == 0 {
+                0.0
+            } else {
+                failed as f64 / total as f64
+            };
+            (rate > max_failure_rate).then(|| {
+                (
+                    stage.to_string(),
+                    format!(
+                        "{} of {} routes differ (failure rate {:.2} > {:.2})",
+                        failed, total, rate, max_failure_rate
+                    ),
+                    json!({
+                        "name": "diffFailureRate",
+                        "failed": failed,
+                        "total": total,
+                        "failureRate": rate,
+                        "maxDiffFailureRate": max_failure_rate,
+                    }),
+                )
+            })
+        }
+        "audit" => {
+            let errors = result["tsc"]["errors"].as_u64().unwrap_or(0);
+            (errors > 0).then(|| {
+                (
+                    stage.to_string(),
+                    format!("tsc reported {} error(s)", errors),
+                    json!({ "name": "tscErrors", "errors": errors }),
+                )
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Keep the stage's code so callers can tell an order error from a crash.
+fn stage_failed(stage: &str, err: RpcError, report: &Report) -> RpcError {
+    let mut data = match err.data.clone() {
+        Some(Value::Object(map)) => Value::Object(map),
+        _ => json!({}),
+    };
+    data["stage"] = json!(stage);
+    if let Some(partial) = data.get("partial").cloned() {
+        data["stagePartial"] = partial;
+    }
+    data["partial"] = report.to_json();
+    RpcError::new(
+        err.code(),
+        format!("pipeline stopped at {}: {}", stage, err.message()),
+        Some(data),
+    )
+}
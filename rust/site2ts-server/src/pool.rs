@@ -0,0 +1,640 @@
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+use tokio::time::MissedTickBehavior;
+use tracing::warn;
+
+use crate::progress::ProgressReporter;
+use crate::session::CancelToken;
+use crate::worker::{CallJob, Worker, WorkerLaunch};
+use crate::{RequestContext, RpcError, RpcResult};
+
+pub(crate) const DEFAULT_WORKERS: usize = 2;
+
+/// Tool calls that must not overlap with any other tool call on the same
+/// project, because they rewrite files the others read.
+const EXCLUSIVE_METHODS: &[&str] = &["apply", "gc"];
+
+/// Deadline for worker methods not listed in DEFAULT_TIMEOUTS, in seconds.
+const DEFAULT_TIMEOUT_SECS: u64 = 600;
+
+/// Per-method deadlines in seconds. Browser-driven methods get the longest.
+const DEFAULT_TIMEOUTS: &[(&str, u64)] = &[
+    ("crawl", 1800),
+    ("diff", 1800),
+    ("audit", 900),
+    ("generate", 900),
+    ("initRuntime", 900),
+    ("analyze", 600),
+    ("assets", 600),
+    ("scaffold", 300),
+    ("apply", 300),
+    ("pack", 300),
+    ("improve", 300),
+];
+
+const HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(30);
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// A worker that stayed up this long resets the crash backoff.
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+static POOL: OnceLock<WorkerPool> = OnceLock::new();
+
+/// Install the pool for this process. Returns false if one already exists.
+pub(crate) fn configure(config: PoolConfig) -> bool {
+    POOL.set(WorkerPool::new(config)).is_ok()
+}
+
+/// The process-wide pool; defaults apply when `configure` was never called.
+pub(crate) fn pool() -> &'static WorkerPool {
+    POOL.get_or_init(|| WorkerPool::new(PoolConfig::default()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PoolConfig {
+    pub(crate) size: usize,
+    /// Worker method name -> slot index it always runs on.
+    pub(crate) pins: BTreeMap<String, usize>,
+    /// Worker method name -> deadline in seconds (0 disables it),
+    /// overriding DEFAULT_TIMEOUTS.
+    pub(crate) timeouts: BTreeMap<String, u64>,
+    pub(crate) launch: WorkerLaunch,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_WORKERS,
+            pins: BTreeMap::new(),
+            timeouts: BTreeMap::new(),
+            launch: WorkerLaunch::default(),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Add a `method=index` pin.
+    pub(crate) fn pin(&mut self, spec: &str) -> Result<()> {
+        let (method, index) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid pin {} (expected method=index)", spec))?;
+        let index = index
+            .parse()
+            .map_err(|_| anyhow!("invalid worker index in pin {}", spec))?;
+        if method.is_empty() {
+            bail!("invalid pin {} (expected method=index)", spec);
+        }
+        self.pins.insert(method.to_string(), index);
+        Ok(())
+    }
+
+    /// Add a `method=seconds` deadline override.
+    pub(crate) fn timeout(&mut self, spec: &str) -> Result<()> {
+        let (method, secs) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid timeout {} (expected method=seconds)", spec))?;
+        let secs = secs
+            .parse()
+            .map_err(|_| anyhow!("invalid seconds in timeout {}", spec))?;
+        if method.is_empty() {
+            bail!("invalid timeout {} (expected method=seconds)", spec);
+        }
+        self.timeouts.insert(method.to_string(), secs);
+        Ok(())
+    }
+
+    /// How long `method` may run before it is cancelled.
+    pub(crate) fn timeout_for(&self, method: &str) -> Option<Duration> {
+        let secs = self.timeouts.get(method).copied().unwrap_or_else(|| {
+            DEFAULT_TIMEOUTS
+                .iter()
+                .find(|(m, _)| *m == method)
+                .map_or(DEFAULT_TIMEOUT_SECS, |(_, secs)| *secs)
+        });
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.size == 0 {
+            bail!("--workers must be at least 1");
+        }
+        for (method, index) in &self.pins {
+            if *index >= self.size {
+                bail!(
+                    "pin {}={} is out of range for {} workers",
+                    method,
+                    index,
+                    self.size
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One worker position in the pool. The process is spawned on first use
+/// and replaced, after a backoff, whenever it dies.
+struct Slot {
+    index: usize,
+    launch: Arc<WorkerLaunch>,
+    state: Mutex<SlotState>,
+    // Held while a replacement starts so only one is spawned at a time.
+    starting: tokio::sync::Mutex<()>,
+    active: AtomicUsize,
+    calls: AtomicU64,
+}
+
+#[derive(Default)]
+struct SlotState {
+    worker: Option<Arc<Worker>>,
+    spawned_at: Option<Instant>,
+    restarts: u32,
+    // Crashes since the last run that stayed up for STABLE_UPTIME.
+    failures: u32,
+    respawn_at: Option<Instant>,
+    last_exit: Option<String>,
+}
+
+impl SlotState {
+    fn install(&mut self, w: Worker) -> Arc<Worker> {
+        let w = Arc::new(w);
+        if self.last_exit.is_some() {
+            self.restarts += 1;
+        }
+        self.worker = Some(w.clone());
+        self.spawned_at = Some(Instant::now());
+        self.respawn_at = None;
+        w
+    }
+
+    /// Forget a dead worker and schedule its replacement.
+    fn retire(&mut self, index: usize, reason: String) {
+        let stable = self
+            .spawned_at
+            .is_some_and(|t| t.elapsed() >= STABLE_UPTIME);
+        self.failures = if stable { 1 } else { self.failures + 1 };
+        let delay = backoff(self.failures);
+        warn!(
+            target = "site2ts",
+            "worker {} is down ({}); restarting in {:?}", index, reason, delay
+        );
+        self.worker = None;
+        self.spawned_at = None;
+        self.respawn_at = Some(Instant::now() + delay);
+        self.last_exit = Some(reason);
+    }
+}
+
+impl Slot {
+    fn new(index: usize, launch: Arc<WorkerLaunch>) -> Self {
+        Self {
+            index,
+            launch,
+            state: Mutex::new(SlotState::default()),
+            starting: tokio::sync::Mutex::new(()),
+            active: AtomicUsize::new(0),
+            calls: AtomicU64::new(0),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SlotState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The running worker, spawning or replacing it as needed. Waits out
+    /// the restart backoff if the previous process died recently.
+    async fn worker(&self) -> Result<Arc<Worker>> {
+        let _starting = self.starting.lock().await;
+        loop {
+            let wait = {
+                let mut state = self.state();
+                if let Some(w) = state.worker.clone() {
+                    match w.exited() {
+                        None => return Ok(w),
+                        Some(reason) => state.retire(self.index, reason),
+                    }
+                }
+                match state.respawn_at {
+                    Some(at) if at > Instant::now() => at,
+                    _ => break,
+                }
+            };
+            tokio::time::sleep_until(wait.into()).await;
+        }
+        let worker = Worker::start(&self.launch).await?;
+        Ok(self.state().install(worker))
+    }
+
+    /// Retire `worker` unless it has already been replaced.
+    fn retire_if_current(&self, worker: &Arc<Worker>, reason: String) {
+        let mut state = self.state();
+        if state
+            .worker
+            .as_ref()
+            .is_some_and(|w| Arc::ptr_eq(w, worker))
+        {
+            state.retire(self.index, reason);
+        }
+    }
+
+    fn spawned(&self) -> bool {
+        self.state().worker.is_some()
+    }
+}
+
+/// A fixed set of Node workers. Unpinned calls go to the least busy worker
+/// that is not dedicated to a pinned method.
+pub(crate) struct WorkerPool {
+    config: PoolConfig,
+    slots: Vec<Slot>,
+    // Makes choosing a slot and counting the call against it atomic.
+    pick: Mutex<()>,
+    projects: Mutex<HashMap<PathBuf, Arc<RwLock<()>>>>,
+}
+
+/// A call assigned to a slot; the slot counts it as active until dropped.
+pub(crate) struct Checkout<'a> {
+    slot: &'a Slot,
+    worker: Arc<Worker>,
+    method: String,
+    timeout: Option<Duration>,
+}
+
+impl Checkout<'_> {
+    pub(crate) async fn call(&self, params: Value, ctx: &mut RequestContext) -> RpcResult<Value> {
+        self.worker
+            .call(
+                &self.method,
+                params,
+                &mut ctx.progress,
+                &ctx.cancel,
+                self.timeout,
+                Some(&CallJob {
+                    id: ctx.job_id.clone(),
+                    project_root: ctx.root.clone(),
+                }),
+            )
+            .await
+    }
+}
+
+impl Drop for Checkout<'_> {
+    fn drop(&mut self) {
+        self.slot.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Held for the duration of a tool call on a project.
+pub(crate) struct ProjectGuard {
+    _shared: Option<OwnedRwLockReadGuard<()>>,
+    _exclusive: Option<OwnedRwLockWriteGuard<()>>,
+}
+
+impl WorkerPool {
+    fn new(config: PoolConfig) -> Self {
+        let launch = Arc::new(config.launch.clone());
+        let slots = (0..config.size.max(1))
+            .map(|i| Slot::new(i, launch.clone()))
+            .collect();
+        Self {
+            config,
+            slots,
+            pick: Mutex::new(()),
+            projects: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserve a worker for `method`, spawning it if needed.
+    pub(crate) async fn checkout(&self, method: &str) -> Result<Checkout<'_>> {
+        let slot = {
+            let _pick = self.pick.lock().unwrap_or_else(|e| e.into_inner());
+            let loads: Vec<(usize, bool)> = self
+                .slots
+                .iter()
+                .map(|s| (s.active.load(Ordering::Relaxed), s.spawned()))
+                .collect();
+            let slot = &self.slots[pick(&self.config, method, &loads)];
+            slot.active.fetch_add(1, Ordering::Relaxed);
+            slot
+        };
+        let worker = match slot.worker().await {
+            Ok(w) => w,
+            Err(e) => {
+                slot.active.fetch_sub(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+        slot.calls.fetch_add(1, Ordering::Relaxed);
+        Ok(Checkout {
+            slot,
+            worker,
+            method: method.to_string(),
+            timeout: self.config.timeout_for(method),
+        })
+    }
+
+    /// The `hello` result of a running worker, starting one if none is.
+    pub(crate) async fn handshake(&self) -> Result<Value> {
+        let slot = match self.slots.iter().find(|s| s.spawned()) {
+            Some(slot) => slot,
+            None => {
+                let loads = vec![(0, false); self.slots.len()];
+                &self.slots[pick(&self.config, "hello", &loads)]
+            }
+        };
+        Ok(slot.worker().await?.hello().clone())
+    }
+
+    pub(crate) async fn call(
+        &self,
+        method: &str,
+        params: Value,
+        ctx: &mut RequestContext,
+    ) -> RpcResult<Value> {
+        let checkout = self.checkout(method).await.map_err(into_rpc_error)?;
+        checkout.call(params, ctx).await
+    }
+
+    /// Wait until a tool call on `root` may run. Exclusive methods wait for
+    /// every other call on the project to finish and block new ones.
+    pub(crate) async fn enter_project(&self, root: &Path, method: &str) -> ProjectGuard {
+        let lock = self.project_lock(root);
+        if EXCLUSIVE_METHODS.contains(&method) {
+            ProjectGuard {
+                _shared: None,
+                _exclusive: Some(lock.write_owned().await),
+            }
+        } else {
+            ProjectGuard {
+                _shared: Some(lock.read_owned().await),
+                _exclusive: None,
+            }
+        }
+    }
+
+    /// Take `root` exclusively if no other call is running on it, without
+    /// waiting.
+    pub(crate) fn try_enter_exclusive(&self, root: &Path) -> Option<ProjectGuard> {
+        let lock = self.project_lock(root).try_write_owned().ok()?;
+        Some(ProjectGuard {
+            _shared: None,
+            _exclusive: Some(lock),
+        })
+    }
+
+    fn project_lock(&self, root: &Path) -> Arc<RwLock<()>> {
+        self.projects
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(root.to_path_buf())
+            .or_default()
+            .clone()
+    }
+
+    /// Watch the workers until the process exits: replace any that died or
+    /// stopped answering `ping`, and bring crashed ones back after backoff.
+    pub(crate) async fn supervise(&self) {
+        let start = tokio::time::Instant::now() + HEALTH_INTERVAL;
+        let mut tick = tokio::time::interval_at(start, HEALTH_INTERVAL);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            for slot in &self.slots {
+                check_health(slot).await;
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Value {
+        let workers: Vec<Value> = self
+            .slots
+            .iter()
+            .enumerate()
+            .map(|(index, slot)| {
+                let pinned: Vec<&str> = self
+                    .config
+                    .pins
+                    .iter()
+                    .filter(|(_, i)| **i == index)
+                    .map(|(m, _)| m.as_str())
+                    .collect();
+                let state = slot.state();
+                json!({
+                    "index": index,
+                    "spawned": state.worker.is_some(),
+                    "restarts": state.restarts,
+                    "lastExit": state.last_exit,
+                    "inFlight": slot.active.load(Ordering::Relaxed),
+                    "calls": slot.calls.load(Ordering::Relaxed),
+                    "pinned": pinned,
+                })
+            })
+            .collect();
+        let in_flight: usize = self
+            .slots
+            .iter()
+            .map(|s| s.active.load(Ordering::Relaxed))
+            .sum();
+        json!({
+            "node": self.config.launch.node,
+            "script": self.config.launch.script,
+            "size": self.slots.len(),
+            "inFlight": in_flight,
+            "exclusive": EXCLUSIVE_METHODS,
+            "workers": workers,
+        })
+    }
+}
+
+async fn check_health(slot: &Slot) {
+    let (worker, respawn_due) = {
+        let state = slot.state();
+        let due =
+            state.last_exit.is_some() && state.respawn_at.is_none_or(|at| at <= Instant::now());
+        (state.worker.clone(), due)
+    };
+    let Some(worker) = worker else {
+        if respawn_due {
+            if let Err(e) = slot.worker().await {
+                slot.state()
+                    .retire(slot.index, format!("respawn failed: {:#}", e));
+            }
+        }
+        return;
+    };
+    if let Some(reason) = worker.exited() {
+        slot.retire_if_current(&worker, reason);
+        return;
+    }
+    let ping = worker
+        .call(
+            "ping",
+            json!({}),
+            &mut ProgressReporter::default(),
+            &CancelToken::default(),
+            Some(PING_TIMEOUT),
+            None,
+        )
+        .await;
+    if ping.is_err() {
+        worker.kill();
+        let reason = worker
+            .exited()
+            .filter(|r| r != "stdout closed")
+            .unwrap_or_else(|| "health ping failed".into());
+        slot.retire_if_current(&worker, reason);
+    }
+}
+
+/// Surface a checkout failure to the client, keeping ENV_MISSING and other
+/// structured errors intact.
+pub(crate) fn into_rpc_error(err: anyhow::Error) -> RpcError {
+    err.downcast::<RpcError>()
+        .unwrap_or_else(|e| RpcError::internal(format!("{:#}", e)))
+}
+
+/// Restart delay after `failures` crashes in a row.
+fn backoff(failures: u32) -> Duration {
+    let exp = failures.saturating_sub(1).min(16);
+    (BACKOFF_BASE * 2u32.pow(exp)).min(BACKOFF_MAX)
+}
+
+/// Choose a slot for `method` given each slot's (active calls, spawned).
+/// Pinned methods always get their slot. Everything else goes to the least
+/// busy undedicated slot, preferring one that is already running.
+fn pick(config: &PoolConfig, method: &str, loads: &[(usize, bool)]) -> usize {
+    if let Some(&index) = config.pins.get(method) {
+        if index < loads.len() {
+            return index;
+        }
+    }
+    let dedicated: HashSet<usize> = config.pins.values().copied().collect();
+    let mut candidates: Vec<usize> = (0..loads.len())
+        .filter(|i| !dedicated.contains(i))
+        .collect();
+    if candidates.is_empty() {
+        candidates = (0..loads.len()).collect();
+    }
+    candidates
+        .into_iter()
+        .min_by_key(|&i| (loads[i].0, !loads[i].1, i))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(size: usize, pins: &[&str]) -> PoolConfig {
+        let mut cfg = PoolConfig {
+            size,
+            ..Default::default()
+        };
+        for p in pins {
+            cfg.pin(p).unwrap();
+        }
+        cfg
+    }
+
+    #[test]
+    fn picks_least_busy_and_prefers_running_workers() {
+        let cfg = config(3, &[]);
+        assert_eq!(
+            pick(&cfg, "crawl", &[(0, false), (0, false), (0, false)]),
+            0
+        );
+        assert_eq!(pick(&cfg, "crawl", &[(1, true), (0, false), (0, true)]), 2);
+        assert_eq!(pick(&cfg, "crawl", &[(1, true), (0, false), (2, true)]), 1);
+        assert_eq!(pick(&cfg, "crawl", &[(3, true), (1, true), (2, true)]), 1);
+    }
+
+    #[test]
+    fn pinned_methods_get_a_dedicated_worker() {
+        let cfg = config(3, &["apply=0"]);
+        assert_eq!(pick(&cfg, "apply", &[(5, true), (0, true), (0, true)]), 0);
+        assert_eq!(pick(&cfg, "crawl", &[(0, true), (4, true), (2, true)]), 2);
+
+        let all = config(1, &["apply=0"]);
+        assert_eq!(pick(&all, "crawl", &[(1, true)]), 0);
+    }
+
+    #[test]
+    fn config_rejects_bad_pins() {
+        let mut cfg = PoolConfig::default();
+        assert!(cfg.pin("apply").is_err());
+        assert!(cfg.pin("apply=x").is_err());
+        assert!(cfg.pin("=1").is_err());
+        cfg.pin("apply=5").unwrap();
+        assert!(cfg.validate().is_err());
+        cfg.size = 6;
+        assert!(cfg.validate().is_ok());
+        cfg.size = 0;
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn timeouts_default_per_method_and_can_be_overridden() {
+        let mut cfg = PoolConfig::default();
+        assert_eq!(cfg.timeout_for("crawl"), Some(Duration::from_secs(1800)));
+        assert_eq!(
+            cfg.timeout_for("somethingNew"),
+            Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS))
+        );
+        cfg.timeout("crawl=60").unwrap();
+        cfg.timeout("diff=0").unwrap();
+        assert_eq!(cfg.timeout_for("crawl"), Some(Duration::from_secs(60)));
+        assert_eq!(cfg.timeout_for("diff"), None);
+        assert!(cfg.timeout("crawl").is_err());
+        assert!(cfg.timeout("crawl=soon").is_err());
+    }
+
+    #[test]
+    fn crash_backoff_grows_and_resets_after_a_stable_run() {
+        assert_eq!(backoff(1), BACKOFF_BASE);
+        assert_eq!(backoff(3), BACKOFF_BASE * 4);
+        assert_eq!(backoff(40), BACKOFF_MAX);
+
+        let mut state = SlotState::default();
+        state.retire(0, "exit status: 1".into());
+        state.retire(0, "exit status: 1".into());
+        assert_eq!(state.failures, 2);
+        assert!(state.respawn_at.unwrap() > Instant::now());
+        assert_eq!(state.last_exit.as_deref(), Some("exit status: 1"));
+
+        state.spawned_at = Some(Instant::now() - STABLE_UPTIME);
+        state.retire(0, "signal: 9".into());
+        assert_eq!(state.failures, 1);
+    }
+
+    #[tokio::test]
+    async fn exclusive_methods_serialize_within_a_project() {
+        let pool = WorkerPool::new(PoolConfig::default());
+        let root = Path::new("/tmp/project-a");
+        let crawl = pool.enter_project(root, "crawl").await;
+        let _other = pool
+            .enter_project(Path::new("/tmp/project-b"), "apply")
+            .await;
+        let diff = pool.enter_project(root, "diff").await;
+
+        let apply =
+            tokio::time::timeout(Duration::from_millis(50), pool.enter_project(root, "apply"))
+                .await;
+        assert!(apply.is_err(), "apply must wait for running calls");
+
+        drop(crawl);
+        drop(diff);
+        let apply = pool.enter_project(root, "apply").await;
+        let audit =
+            tokio::time::timeout(Duration::from_millis(50), pool.enter_project(root, "audit"))
+                .await;
+        assert!(audit.is_err(), "calls must wait for apply");
+        drop(apply);
+    }
+}
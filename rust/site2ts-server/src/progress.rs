@@ -0,0 +1,232 @@
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::jobs::LiveJob;
+use crate::session::Notifier;
+
+/// Turns worker `progress` lines into MCP `notifications/progress` for the
+/// request that asked for them via `_meta.progressToken`.
+#[derive(Debug, Default)]
+pub(crate) struct ProgressReporter {
+    token: Option<Value>,
+    /// The last `progress` value sent; each one must be greater.
+    last: Option<f64>,
+    /// Where the worker's count for the current phase starts on the overall
+    /// counter, and that phase with its own total.
+    base: f64,
+    phase: Option<(String, f64)>,
+    notifier: Notifier,
+    job: Option<Arc<LiveJob>>,
+}
+
+impl ProgressReporter {
+    pub(crate) fn new(token: Option<Value>, notifier: Notifier) -> Self {
+        Self {
+            token,
+            last: None,
+            base: 0.0,
+            phase: None,
+            notifier,
+            job: None,
+        }
+    }
+
+    /// Read `_meta.progressToken` from request params. Only strings and
+    /// integers are valid tokens per the MCP spec.
+    pub(crate) fn from_meta(params: &Value, notifier: Notifier) -> Self {
+        let token = params
+            .get("_meta")
+            .and_then(|m| m.get("progressToken"))
+            .filter(|t| t.is_string() || t.is_i64() || t.is_u64())
+            .cloned();
+        Self::new(token, notifier)
+    }
+
+    /// Also keep progress on `job`, for pollers that did not pass a token.
+    /// Returns the job tracked before.
+    pub(crate) fn track(&mut self, job: Option<Arc<LiveJob>>) -> Option<Arc<LiveJob>> {
+        std::mem::replace(&mut self.job, job)
+    }
+
+    /// Forward one worker `emitProgress` payload. Progress for requests
+    /// without a token is only logged.
+    pub(crate) fn report(&mut self, payload: &Value) {
+        if let Some(job) = &self.job {
+            job.progress(payload);
+        }
+        match self.notification(payload) {
+            Some(params) => {
+                self.notifier.notify("notifications/progress", params);
+            }
+            None => debug!(target = "site2ts", progress = %payload, "worker progress"),
+        }
+    }
+
+    /// Build the `notifications/progress` params for a worker payload.
+    /// `progress` is one counter over the whole call. Within a phase it
+    /// follows the worker's `current`, and `total` is that phase's total on
+    /// the same counter, so the bar a client draws is the phase's. A new
+    /// phase, or one that counts from 1 again, carries on from the last
+    /// value sent. A payload without numbers counts as one step more, and
+    /// pushes the phase's total out by one.
+    fn notification(&mut self, payload: &Value) -> Option<Value> {
+        let token = self.token.clone()?;
+        let last = self.last.unwrap_or(-1.0);
+        let current = payload.get("current").and_then(Value::as_f64);
+        let total = payload.get("total").and_then(Value::as_f64);
+        let progress = match (current, total) {
+            (Some(current), Some(total)) => {
+                let key = format!("{} {}", field(payload, "tool"), field(payload, "phase"));
+                let same_phase = self.phase.as_ref().is_some_and(|(k, _)| *k == key);
+                if !same_phase || self.base + current <= last {
+                    self.base = (last + 1.0 - current).max(0.0);
+                }
+                self.phase = Some((key, total));
+                self.base + current
+            }
+            _ => {
+                self.base += 1.0;
+                last + 1.0
+            }
+        };
+        self.last = Some(progress);
+        let mut params = json!({
+            "progressToken": token,
+            "progress": number(progress),
+            "message": progress_message(payload),
+        });
+        if let Some((_, phase_total)) = &self.phase {
+            let total = self.base + phase_total;
+            if progress <= total {
+                params["total"] = number(total);
+            }
+        }
+        Some(params)
+    }
+}
+
+fn field<'a>(payload: &'a Value, key: &str) -> &'a str {
+    payload.get(key).and_then(|v| v.as_str()).unwrap_or("")
+}
+
+/// `x` as a JSON integer when it is one, so counts do not read as `3.0`.
+fn number(x: f64) -> Value {
+    if x.fract() == 0.0 && (0.0..9_007_199_254_740_992.0).contains(&x) {
+        json!(x as u64)
+    } else {
+        json!(x)
+    }
+}
+
+fn progress_message(payload: &Value) -> String {
+    let field = |k: &str| field(payload, k);
+    let mut msg = format!("{} {}", field("tool"), field("phase"))
+        .trim()
+        .to_string();
+    match (
+        payload.get("current").and_then(|v| v.as_f64()),
+        payload.get("total").and_then(|v| v.as_f64()),
+    ) {
+        (Some(current), Some(total)) => msg.push_str(&format!(" ({}/{})", current, total)),
+        (Some(current), None) => msg.push_str(&format!(" ({})", current)),
+        (None, Some(total)) => msg.push_str(&format!(" (0/{})", total)),
+        (None, None) => {}
+    }
+    let detail = field("detail");
+    if !detail.is_empty() {
+        msg.push_str(": ");
+        msg.push_str(detail);
+    }
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_read_from_meta() {
+        let r = ProgressReporter::from_meta(
+            &json!({ "_meta": { "progressToken": "abc" } }),
+            Notifier::default(),
+        );
+        assert_eq!(r.token, Some(json!("abc")));
+        let r = ProgressReporter::from_meta(
+            &json!({ "_meta": { "progressToken": 7 } }),
+            Notifier::default(),
+        );
+        assert_eq!(r.token, Some(json!(7)));
+        let r = ProgressReporter::from_meta(
+            &json!({ "_meta": { "progressToken": {} } }),
+            Notifier::default(),
+        );
+        assert!(r.token.is_none());
+        assert!(ProgressReporter::from_meta(&json!({}), Notifier::default())
+            .token
+            .is_none());
+    }
+
+    #[test]
+    fn notifications_increase_and_describe_payload() {
+        let mut r = ProgressReporter::new(Some(json!("t")), Notifier::default());
+        let first = r
+            .notification(&json!({ "tool": "crawl", "phase": "start", "detail": "https://a.test" }))
+            .unwrap();
+        assert_eq!(first["progressToken"], "t");
+        assert_eq!(first["progress"], 0);
+        assert!(first.get("total").is_none());
+        assert_eq!(first["message"], "crawl start: https://a.test");
+
+        let second = r
+            .notification(&json!({ "tool": "crawl", "phase": "fetch", "current": 3, "total": 50 }))
+            .unwrap();
+        assert_eq!(second["progress"], 3);
+        assert_eq!(second["total"], 50);
+        assert_eq!(second["message"], "crawl fetch (3/50)");
+    }
+
+    #[test]
+    fn progress_is_one_counter_across_phases() {
+        let mut r = ProgressReporter::new(Some(json!("t")), Notifier::default());
+        let mut send = |phase: &str, counts: Option<(u64, u64)>| {
+            let mut payload = json!({ "tool": "diff", "phase": phase });
+            if let Some((current, total)) = counts {
+                payload["current"] = json!(current);
+                payload["total"] = json!(total);
+            }
+            let n = r.notification(&payload).unwrap();
+            (n["progress"].as_f64().unwrap(), n["total"].as_f64())
+        };
+        let sent = [
+            send("start", None),
+            send("route", Some((1, 3))),
+            send("build", None),
+            send("route", Some((2, 3))),
+            send("compare", Some((1, 3))),
+            send("compare", Some((2, 3))),
+            send("route", Some((3, 3))),
+            send("complete", None),
+        ];
+        for pair in sent.windows(2) {
+            assert!(pair[1].0 > pair[0].0, "progress must increase: {:?}", sent);
+        }
+        for (progress, total) in sent {
+            assert!(total.is_none_or(|t| progress <= t), "{:?}", sent);
+        }
+        assert!(sent[1..].iter().all(|(_, t)| t.is_some()), "{:?}", sent);
+        // Within a phase the bar moves with the worker's count.
+        let (a, b) = (sent[4], sent[5]);
+        assert_eq!((b.0 - a.0, b.1), (1.0, a.1));
+        let end = sent[7];
+        assert_eq!(Some(end.0), end.1, "the last phase ends full");
+    }
+
+    #[test]
+    fn no_token_means_no_notification() {
+        let mut r = ProgressReporter::default();
+        assert!(r
+            .notification(&json!({ "tool": "diff", "phase": "start" }))
+            .is_none());
+    }
+}
@@ -0,0 +1,458 @@
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::session::Session;
+use crate::RpcError;
+
+const SCHEME: &str = "site2ts://";
+
+/// Files written per route under `reports/diff/<diffId>/<route>/`.
+const DIFF_FILES: &[&str] = &[
+    "baseline.png",
+    "actual.png",
+    "diff.png",
+    "metrics.json",
+    "summary.json",
+];
+
+/// Files each generation exposes under `generations/<generationId>/`.
+const GENERATION_FILES: &[&str] = &["meta", "analysis", "fallbacks"];
+
+/// Page size for `resources/list`; diff runs add several files per route.
+const PAGE_SIZE: usize = 500;
+
+const TEMPLATES: &[(&str, &str, &str)] = &[
+    (
+        "site2ts://diff/{diffId}/{route}/{file}",
+        "Diff artifact",
+        "Per-route diff output: baseline.png, actual.png, diff.png, metrics.json or summary.json.",
+    ),
+    (
+        "site2ts://diff/{diffId}/report",
+        "Diff report",
+        "HTML dashboard written when diff runs with renderReport.",
+    ),
+    (
+        "site2ts://audit/{auditId}/{tool}",
+        "Audit report",
+        "tsc or eslint report for an audit run.",
+    ),
+    (
+        "site2ts://apply/{jobId}/plan",
+        "Apply plan",
+        "Plan written by a dry-run apply.",
+    ),
+    (
+        "site2ts://generations/{generationId}/{file}",
+        "Generation artifact",
+        "Per-generation output: meta (generation.json), analysis or fallbacks (Tailwind fallback report).",
+    ),
+    ("site2ts://logs/{jobId}", "Job log", "NDJSON log for a job."),
+];
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ResourceParams {
+    uri: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ListParams {
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+pub(crate) fn list(base: &Path, params: ListParams) -> Result<Value, RpcError> {
+    let mut entries: Vec<(String, PathBuf)> = Vec::new();
+    let reports = base.join("reports");
+
+    for diff_dir in subdirs(&reports.join("diff")) {
+        let diff_id = file_name(&diff_dir);
+        if diff_dir.join("index.html").is_file() {
+            entries.push((
+                format!("{}diff/{}/report", SCHEME, diff_id),
+                diff_dir.join("index.html"),
+            ));
+        }
+        for route_dir in subdirs(&diff_dir) {
+            let route = file_name(&route_dir);
+            for file in DIFF_FILES {
+                let path = route_dir.join(file);
+                if path.is_file() {
+                    entries.push((
+                        format!("{}diff/{}/{}/{}", SCHEME, diff_id, route, file),
+                        path,
+                    ));
+                }
+            }
+        }
+    }
+    let analysis = base.join("staging").join("meta").join("analysis.json");
+    if analysis.is_file() {
+        entries.push((format!("{}analysis", SCHEME), analysis));
+    }
+    for tool in ["tsc", "eslint"] {
+        for (stem, path) in files_with_suffix(&reports.join(tool), ".json") {
+            entries.push((format!("{}audit/{}/{}", SCHEME, stem, tool), path));
+        }
+    }
+    for (stem, path) in files_with_suffix(&reports.join("apply"), ".plan.json") {
+        entries.push((format!("{}apply/{}/plan", SCHEME, stem), path));
+    }
+    for gen_dir in subdirs(&base.join("generations")) {
+        let generation_id = file_name(&gen_dir);
+        for file in GENERATION_FILES {
+            let uri = format!("{}generations/{}/{}", SCHEME, generation_id, file);
+            if let Some(path) = resolve(base, &uri).filter(|p| p.is_file()) {
+                entries.push((uri, path));
+            }
+        }
+    }
+    let fallbacks = reports.join("tailwind").join("fallbacks.json");
+    if fallbacks.is_file() {
+        entries.push((format!("{}tailwind/fallbacks", SCHEME), fallbacks));
+    }
+    for (stem, path) in files_with_suffix(&base.join("logs"), ".ndjson") {
+        entries.push((format!("{}logs/{}", SCHEME, stem), path));
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let start = match params.cursor.as_deref() {
+        Some(c) => c
+            .parse::<usize>()
+            .map_err(|_| RpcError::invalid_params(format!("invalid cursor: {}", c)))?,
+        None => 0,
+    };
+    let resources: Vec<Value> = entries
+        .iter()
+        .skip(start)
+        .take(PAGE_SIZE)
+        .map(|(uri, path)| {
+            let mut r = json!({
+                "uri": uri,
+                "name": uri.trim_start_matches(SCHEME),
+                "mimeType": mime_type(path),
+            });
+            if let Ok(meta) = fs::metadata(path) {
+                r["size"] = json!(meta.len());
+            }
+            r
+        })
+        .collect();
+    let mut out = json!({ "resources": resources });
+    if start + PAGE_SIZE < entries.len() {
+        out["nextCursor"] = json!((start + PAGE_SIZE).to_string());
+    }
+    Ok(out)
+}
+
+pub(crate) fn templates() -> Value {
+    let templates: Vec<Value> = TEMPLATES
+        .iter()
+        .map(|(uri, name, description)| {
+            json!({ "uriTemplate": uri, "name": name, "description": description })
+        })
+        .collect();
+    json!({ "resourceTemplates": templates })
+}
+
+pub(crate) fn read(base: &Path, params: ResourceParams) -> Result<Value, RpcError> {
+    let path = resolve(base, &params.uri).ok_or_else(|| not_found(&params.uri))?;
+    let bytes = fs::read(&path).map_err(|_| not_found(&params.uri))?;
+    let mime = mime_type(&path);
+    let mut content = json!({ "uri": params.uri, "mimeType": mime });
+    if mime.starts_with("image/") {
+        content["blob"] = json!(base64::engine::general_purpose::STANDARD.encode(&bytes));
+    } else {
+        content["text"] = json!(String::from_utf8_lossy(&bytes));
+    }
+    Ok(json!({ "contents": [content] }))
+}
+
+pub(crate) fn subscribe(
+    base: &Path,
+    session: &Session,
+    params: ResourceParams,
+) -> Result<Value, RpcError> {
+    let path = resolve(base, &params.uri)
+        .ok_or_else(|| RpcError::invalid_params(format!("unknown resource uri: {}", params.uri)))?;
+    session.subscriptions().insert(params.uri, &path);
+    Ok(json!({}))
+}
+
+pub(crate) fn unsubscribe(session: &Session, params: ResourceParams) -> Result<Value, RpcError> {
+    session.subscriptions().remove(&params.uri);
+    Ok(json!({}))
+}
+
+/// Send `notifications/resources/updated` for every subscribed resource a
+/// job has rewritten since we last looked.
+pub(crate) fn notify_updated(session: &Session) {
+    let Ok(base) = session.sandbox() else {
+        return;
+    };
+    let changed = session.subscriptions().changed(&base);
+    let notifier = session.notifier();
+    for uri in changed {
+        notifier.notify("notifications/resources/updated", json!({ "uri": uri }));
+    }
+}
+
+/// Subscribed URIs with the modification time last seen for each.
+#[derive(Debug, Default)]
+pub(crate) struct Subscriptions {
+    seen: HashMap<String, Option<SystemTime>>,
+}
+
+impl Subscriptions {
+    fn insert(&mut self, uri: String, path: &Path) {
+        self.seen.insert(uri, modified(path));
+    }
+
+    fn remove(&mut self, uri: &str) {
+        self.seen.remove(uri);
+    }
+
+    fn changed(&mut self, base: &Path) -> Vec<String> {
+        let mut out = Vec::new();
+        for (uri, last) in self.seen.iter_mut() {
+            let now = resolve(base, uri).and_then(|p| modified(&p));
+            if now.is_some() && now != *last {
+                *last = now;
+                out.push(uri.clone());
+            }
+        }
+        out.sort();
+        out
+    }
+}
+
+/// Map a `site2ts://` URI to its file under the sandbox. Every dynamic
+/// segment must be a single plain path component.
+fn resolve(base: &Path, uri: &str) -> Option<PathBuf> {
+    let rest = uri.strip_prefix(SCHEME)?;
+    let parts: Vec<&str> = rest.split('/').collect();
+    if !parts.iter().all(|p| is_safe_segment(p)) {
+        return None;
+    }
+    let reports = base.join("reports");
+    let path = match parts.as_slice() {
+        ["diff", diff_id, "report"] => reports.join("diff").join(diff_id).join("index.html"),
+        ["diff", diff_id, route, file] if DIFF_FILES.contains(file) => {
+            reports.join("diff").join(diff_id).join(route).join(file)
+        }
+        ["analysis"] => base.join("staging").join("meta").join("analysis.json"),
+        ["audit", audit_id, tool @ ("tsc" | "eslint")] => {
+            reports.join(tool).join(format!("{}.json", audit_id))
+        }
+        ["apply", job_id, "plan"] => reports.join("apply").join(format!("{}.plan.json", job_id)),
+        ["tailwind", "fallbacks"] => reports.join("tailwind").join("fallbacks.json"),
+        ["generations", generation_id, file] => {
+            let dir = base.join("generations").join(generation_id);
+            match *file {
+                "meta" => dir.join("generation.json"),
+                "analysis" => dir.join("staging").join("meta").join("analysis.json"),
+                "fallbacks" => dir.join("reports").join("tailwind").join("fallbacks.json"),
+                _ => return None,
+            }
+        }
+        ["logs", job_id] => base.join("logs").join(format!("{}.ndjson", job_id)),
+        _ => return None,
+    };
+    Some(path)
+}
+
+fn is_safe_segment(s: &str) -> bool {
+    !s.is_empty()
+        && s != "."
+        && s != ".."
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    if name.ends_with(".png") {
+        "image/png"
+    } else if name.ends_with(".ndjson") {
+        "application/x-ndjson"
+    } else if name.ends_with(".json") {
+        "application/json"
+    } else if name.ends_with(".html") {
+        "text/html"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+fn not_found(uri: &str) -> RpcError {
+    RpcError::new(-32002, "resource not found", Some(json!({ "uri": uri })))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    let mut out: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .collect();
+    out.sort();
+    out
+}
+
+fn files_with_suffix(dir: &Path, suffix: &str) -> Vec<(String, PathBuf)> {
+    let mut out: Vec<(String, PathBuf)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            let stem = name.strip_suffix(suffix)?.to_string();
+            (is_safe_segment(&stem) && e.path().is_file()).then(|| (stem, e.path()))
+        })
+        .collect();
+    out.sort();
+    out
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("site2ts-res-{}-{}", name, ulid::Ulid::new()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, bytes: &[u8]) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn resolve_maps_uris_and_rejects_traversal() {
+        let base = Path::new("/p/.site2ts");
+        assert_eq!(
+            resolve(base, "site2ts://diff/D1/root/diff.png").unwrap(),
+            base.join("reports/diff/D1/root/diff.png")
+        );
+        assert_eq!(
+            resolve(base, "site2ts://logs/J1").unwrap(),
+            base.join("logs/J1.ndjson")
+        );
+        assert_eq!(
+            resolve(base, "site2ts://apply/J2/plan").unwrap(),
+            base.join("reports/apply/J2.plan.json")
+        );
+        assert_eq!(
+            resolve(base, "site2ts://generations/G1/fallbacks").unwrap(),
+            base.join("generations/G1/reports/tailwind/fallbacks.json")
+        );
+        assert!(resolve(base, "site2ts://logs/..").is_none());
+        assert!(resolve(base, "site2ts://diff/D1/../diff.png").is_none());
+        assert!(resolve(base, "site2ts://diff/D1/root/secret.txt").is_none());
+        assert!(resolve(base, "file:///etc/passwd").is_none());
+    }
+
+    #[test]
+    fn list_and_read_artifacts() {
+        let base = sandbox("list");
+        write(
+            &base.join("reports/diff/D1/root/diff.png"),
+            &[0x89, b'P', b'N', b'G'],
+        );
+        write(&base.join("reports/tsc/A1.json"), b"{}");
+        write(&base.join("logs/J1.ndjson"), b"{\"msg\":\"hi\"}\n");
+
+        let listed = list(&base, ListParams::default()).unwrap();
+        let uris: Vec<&str> = listed["resources"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["uri"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            uris,
+            vec![
+                "site2ts://audit/A1/tsc",
+                "site2ts://diff/D1/root/diff.png",
+                "site2ts://logs/J1"
+            ]
+        );
+
+        let png = read(
+            &base,
+            ResourceParams {
+                uri: "site2ts://diff/D1/root/diff.png".into(),
+            },
+        )
+        .unwrap();
+        assert_eq!(png["contents"][0]["mimeType"], "image/png");
+        assert_eq!(png["contents"][0]["blob"], "iVBORw==");
+
+        let log = read(
+            &base,
+            ResourceParams {
+                uri: "site2ts://logs/J1".into(),
+            },
+        )
+        .unwrap();
+        assert_eq!(log["contents"][0]["mimeType"], "application/x-ndjson");
+        assert!(log["contents"][0]["text"].as_str().unwrap().contains("hi"));
+
+        let err = read(
+            &base,
+            ResourceParams {
+                uri: "site2ts://logs/missing".into(),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.code(), -32002);
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn subscriptions_report_rewritten_files_once() {
+        let base = sandbox("subs");
+        let log = base.join("logs/J1.ndjson");
+        write(&log, b"a\n");
+        let mut subs = Subscriptions::default();
+        subs.insert("site2ts://logs/J1".into(), &log);
+        subs.insert("site2ts://logs/J2".into(), &base.join("logs/J2.ndjson"));
+        assert!(subs.changed(&base).is_empty());
+
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        fs::File::options()
+            .write(true)
+            .open(&log)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        write(&base.join("logs/J2.ndjson"), b"b\n");
+        assert_eq!(
+            subs.changed(&base),
+            vec![
+                "site2ts://logs/J1".to_string(),
+                "site2ts://logs/J2".to_string()
+            ]
+        );
+        assert!(subs.changed(&base).is_empty());
+        let _ = fs::remove_dir_all(&base);
+    }
+}
@@ -0,0 +1,325 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+
+use crate::backend::{Backend, WorkerBackend};
+use crate::resources::Subscriptions;
+use crate::{RpcError, RpcResult};
+
+/// Outbound channel for server-initiated messages. A default notifier has
+/// no receiver and silently drops everything.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Notifier {
+    tx: Option<UnboundedSender<Value>>,
+}
+
+impl Notifier {
+    pub(crate) fn new(tx: UnboundedSender<Value>) -> Self {
+        Self { tx: Some(tx) }
+    }
+
+    /// Whether a receiver is still listening.
+    pub(crate) fn is_open(&self) -> bool {
+        self.tx.as_ref().is_some_and(|tx| !tx.is_closed())
+    }
+
+    /// Queue a JSON-RPC notification. Returns false when nobody is listening.
+    pub(crate) fn notify(&self, method: &str, params: Value) -> bool {
+        let msg = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        match &self.tx {
+            Some(tx) => tx.send(msg).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Cancellation signal for one request, shared between the session (which
+/// receives `notifications/cancelled`) and the code running the request.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CancelToken {
+    inner: Arc<CancelInner>,
+}
+
+#[derive(Debug, Default)]
+struct CancelInner {
+    reason: Mutex<Option<String>>,
+    notify: Notify,
+}
+
+impl CancelToken {
+    /// Cancel with `reason`. Only the first reason is kept.
+    pub(crate) fn cancel(&self, reason: impl Into<String>) {
+        let mut slot = self.inner.reason.lock().unwrap_or_else(|e| e.into_inner());
+        if slot.is_none() {
+            *slot = Some(reason.into());
+        }
+        drop(slot);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub(crate) fn reason(&self) -> Option<String> {
+        self.inner
+            .reason
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Resolve with the reason once the token is cancelled.
+    pub(crate) async fn cancelled(&self) -> String {
+        loop {
+            let notified = self.inner.notify.notified();
+            if let Some(reason) = self.reason() {
+                return reason;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// The project root `init` bound a session to. Request contexts share it so
+/// an `init` run inside a pipeline binds the session as well.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProjectSlot(Arc<Mutex<Option<PathBuf>>>);
+
+impl ProjectSlot {
+    pub(crate) fn get(&self) -> Option<PathBuf> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub(crate) fn set(&self, root: PathBuf) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(root);
+    }
+}
+
+/// One connected MCP client. stdio serves exactly one; the HTTP transport
+/// keeps one per `Mcp-Session-Id`.
+#[derive(Debug)]
+pub(crate) struct Session {
+    id: String,
+    notifier: Mutex<Notifier>,
+    subscriptions: Mutex<Subscriptions>,
+    in_flight: Mutex<HashMap<String, InFlight>>,
+    next_seq: AtomicU64,
+    backend: Arc<dyn Backend>,
+    project: ProjectSlot,
+    /// The MCP protocol version `initialize` settled on.
+    protocol_version: Mutex<Option<String>>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new("", Notifier::default())
+    }
+}
+
+/// A request that has been received and not yet answered.
+#[derive(Debug, Clone)]
+struct InFlight {
+    seq: u64,
+    id: Value,
+    method: String,
+    started_at: DateTime<Utc>,
+    cancel: CancelToken,
+}
+
+/// Removes its request from the session's in-flight table when dropped.
+pub(crate) struct InFlightGuard<'a> {
+    session: &'a Session,
+    key: String,
+    cancel: CancelToken,
+}
+
+impl InFlightGuard<'_> {
+    /// Fires when the client cancels this request.
+    pub(crate) fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.session.in_flight_table().remove(&self.key);
+    }
+}
+
+impl Session {
+    pub(crate) fn new(id: impl Into<String>, notifier: Notifier) -> Self {
+        Self {
+            id: id.into(),
+            notifier: Mutex::new(notifier),
+            subscriptions: Mutex::new(Subscriptions::default()),
+            in_flight: Mutex::new(HashMap::new()),
+            next_seq: AtomicU64::new(0),
+            backend: Arc::new(WorkerBackend),
+            project: ProjectSlot::default(),
+            protocol_version: Mutex::new(None),
+        }
+    }
+
+    /// Run this session's tool calls on `backend` instead of the worker pool.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn with_backend(mut self, backend: Arc<dyn Backend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub(crate) fn backend(&self) -> Arc<dyn Backend> {
+        self.backend.clone()
+    }
+
+    pub(crate) fn project(&self) -> ProjectSlot {
+        self.project.clone()
+    }
+
+    /// The bound project's root; calls other than `init` need one.
+    pub(crate) fn project_root(&self) -> RpcResult<PathBuf> {
+        self.project.get().ok_or_else(|| {
+            RpcError::bad_input(
+                "no project; call init with projectRoot first",
+                json!({ "session": self.id }),
+            )
+        })
+    }
+
+    /// The bound project's `.site2ts` sandbox.
+    pub(crate) fn sandbox(&self) -> RpcResult<PathBuf> {
+        Ok(self.project_root()?.join(".site2ts"))
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub(crate) fn protocol_version(&self) -> Option<String> {
+        self.protocol_version
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set_protocol_version(&self, version: &str) {
+        *self
+            .protocol_version
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(version.to_string());
+    }
+
+    /// Channel for messages not tied to a request, such as resource updates.
+    pub(crate) fn notifier(&self) -> Notifier {
+        self.notifier
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set_notifier(&self, notifier: Notifier) {
+        *self.notifier.lock().unwrap_or_else(|e| e.into_inner()) = notifier;
+    }
+
+    pub(crate) fn subscriptions(&self) -> MutexGuard<'_, Subscriptions> {
+        self.subscriptions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Track a request until the returned guard is dropped.
+    pub(crate) fn begin(&self, id: &Value, method: &str) -> InFlightGuard<'_> {
+        let key = id.to_string();
+        let cancel = CancelToken::default();
+        self.in_flight_table().insert(
+            key.clone(),
+            InFlight {
+                seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+                id: id.clone(),
+                method: method.to_string(),
+                started_at: Utc::now(),
+                cancel: cancel.clone(),
+            },
+        );
+        InFlightGuard {
+            session: self,
+            key,
+            cancel,
+        }
+    }
+
+    /// Cancel the in-flight request `id`. Returns false if it already
+    /// finished or was never seen.
+    pub(crate) fn cancel(&self, id: &Value, reason: &str) -> bool {
+        match self.in_flight_table().get(&id.to_string()) {
+            Some(entry) => {
+                entry.cancel.cancel(reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Requests currently being handled, oldest first.
+    pub(crate) fn in_flight(&self) -> Vec<Value> {
+        let now = Utc::now();
+        let mut entries: Vec<InFlight> = self.in_flight_table().values().cloned().collect();
+        entries.sort_by_key(|e| e.seq);
+        entries
+            .into_iter()
+            .map(|e| {
+                json!({
+                    "id": e.id,
+                    "method": e.method,
+                    "startedAt": e.started_at.to_rfc3339(),
+                    "elapsedMs": (now - e.started_at).num_milliseconds(),
+                })
+            })
+            .collect()
+    }
+
+    fn in_flight_table(&self) -> MutexGuard<'_, HashMap<String, InFlight>> {
+        self.in_flight.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_flight_requests_are_tracked_until_answered() {
+        let session = Session::default();
+        let a = session.begin(&json!(1), "crawl");
+        {
+            let _b = session.begin(&json!("b"), "status");
+            let methods: Vec<Value> = session
+                .in_flight()
+                .iter()
+                .map(|e| e["method"].clone())
+                .collect();
+            assert_eq!(methods, vec![json!("crawl"), json!("status")]);
+        }
+        assert_eq!(session.in_flight().len(), 1);
+        assert_eq!(session.in_flight()[0]["id"], 1);
+        drop(a);
+        assert!(session.in_flight().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_request_wakes_its_token() {
+        let session = Session::default();
+        let guard = session.begin(&json!(7), "tools/call");
+        let token = guard.cancel_token();
+        let waiter = tokio::spawn({
+            let token = token.clone();
+            async move { token.cancelled().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!session.cancel(&json!(8), "nope"));
+        assert!(session.cancel(&json!(7), "user abort"));
+        token.cancel("second reason is ignored");
+        assert_eq!(waiter.await.unwrap(), "user abort");
+        drop(guard);
+        assert!(!session.cancel(&json!(7), "too late"));
+    }
+}
@@ -1,36 +1,206 @@
 use anyhow::{anyhow, Context, Result};
-use once_cell::sync::OnceCell;
 use serde_json::{json, Value};
-use std::io::{BufRead, Write};
-use std::path::PathBuf;
-use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
-use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, warn};
 use ulid::Ulid;
 
+use crate::progress::ProgressReporter;
+use crate::session::CancelToken;
 use crate::RpcError;
 
-static WORKER: OnceCell<Mutex<Worker>> = OnceCell::new();
+/// JSON-RPC code for a request that was cancelled before it finished.
+pub(crate) const CANCELLED_CODE: i32 = -32800;
 
+/// How long a worker gets to wind down a cancelled call before it is killed.
+const CANCEL_GRACE: Duration = Duration::from_secs(5);
+
+/// Version of the server <-> worker message format. Bump it together with
+/// `PROTOCOL_VERSION` in the worker on any incompatible change.
+pub(crate) const PROTOCOL_VERSION: u64 = 1;
+
+/// Worker methods the server calls; a worker lacking any of them is stale.
+const REQUIRED_METHODS: &[&str] = &[
+    "ping",
+    "cancel",
+    "initRuntime",
+    "runtimeInfo",
+    "crawl",
+    "analyze",
+    "scaffold",
+    "generate",
+    "diff",
+    "audit",
+    "apply",
+    "assets",
+    "pack",
+    "improve",
+];
+
+/// Oldest Node.js major the worker supports (`engines` in its package.json).
+const MIN_NODE_MAJOR: u64 = 20;
+
+/// How long a freshly spawned worker has to answer `hello`.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Calls waiting for the worker, keyed by the request id we sent.
+type PendingMap = Arc<Mutex<HashMap<String, Pending>>>;
+
+struct Pending {
+    response: oneshot::Sender<std::result::Result<Value, RpcError>>,
+    progress: mpsc::UnboundedSender<Value>,
+    method: String,
+    /// `None` for internal calls such as health pings.
+    job: Option<CallJob>,
+}
+
+/// The tool call a worker request runs for. Its stderr is filed under the
+/// job's log and the worker resolves paths against its project.
+#[derive(Debug, Clone)]
+pub(crate) struct CallJob {
+    pub(crate) id: String,
+    pub(crate) project_root: PathBuf,
+}
+
+/// Client for one Node worker process. Any number of calls may be in
+/// flight at once; a reader task routes each response and progress
+/// message back to its caller by request id.
 pub struct Worker {
-    // Keep process alive; never read directly
-    #[allow(dead_code)]
-    child: Child,
-    stdin: ChildStdin,
-    stdout: std::io::BufReader<ChildStdout>,
+    child: Mutex<Child>,
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    pending: PendingMap,
+    // Set by the reader task once stdout closes.
+    closed: Arc<AtomicBool>,
+    /// What the worker reported in the `hello` handshake.
+    hello: Value,
+}
+
+/// Worker script locations tried, in order, under each ancestor of the
+/// server executable's directory.
+const SCRIPT_LAYOUTS: &[&str] = &[
+    "node/site2ts-worker/dist/index.js",
+    "site2ts-worker/dist/index.js",
+    "lib/site2ts-worker/dist/index.js",
+];
+
+/// How many ancestors of the executable's directory to search.
+const SCRIPT_SEARCH_DEPTH: usize = 5;
+
+/// How to start a Node worker process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WorkerLaunch {
+    pub(crate) node: PathBuf,
+    pub(crate) script: PathBuf,
+    /// Extra arguments for node, placed before the script.
+    pub(crate) args: Vec<String>,
+    /// Added to the inherited environment.
+    pub(crate) env: BTreeMap<String, String>,
+    /// Where the default script was looked for; empty once overridden.
+    pub(crate) searched: Vec<PathBuf>,
+}
+
+impl Default for WorkerLaunch {
+    /// `node` from PATH and the first worker build found next to the
+    /// executable, falling back to the current directory.
+    fn default() -> Self {
+        let exe_dir = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf));
+        let mut searched: Vec<PathBuf> = exe_dir
+            .iter()
+            .flat_map(|dir| dir.ancestors().take(SCRIPT_SEARCH_DEPTH + 1))
+            .flat_map(|dir| SCRIPT_LAYOUTS.iter().map(move |l| dir.join(l)))
+            .collect();
+        searched.push(PathBuf::from(SCRIPT_LAYOUTS[0]));
+        let script = searched
+            .iter()
+            .find(|p| p.is_file())
+            .unwrap_or(&searched[0])
+            .clone();
+        Self {
+            node: PathBuf::from("node"),
+            script,
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            searched,
+        }
+    }
+}
+
+impl WorkerLaunch {
+    pub(crate) fn set_script(&mut self, script: PathBuf) {
+        self.script = script;
+        self.searched.clear();
+    }
 }
 
 impl Worker {
-    fn spawn() -> Result<Self> {
-        let script = PathBuf::from("node")
-            .join("site2ts-worker")
-            .join("dist")
-            .join("index.js");
-        let mut cmd = Command::new("node");
-        cmd.arg(script)
+    /// Start a worker and check it is compatible with this server. A missing
+    /// script or node binary, or a worker that fails the `hello` handshake,
+    /// is an ENV_MISSING `RpcError` wrapped in the returned error.
+    pub(crate) async fn start(launch: &WorkerLaunch) -> Result<Self> {
+        let mut worker = Self::spawn(launch)?;
+        let hello = worker
+            .call(
+                "hello",
+                json!({ "protocolVersion": PROTOCOL_VERSION }),
+                &mut ProgressReporter::default(),
+                &CancelToken::default(),
+                Some(HELLO_TIMEOUT),
+                None,
+            )
+            .await
+            .map_err(|e| handshake_failed(&launch.script, e))?;
+        check_hello(&hello, &launch.script)?;
+        worker.hello = hello;
+        Ok(worker)
+    }
+
+    fn spawn(launch: &WorkerLaunch) -> Result<Self> {
+        if !launch.script.is_file() {
+            let mut data = json!({ "missing": "worker script", "path": launch.script });
+            if !launch.searched.is_empty() {
+                data["searched"] = json!(launch.searched);
+            }
+            return Err(RpcError::env_missing(
+                format!(
+                    "worker script not found at {}; build node/site2ts-worker or pass --worker-script",
+                    launch.script.display()
+                ),
+                data,
+            )
+            .into());
+        }
+        let mut cmd = Command::new(&launch.node);
+        cmd.args(&launch.args)
+            .arg(&launch.script)
+            .envs(&launch.env)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::inherit());
-        let mut child = cmd.spawn().context("spawn node worker")?;
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(RpcError::env_missing(
+                    format!(
+                        "node not found ({}); install Node.js or pass --node",
+                        launch.node.display()
+                    ),
+                    json!({ "missing": "node", "path": launch.node }),
+                )
+                .into())
+            }
+            Err(e) => return Err(e).context("spawn node worker"),
+        };
         let stdin = child
             .stdin
             .take()
@@ -39,63 +209,630 @@ impl Worker {
             .stdout
             .take()
             .ok_or_else(|| anyhow!("no worker stdout"))?;
+        let stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| anyhow!("no worker stderr"))?;
+        let pending = PendingMap::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        tokio::spawn(stderr_loop(BufReader::new(stderr), pending.clone()));
+        tokio::spawn(read_loop(
+            BufReader::new(stdout),
+            pending.clone(),
+            closed.clone(),
+        ));
         Ok(Self {
-            child,
-            stdin,
-            stdout: std::io::BufReader::new(stdout),
+            child: Mutex::new(child),
+            stdin: tokio::sync::Mutex::new(stdin),
+            pending,
+            closed,
+            hello: Value::Null,
         })
     }
 
-    pub fn get() -> Result<&'static Mutex<Worker>> {
-        WORKER.get_or_try_init(|| Worker::spawn().map(Mutex::new))
+    /// The worker's `hello` result: versions and supported methods.
+    pub(crate) fn hello(&self) -> &Value {
+        &self.hello
+    }
+
+    /// Why the process is gone, or `None` while it is still running.
+    pub(crate) fn exited(&self) -> Option<String> {
+        let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+        match child.try_wait() {
+            Ok(Some(status)) => Some(status.to_string()),
+            Err(e) => Some(format!("wait failed: {}", e)),
+            Ok(None) if self.closed.load(Ordering::Relaxed) => Some("stdout closed".into()),
+            Ok(None) => None,
+        }
+    }
+
+    /// Kill the process. Calls still waiting fail once its stdout closes.
+    pub(crate) fn kill(&self) {
+        let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = child.start_kill();
     }
 
-    pub fn call(&mut self, method: &str, params: Value) -> std::result::Result<Value, RpcError> {
+    /// Run `method` on the worker for `job`. When `cancel` fires or
+    /// `timeout` elapses the worker is asked to stop and gets CANCEL_GRACE to
+    /// answer with what it has so far; if it does not, the process is killed.
+    pub async fn call(
+        &self,
+        method: &str,
+        mut params: Value,
+        progress: &mut ProgressReporter,
+        cancel: &CancelToken,
+        timeout: Option<Duration>,
+        job: Option<&CallJob>,
+    ) -> std::result::Result<Value, RpcError> {
         let id = Ulid::new().to_string();
+        if let Some(job) = job.filter(|_| params.is_object()) {
+            params["_meta"] = json!({ "jobId": job.id, "projectRoot": job.project_root });
+        }
         let req = json!({
             "jsonrpc": "2.0",
             "method": method,
             "params": params,
             "id": id
         });
-        let line = serde_json::to_string(&req)
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+        let _pending = PendingGuard::insert(
+            &self.pending,
+            &id,
+            Pending {
+                response: response_tx,
+                progress: progress_tx,
+                method: method.to_string(),
+                job: job.cloned(),
+            },
+        );
+        self.send(&req).await?;
+
+        let deadline = tokio::time::sleep(timeout.unwrap_or_default());
+        let grace = tokio::time::sleep(CANCEL_GRACE);
+        tokio::pin!(deadline, grace);
+        let mut stop: Option<Stop> = None;
+        loop {
+            tokio::select! {
+                biased;
+                Some(params) = progress_rx.recv() => progress.report(&params),
+                res = &mut response_rx => {
+                    // Progress sent before the response must not be lost.
+                    while let Ok(params) = progress_rx.try_recv() {
+                        progress.report(&params);
+                    }
+                    let res = res.unwrap_or_else(|_| Err(restarted_error()));
+                    return match (stop, res) {
+                        (Some(stop), Err(err)) => {
+                            let partial = err.data.and_then(|d| d.get("partial").cloned());
+                            Err(stop.into_error(partial, false))
+                        }
+                        (_, res) => res,
+                    };
+                }
+                reason = cancel.cancelled(), if stop.is_none() => {
+                    self.request_cancel(&id, &reason).await;
+                    grace.as_mut().reset(Instant::now() + CANCEL_GRACE);
+                    stop = Some(Stop { reason, timed_out: false });
+                }
+                _ = &mut deadline, if stop.is_none() && timeout.is_some() => {
+                    let reason = format!(
+                        "{} timed out after {}s",
+                        method,
+                        timeout.unwrap_or_default().as_secs()
+                    );
+                    self.request_cancel(&id, &reason).await;
+                    grace.as_mut().reset(Instant::now() + CANCEL_GRACE);
+                    stop = Some(Stop { reason, timed_out: true });
+                }
+                _ = &mut grace, if stop.is_some() => {
+                    warn!(
+                        target = "site2ts",
+                        "worker did not stop {} {} within {:?}; killing it", method, id, CANCEL_GRACE
+                    );
+                    self.kill();
+                    let stop = stop.take().unwrap_or_default();
+                    return Err(stop.into_error(None, true));
+                }
+            }
+        }
+    }
+
+    /// Ask the worker to abandon request `id`. It answers the request itself
+    /// with a cancellation error carrying any partial results.
+    async fn request_cancel(&self, id: &str, reason: &str) {
+        let msg = json!({
+            "jsonrpc": "2.0",
+            "method": "cancel",
+            "params": { "id": id, "reason": reason }
+        });
+        if let Err(e) = self.send(&msg).await {
+            debug!(target = "site2ts", "send cancel failed: {}", e);
+        }
+    }
+
+    async fn send(&self, msg: &Value) -> std::result::Result<(), RpcError> {
+        let line = serde_json::to_string(msg)
             .map_err(|e| RpcError::internal(format!("serialize worker request failed: {}", e)))?
             + "\n";
-        self.stdin
-            .write_all(line.as_bytes())
-            .map_err(|e| RpcError::internal(format!("write worker stdin failed: {}", e)))?;
-        self.stdin
-            .flush()
-            .map_err(|e| RpcError::internal(format!("flush worker stdin failed: {}", e)))?;
+        let mut stdin = self.stdin.lock().await;
+        // A closed pipe means the process is gone; the pool replaces it.
+        if let Err(e) = stdin.write_all(line.as_bytes()).await {
+            debug!(target = "site2ts", "write worker stdin failed: {}", e);
+            return Err(restarted_error());
+        }
+        if let Err(e) = stdin.flush().await {
+            debug!(target = "site2ts", "flush worker stdin failed: {}", e);
+            return Err(restarted_error());
+        }
+        Ok(())
+    }
+}
 
-        loop {
-            let mut buf = String::new();
-            self.stdout
-                .read_line(&mut buf)
-                .map_err(|e| RpcError::internal(format!("read worker response failed: {}", e)))?;
-            if buf.trim().is_empty() {
-                return Err(RpcError::internal("empty response from worker"));
+/// Why a call is being stopped early.
+#[derive(Debug, Default)]
+struct Stop {
+    reason: String,
+    timed_out: bool,
+}
+
+impl Stop {
+    /// The error for a call that ended cancelled. Whatever the worker
+    /// finished before stopping is kept under `data.partial`.
+    fn into_error(self, partial: Option<Value>, killed: bool) -> RpcError {
+        let mut data = json!({ "state": "cancelled", "reason": self.reason });
+        if self.timed_out {
+            data["timedOut"] = json!(true);
+        }
+        if killed {
+            data["workerKilled"] = json!(true);
+        }
+        if let Some(partial) = partial {
+            data["partial"] = partial;
+        }
+        RpcError::new(
+            CANCELLED_CODE,
+            format!("request cancelled: {}", self.reason),
+            Some(data),
+        )
+    }
+}
+
+/// Removes a call from the pending table when the caller stops waiting,
+/// so a late response is reported as unknown instead of leaking.
+struct PendingGuard<'a> {
+    pending: &'a PendingMap,
+    id: String,
+}
+
+impl<'a> PendingGuard<'a> {
+    fn insert(pending: &'a PendingMap, id: &str, entry: Pending) -> Self {
+        pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id.to_string(), entry);
+        Self {
+            pending,
+            id: id.to_string(),
+        }
+    }
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// A worker that could not complete `hello`. Builds from before the
+/// handshake answer it with "method not found".
+fn handshake_failed(script: &Path, err: RpcError) -> RpcError {
+    let message = if err.code() == -32601 {
+        format!(
+            "worker at {} does not support the hello handshake; it predates this server, rebuild node/site2ts-worker (npm run build)",
+            script.display()
+        )
+    } else {
+        format!(
+            "worker at {} failed the hello handshake: {}",
+            script.display(),
+            err.message()
+        )
+    };
+    RpcError::env_missing(
+        message,
+        json!({ "missing": "compatible worker", "path": script, "cause": err.to_json() }),
+    )
+}
+
+/// Refuse a worker that speaks another protocol version, lacks methods the
+/// server calls, or runs on a Node.js that is too old.
+fn check_hello(hello: &Value, script: &Path) -> std::result::Result<(), RpcError> {
+    let mut problems = Vec::new();
+    let version = hello.get("protocolVersion").and_then(Value::as_u64);
+    if version != Some(PROTOCOL_VERSION) {
+        problems.push(format!(
+            "protocol version {} (server speaks {})",
+            version.map_or("unknown".to_string(), |v| v.to_string()),
+            PROTOCOL_VERSION
+        ));
+    }
+    let methods: Vec<&str> = hello
+        .get("methods")
+        .and_then(Value::as_array)
+        .map(|m| m.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let missing: Vec<&str> = REQUIRED_METHODS
+        .iter()
+        .copied()
+        .filter(|m| !methods.contains(m))
+        .collect();
+    if !missing.is_empty() {
+        problems.push(format!("missing methods {}", missing.join(", ")));
+    }
+    let node = hello
+        .get("node")
+        .and_then(Value::as_str)
+        .unwrap_or("unknown");
+    let node_major = node
+        .trim_start_matches('v')
+        .split('.')
+        .next()
+        .and_then(|m| m.parse::<u64>().ok());
+    if node_major.is_none_or(|m| m < MIN_NODE_MAJOR) {
+        problems.push(format!("node {} (need {}+)", node, MIN_NODE_MAJOR));
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(RpcError::env_missing(
+        format!(
+            "incompatible worker at {}: {}; rebuild node/site2ts-worker (npm run build)",
+            script.display(),
+            problems.join("; ")
+        ),
+        json!({
+            "missing": "compatible worker",
+            "path": script,
+            "expected": {
+                "protocolVersion": PROTOCOL_VERSION,
+                "methods": REQUIRED_METHODS,
+                "node": format!(">={}", MIN_NODE_MAJOR),
+            },
+            "actual": hello,
+        }),
+    ))
+}
+
+/// The error for a call whose worker died before answering. The pool
+/// replaces the worker, so the caller may simply retry.
+fn restarted_error() -> RpcError {
+    RpcError::new(
+        -32603,
+        "worker exited before responding and has been restarted; retry the request",
+        Some(json!({ "workerRestarted": true })),
+    )
+}
+
+/// Route worker output to pending calls until stdout closes, then fail
+/// whatever is still waiting.
+async fn read_loop<R: AsyncBufRead + Unpin>(
+    mut stdout: R,
+    pending: PendingMap,
+    closed: Arc<AtomicBool>,
+) {
+    loop {
+        let mut buf = String::new();
+        match stdout.read_line(&mut buf).await {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                warn!(target = "site2ts", "read worker stdout failed: {}", e);
+                break;
             }
-            let v: Value = serde_json::from_str(&buf)
-                .map_err(|e| RpcError::internal(format!("parse worker JSON failed: {}", e)))?;
-            if let Some(method) = v.get("method").and_then(|m| m.as_str()) {
-                if method == "progress" {
-                    print!("{}", buf);
-                    std::io::stdout().flush().ok();
-                    continue;
-                }
+        }
+        if buf.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Value>(&buf) {
+            Ok(msg) => route(&pending, msg),
+            Err(e) => warn!(
+                target = "site2ts",
+                "discarding unparseable worker output: {}", e
+            ),
+        }
+    }
+    closed.store(true, Ordering::Relaxed);
+    let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+    for (_, call) in pending.drain() {
+        let _ = call.response.send(Err(restarted_error()));
+    }
+}
+
+/// File worker stderr under the jobs running when each line arrives. With
+/// several jobs in flight a line cannot be attributed to one of them, so it
+/// goes to each of their logs together with the ids it is shared between.
+async fn stderr_loop<R: AsyncBufRead + Unpin>(stderr: R, pending: PendingMap) {
+    let mut lines = stderr.lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                debug!(target = "site2ts", "read worker stderr failed: {}", e);
+                break;
             }
-            if let Some(err) = v.get("error") {
-                let code = err.get("code").and_then(|c| c.as_i64()).unwrap_or(-32603) as i32;
-                let message = err
-                    .get("message")
-                    .and_then(|m| m.as_str())
-                    .unwrap_or("worker error")
-                    .to_string();
-                let data = err.get("data").cloned();
-                return Err(RpcError::new(code, message, data));
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let jobs: Vec<(CallJob, String)> = pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .filter_map(|p| Some((p.job.clone()?, p.method.clone())))
+            .collect();
+        if jobs.is_empty() {
+            debug!(target = "site2ts", "worker stderr: {}", line);
+            continue;
+        }
+        let level = stderr_level(&line);
+        for (job, method) in &jobs {
+            let mut data = json!({ "source": "worker-stderr", "method": method });
+            if jobs.len() > 1 {
+                data["sharedWith"] = jobs
+                    .iter()
+                    .map(|(other, _)| other.id.as_str())
+                    .filter(|id| *id != job.id)
+                    .collect();
+            }
+            let phase = if method == "initRuntime" {
+                "init"
+            } else {
+                method
+            };
+            let logs_dir = job.project_root.join(".site2ts").join("logs");
+            if let Err(e) = crate::append_ndjson(&logs_dir, level, &job.id, phase, &line, data) {
+                debug!(target = "site2ts", "write job log failed: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Lines that look like problems are `warn`; the rest is `debug` noise.
+fn stderr_level(line: &str) -> &'static str {
+    let lower = line.to_ascii_lowercase();
+    if ["error", "warn", "fail", "exception", "fatal"]
+        .iter()
+        .any(|w| lower.contains(w))
+    {
+        "warn"
+    } else {
+        "debug"
+    }
+}
+
+fn route(pending: &PendingMap, msg: Value) {
+    let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+    if msg.get("method").and_then(|m| m.as_str()) == Some("progress") {
+        let params = msg.get("params").cloned().unwrap_or(Value::Null);
+        let target = match params.get("requestId").and_then(|v| v.as_str()) {
+            Some(id) => pending.get(id),
+            // Untagged progress is only unambiguous with a single call in flight.
+            None if pending.len() == 1 => pending.values().next(),
+            None => None,
+        };
+        match target {
+            Some(call) => {
+                let _ = call.progress.send(params);
             }
-            return Ok(v.get("result").cloned().unwrap_or(json!({})));
+            None => debug!(target = "site2ts", "dropping unroutable worker progress"),
+        }
+        return;
+    }
+
+    let Some(id) = msg.get("id").and_then(|v| v.as_str()) else {
+        warn!(
+            target = "site2ts",
+            "worker message without request id: {}", msg
+        );
+        return;
+    };
+    let Some(call) = pending.remove(id) else {
+        warn!(
+            target = "site2ts",
+            "worker response for unknown request id {}", id
+        );
+        return;
+    };
+    let _ = call.response.send(response_result(&msg));
+}
+
+fn response_result(msg: &Value) -> std::result::Result<Value, RpcError> {
+    if let Some(err) = msg.get("error") {
+        let code = err.get("code").and_then(|c| c.as_i64()).unwrap_or(-32603) as i32;
+        let message = err
+            .get("message")
+            .and_then(|m| m.as_str())
+            .unwrap_or("worker error")
+            .to_string();
+        let data = err.get("data").cloned();
+        return Err(RpcError::new(code, message, data));
+    }
+    Ok(msg.get("result").cloned().unwrap_or(json!({})))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(
+        pending: &PendingMap,
+        id: &str,
+        root: &Path,
+    ) -> (
+        oneshot::Receiver<std::result::Result<Value, RpcError>>,
+        mpsc::UnboundedReceiver<Value>,
+    ) {
+        let (response, response_rx) = oneshot::channel();
+        let (progress, progress_rx) = mpsc::unbounded_channel();
+        pending.lock().unwrap().insert(
+            id.to_string(),
+            Pending {
+                response,
+                progress,
+                method: "crawl".into(),
+                job: Some(CallJob {
+                    id: format!("JOB-{}", id),
+                    project_root: root.to_path_buf(),
+                }),
+            },
+        );
+        (response_rx, progress_rx)
+    }
+
+    #[tokio::test]
+    async fn responses_and_progress_are_routed_by_id() {
+        let pending = PendingMap::default();
+        let (a, mut a_progress) = register(&pending, "a", Path::new("."));
+        let (b, mut b_progress) = register(&pending, "b", Path::new("."));
+        let output = [
+            r#"{"jsonrpc":"2.0","method":"progress","params":{"requestId":"b","tool":"diff","phase":"build"}}"#,
+            r#"{"jsonrpc":"2.0","result":{"from":"b"},"id":"b"}"#,
+            r#"{"jsonrpc":"2.0","result":{"from":"stale"},"id":"zzz"}"#,
+            r#"{"jsonrpc":"2.0","method":"progress","params":{"requestId":"a","tool":"crawl","phase":"fetch"}}"#,
+            r#"{"jsonrpc":"2.0","error":{"code":-32001,"message":"crawl first"},"id":"a"}"#,
+        ]
+        .join("\n");
+        read_loop(
+            BufReader::new(output.as_bytes()),
+            pending.clone(),
+            Arc::default(),
+        )
+        .await;
+
+        assert_eq!(b.await.unwrap().unwrap(), json!({ "from": "b" }));
+        assert_eq!(b_progress.recv().await.unwrap()["tool"], "diff");
+        assert_eq!(a.await.unwrap().unwrap_err().code(), -32001);
+        assert_eq!(a_progress.recv().await.unwrap()["tool"], "crawl");
+        assert!(pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_script_or_node_is_env_missing() {
+        let mut launch = WorkerLaunch::default();
+        launch.set_script(PathBuf::from("/nonexistent/site2ts/index.js"));
+        let err = Worker::spawn(&launch).err().unwrap();
+        let err = err.downcast::<RpcError>().unwrap();
+        assert_eq!(err.data.as_ref().unwrap()["class"], "ENV_MISSING");
+        assert_eq!(err.data.as_ref().unwrap()["missing"], "worker script");
+
+        launch.set_script(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"));
+        launch.node = PathBuf::from("/nonexistent/bin/node");
+        let err = Worker::spawn(&launch).err().unwrap();
+        let err = err.downcast::<RpcError>().unwrap();
+        assert_eq!(err.data.as_ref().unwrap()["missing"], "node");
+    }
+
+    #[test]
+    fn hello_must_match_protocol_methods_and_node() {
+        let script = Path::new("dist/index.js");
+        let mut hello = json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "workerVersion": "0.1.0",
+            "methods": REQUIRED_METHODS,
+            "node": "20.11.1",
+            "playwright": "1.57.0",
+        });
+        assert!(check_hello(&hello, script).is_ok());
+
+        hello["protocolVersion"] = json!(PROTOCOL_VERSION + 1);
+        hello["methods"] = json!(["ping", "crawl"]);
+        hello["node"] = json!("18.19.0");
+        let err = check_hello(&hello, script).unwrap_err();
+        let data = err.data.as_ref().unwrap();
+        assert_eq!(data["class"], "ENV_MISSING");
+        assert_eq!(data["actual"], hello);
+        assert!(err.message().contains("protocol version"));
+        assert!(err
+            .message()
+            .contains("missing methods cancel, initRuntime"));
+        assert!(err.message().contains("node 18.19.0"));
+
+        let stale = handshake_failed(script, RpcError::new(-32601, "method not found", None));
+        assert_eq!(stale.code(), -32010);
+        assert!(stale.message().contains("predates this server"));
+    }
+
+    #[test]
+    fn default_script_is_searched_next_to_the_executable() {
+        let launch = WorkerLaunch::default();
+        let exe_dir = std::env::current_exe().unwrap();
+        let exe_dir = exe_dir.parent().unwrap();
+        assert!(launch.searched[0].starts_with(exe_dir));
+        assert_eq!(
+            launch.searched.last().unwrap(),
+            &PathBuf::from("node/site2ts-worker/dist/index.js")
+        );
+        assert!(launch.searched.contains(&launch.script));
+    }
+
+    #[tokio::test]
+    async fn stderr_is_filed_under_the_running_jobs() {
+        let root = std::env::temp_dir().join(format!("site2ts-logs-{}", Ulid::new()));
+        let logs = root.join(".site2ts").join("logs");
+        let pending = PendingMap::default();
+        let _a = register(&pending, "a", &root);
+        stderr_loop(&b"Error: net::ERR_NAME_NOT_RESOLVED\n"[..], pending.clone()).await;
+        let _b = register(&pending, "b", &root);
+        stderr_loop(&b"\nDEBUG pw:browser launching\n"[..], pending.clone()).await;
+
+        let read = |job: &str| -> Vec<Value> {
+            std::fs::read_to_string(logs.join(format!("{}.ndjson", job)))
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect()
+        };
+        let a = read("JOB-a");
+        assert_eq!(a.len(), 2);
+        assert_eq!(a[0]["level"], "warn");
+        assert_eq!(a[0]["phase"], "crawl");
+        assert_eq!(a[0]["msg"], "Error: net::ERR_NAME_NOT_RESOLVED");
+        assert!(a[0]["data"].get("sharedWith").is_none());
+        assert_eq!(a[1]["level"], "debug");
+        assert_eq!(a[1]["data"]["sharedWith"], json!(["JOB-b"]));
+        assert_eq!(read("JOB-b").len(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn cancelled_calls_keep_partial_results() {
+        let err = Stop {
+            reason: "crawl timed out after 60s".into(),
+            timed_out: true,
         }
+        .into_error(Some(json!({ "pages": 12 })), false);
+        assert_eq!(err.code(), CANCELLED_CODE);
+        let data = err.data.unwrap();
+        assert_eq!(data["state"], "cancelled");
+        assert_eq!(data["timedOut"], true);
+        assert_eq!(data["partial"]["pages"], 12);
+        assert!(data.get("workerKilled").is_none());
+    }
+
+    #[tokio::test]
+    async fn calls_still_waiting_fail_when_worker_exits() {
+        let pending = PendingMap::default();
+        let (a, _progress) = register(&pending, "a", Path::new("."));
+        let closed = Arc::new(AtomicBool::new(false));
+        read_loop(BufReader::new(&b""[..]), pending.clone(), closed.clone()).await;
+        let err = a.await.unwrap().unwrap_err();
+        assert!(err.message().contains("restarted"));
+        assert!(closed.load(Ordering::Relaxed));
     }
 }
@@ -0,0 +1,368 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use tracing::warn;
+use ulid::Ulid;
+
+use crate::{RpcError, RpcResult};
+
+/// A holder on another host cannot be checked for liveness, so its lock is
+/// treated as stale once it is this old.
+const REMOTE_STALE_AFTER_HOURS: i64 = 6;
+
+/// Who holds a project's workspace lock, as written to `.site2ts/lock.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Holder {
+    pid: u32,
+    host: String,
+    started_at: String,
+    /// The call that took the lock.
+    method: String,
+}
+
+impl Holder {
+    fn current(method: &str) -> Self {
+        Self {
+            pid: std::process::id(),
+            host: hostname(),
+            started_at: Utc::now().to_rfc3339(),
+            method: method.to_string(),
+        }
+    }
+
+    fn is_us(&self) -> bool {
+        self.pid == std::process::id() && self.host == hostname()
+    }
+
+    /// Why the holder no longer holds the lock, if it is known to be gone.
+    fn stale_reason(&self) -> Option<String> {
+        if self.host == hostname() {
+            return (!pid_alive(self.pid)).then(|| format!("process {} is gone", self.pid));
+        }
+        let started = DateTime::parse_from_rfc3339(&self.started_at).ok()?;
+        let age = Utc::now() - started.with_timezone(&Utc);
+        (age > Duration::hours(REMOTE_STALE_AFTER_HOURS)).then(|| {
+            format!(
+                "held on {} for more than {}h",
+                self.host, REMOTE_STALE_AFTER_HOURS
+            )
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+pub(crate) fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "localhost".into())
+}
+
+/// Whether process `pid` on this host is still running. When that cannot
+/// be told the holder is assumed alive; `lock/break` clears it.
+pub(crate) fn pid_alive(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        return Path::new("/proc").join(pid.to_string()).exists();
+    }
+    if cfg!(unix) {
+        return std::process::Command::new("kill")
+            .args(["-0", &pid.to_string()])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(true);
+    }
+    true
+}
+
+fn path(base: &Path) -> PathBuf {
+    base.join("lock.json")
+}
+
+fn read(base: &Path) -> Option<Holder> {
+    read_at(&path(base))
+}
+
+fn read_at(file: &Path) -> Option<Holder> {
+    let bytes = fs::read(file).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Remove the lock file if it still names `stale`. It is first renamed to
+/// a name only this call uses, so two processes replacing the same stale
+/// holder cannot both remove it: whoever renames second finds the other's
+/// fresh lock and puts it back. Returns whether `stale` was removed.
+fn remove_stale(base: &Path, stale: &Holder) -> RpcResult<bool> {
+    let aside = base.join(format!("lock.json.{}.stale", Ulid::new()));
+    match fs::rename(path(base), &aside) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(RpcError::internal(e.to_string())),
+    }
+    if read_at(&aside).as_ref() == Some(stale) {
+        fs::remove_file(&aside).map_err(|e| RpcError::internal(e.to_string()))?;
+        return Ok(true);
+    }
+    // Someone else's lock: restore it unless yet another one was taken
+    // meanwhile, which a hard link, unlike a rename, does not overwrite.
+    let restored = fs::hard_link(&aside, path(base));
+    let _ = fs::remove_file(&aside);
+    if let Err(e) = restored {
+        warn!(
+            target = "site2ts",
+            "workspace lock of {} changed hands while replacing a stale one: {}",
+            base.display(),
+            e
+        );
+    }
+    Ok(false)
+}
+
+/// Calls in this process holding each project's lock, keyed by the
+/// canonical sandbox path. The file is written by the first and removed by
+/// the last.
+fn held() -> MutexGuard<'static, BTreeMap<PathBuf, usize>> {
+    static HELD: Mutex<BTreeMap<PathBuf, usize>> = Mutex::new(BTreeMap::new());
+    HELD.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Holds the workspace lock for one call; the last one out removes it.
+#[derive(Debug)]
+pub(crate) struct WorkspaceGuard {
+    base: PathBuf,
+}
+
+impl Drop for WorkspaceGuard {
+    fn drop(&mut self) {
+        let mut held = held();
+        let Some(count) = held.get_mut(&self.base) else {
+            return;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        held.remove(&self.base);
+        if read(&self.base).is_some_and(|h| h.is_us()) {
+            if let Err(e) = fs::remove_file(path(&self.base)) {
+                warn!(target = "site2ts", "releasing workspace lock failed: {}", e);
+            }
+        }
+    }
+}
+
+fn locked(base: &Path, holder: &Holder) -> RpcError {
+    RpcError::workspace_locked(
+        format!(
+            "project is locked by pid {} on {} ({} since {}); retry later or use lock/break",
+            holder.pid, holder.host, holder.method, holder.started_at
+        ),
+        json!({ "lock": path(base), "holder": holder.to_json() }),
+    )
+}
+
+/// Take the workspace lock of the project whose sandbox is `base` for a
+/// call to `method`. Calls in this process share it; a live holder in
+/// another process fails the call, a stale one is replaced.
+pub(crate) fn acquire(base: &Path, method: &str) -> RpcResult<WorkspaceGuard> {
+    fs::create_dir_all(base).map_err(|e| RpcError::internal(e.to_string()))?;
+    let base = fs::canonicalize(base).map_err(|e| RpcError::internal(e.to_string()))?;
+    let mut held = held();
+    let mut replaced = false;
+    loop {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path(&base));
+        match file {
+            Ok(mut f) => {
+                let holder = Holder::current(method);
+                let body = serde_json::to_vec_pretty(&holder)
+                    .map_err(|e| RpcError::internal(e.to_string()))?;
+                f.write_all(&body)
+                    .map_err(|e| RpcError::internal(e.to_string()))?;
+                break;
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let Some(holder) = read(&base) else {
+                    // Half-written by another process that is taking it now.
+                    return Err(RpcError::workspace_locked(
+                        "project lock is being taken by another process; retry later",
+                        json!({ "lock": path(&base) }),
+                    ));
+                };
+                if holder.is_us() {
+                    break;
+                }
+                // A stale lock is replaced once; finding another one right
+                // after means someone else is taking it too.
+                match holder.stale_reason() {
+                    Some(reason) if !replaced => {
+                        warn!(
+                            target = "site2ts",
+                            "replacing stale workspace lock of {}: {}",
+                            base.display(),
+                            reason
+                        );
+                        remove_stale(&base, &holder)?;
+                        replaced = true;
+                    }
+                    _ => return Err(locked(&base, &holder)),
+                }
+            }
+            Err(e) => return Err(RpcError::internal(e.to_string())),
+        }
+    }
+    *held.entry(base.clone()).or_default() += 1;
+    Ok(WorkspaceGuard { base })
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct BreakParams {
+    /// Also break a lock whose holder still looks alive.
+    #[serde(default)]
+    force: bool,
+}
+
+/// Who holds the lock of the project whose sandbox is `base`, if anyone.
+pub(crate) fn status(base: &Path) -> RpcResult<Value> {
+    let Some(holder) = read(base) else {
+        return Ok(json!({ "locked": false, "lock": path(base) }));
+    };
+    let stale = holder.stale_reason();
+    Ok(json!({
+        "locked": true,
+        "lock": path(base),
+        "holder": holder.to_json(),
+        "ours": holder.is_us(),
+        "stale": stale.is_some(),
+        "staleReason": stale,
+    }))
+}
+
+/// Remove the lock of the project whose sandbox is `base`. A holder that
+/// still looks alive is only evicted with `force`.
+pub(crate) fn break_lock(base: &Path, params: BreakParams) -> RpcResult<Value> {
+    let Some(holder) = read(base) else {
+        return Ok(json!({ "broken": false, "lock": path(base) }));
+    };
+    if holder.stale_reason().is_none() && !params.force {
+        let what = if holder.is_us() {
+            "this server is running calls on the project"
+        } else {
+            "its holder looks alive"
+        };
+        return Err(RpcError::invalid_params(format!(
+            "lock is not stale: {}; pass force: true to break it anyway",
+            what
+        )));
+    }
+    // Only the holder checked above is evicted, not one that took over since.
+    let broken = remove_stale(base, &holder)?;
+    Ok(json!({ "broken": broken, "lock": path(base), "holder": holder.to_json() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plant(base: &Path, holder: &Holder) {
+        fs::write(path(base), serde_json::to_vec(holder).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn workspace_lock_is_shared_in_process_and_refused_across_processes() {
+        let base = std::env::temp_dir().join(format!("site2ts-lock-{}", Ulid::new()));
+        let outer = acquire(&base, "pipeline").unwrap();
+        let inner = acquire(&base, "generate").unwrap();
+        let st = status(&base).unwrap();
+        assert_eq!(st["locked"], true);
+        assert_eq!(st["ours"], true);
+        assert_eq!(st["holder"]["method"], "pipeline");
+        assert!(break_lock(&base, BreakParams::default()).is_err());
+        drop(inner);
+        assert!(path(&base).is_file());
+        drop(outer);
+        assert_eq!(status(&base).unwrap()["locked"], false);
+
+        // A live process elsewhere: refused until broken with force.
+        let other = Holder {
+            pid: 1,
+            host: "ci-runner".into(),
+            started_at: Utc::now().to_rfc3339(),
+            method: "apply".into(),
+        };
+        plant(&base, &other);
+        let err = acquire(&base, "generate").unwrap_err();
+        assert_eq!(err.code(), -32013);
+        assert_eq!(err.data.as_ref().unwrap()["holder"]["host"], "ci-runner");
+        assert_eq!(status(&base).unwrap()["stale"], false);
+        assert!(break_lock(&base, BreakParams::default()).is_err());
+        let broken = break_lock(&base, BreakParams { force: true }).unwrap();
+        assert_eq!(broken["holder"]["pid"], 1);
+
+        // Stale holders are replaced: a dead pid here, an old lock elsewhere.
+        let dead = Holder {
+            pid: u32::MAX,
+            host: hostname(),
+            ..other.clone()
+        };
+        let old = Holder {
+            started_at: (Utc::now() - Duration::hours(7)).to_rfc3339(),
+            ..other
+        };
+        for holder in [dead, old] {
+            plant(&base, &holder);
+            assert_eq!(status(&base).unwrap()["stale"], true);
+            let guard = acquire(&base, "crawl").unwrap();
+            assert!(read(&base).unwrap().is_us());
+            drop(guard);
+        }
+        assert!(!path(&base).exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn a_fresh_lock_taken_over_a_stale_one_is_not_removed() {
+        let base = std::env::temp_dir().join(format!("site2ts-lock-{}", Ulid::new()));
+        fs::create_dir_all(&base).unwrap();
+        let stale = Holder {
+            pid: u32::MAX,
+            host: hostname(),
+            started_at: Utc::now().to_rfc3339(),
+            method: "apply".into(),
+        };
+        // Another process replaced the stale holder we read before we did.
+        let fresh = Holder {
+            pid: 1,
+            method: "crawl".into(),
+            ..stale.clone()
+        };
+        plant(&base, &fresh);
+        assert!(!remove_stale(&base, &stale).unwrap());
+        assert_eq!(read(&base), Some(fresh));
+
+        plant(&base, &stale);
+        assert!(remove_stale(&base, &stale).unwrap());
+        assert!(!path(&base).exists());
+        assert!(!remove_stale(&base, &stale).unwrap());
+        assert_eq!(
+            fs::read_dir(&base).unwrap().count(),
+            0,
+            "nothing left aside"
+        );
+        fs::remove_dir_all(&base).unwrap();
+    }
+}